@@ -0,0 +1,183 @@
+use std::fmt;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Destination for Common Log Format access lines. Cloning shares the same
+/// underlying writer, so every connection appends to one file or stream.
+#[derive(Clone)]
+pub struct AccessLog {
+    sink: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AccessLog")
+    }
+}
+
+impl AccessLog {
+    pub fn new<W>(writer: W) -> Self
+    where
+        W: Write + Send + 'static,
+    {
+        Self {
+            sink: Arc::new(Mutex::new(Box::new(writer))),
+        }
+    }
+
+    /// Open `path` for appending, or stdout when `path` is `-`.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        if path == Path::new("-") {
+            return Ok(Self::new(std::io::stdout()));
+        }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self::new(file))
+    }
+
+    pub fn record(&self, entry: &AccessLogEntry<'_>) {
+        let line = entry.to_clf(SystemTime::now());
+        let mut sink = self.sink.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = writeln!(sink, "{}", line).and_then(|_| sink.flush()) {
+            tracing::warn!("Failed to write access log: {}", e);
+        }
+    }
+}
+
+/// One completed request.
+pub struct AccessLogEntry<'a> {
+    pub client: Option<IpAddr>,
+    pub method: &'a str,
+    pub target: &'a str,
+    pub status: u16,
+    /// Response body bytes, or bytes relayed to the client for a tunnel.
+    pub bytes_sent: u64,
+    pub duration: Duration,
+    /// Everything read from the client: request line, headers, and body.
+    pub bytes_read: u64,
+    /// Everything written to the client: status line, headers, and body.
+    pub bytes_written: u64,
+}
+
+impl AccessLogEntry<'_> {
+    /// Format as CLF with the request duration in milliseconds and the
+    /// bytes read from and written to the client appended:
+    /// `host - - [date] "request" status bytes duration_ms bytes_read bytes_written`.
+    pub fn to_clf(&self, now: SystemTime) -> String {
+        let client = self
+            .client
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let bytes = if self.bytes_sent == 0 {
+            "-".to_string()
+        } else {
+            self.bytes_sent.to_string()
+        };
+        format!(
+            "{} - - [{}] \"{} {} HTTP/1.1\" {} {} {} {} {}",
+            client,
+            clf_timestamp(now),
+            self.method,
+            self.target,
+            self.status,
+            bytes,
+            self.duration.as_millis(),
+            self.bytes_read,
+            self.bytes_written
+        )
+    }
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// `10/Oct/2000:13:55:36 +0000`, always in UTC.
+fn clf_timestamp(now: SystemTime) -> String {
+    let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+/// Days since 1970-01-01 to (year, month, day), per Howard Hinnant's
+/// `civil_from_days` algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clf_timestamp() {
+        // 2000-10-10 13:55:36 UTC, the example from the Apache docs.
+        let time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        assert_eq!(clf_timestamp(time), "10/Oct/2000:13:55:36 +0000");
+    }
+
+    #[test]
+    fn test_clf_timestamp_leap_day() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_709_164_800);
+        assert_eq!(clf_timestamp(time), "29/Feb/2024:00:00:00 +0000");
+    }
+
+    #[test]
+    fn test_to_clf_line() {
+        let entry = AccessLogEntry {
+            client: Some("203.0.113.7".parse().unwrap()),
+            method: "GET",
+            target: "http://example.com/",
+            status: 200,
+            bytes_sent: 512,
+            duration: Duration::from_millis(42),
+            bytes_read: 64,
+            bytes_written: 600,
+        };
+        let time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        assert_eq!(
+            entry.to_clf(time),
+            "203.0.113.7 - - [10/Oct/2000:13:55:36 +0000] \"GET http://example.com/ HTTP/1.1\" 200 512 42 64 600"
+        );
+    }
+
+    #[test]
+    fn test_to_clf_unknown_client_and_empty_body() {
+        let entry = AccessLogEntry {
+            client: None,
+            method: "CONNECT",
+            target: "example.com:443",
+            status: 403,
+            bytes_sent: 0,
+            duration: Duration::ZERO,
+            bytes_read: 52,
+            bytes_written: 27,
+        };
+        let line = entry.to_clf(UNIX_EPOCH);
+        assert!(line.starts_with("- - - [01/Jan/1970:00:00:00 +0000]"));
+        assert!(line.ends_with("\" 403 - 0 52 27"));
+    }
+}
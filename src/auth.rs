@@ -0,0 +1,138 @@
+use anyhow::{Context, Result};
+use base64::Engine;
+use std::path::Path;
+
+/// Accepted `Proxy-Authorization: Basic` credentials. A request is allowed
+/// if it matches any configured user/password pair.
+#[derive(Debug, Clone, Default)]
+pub struct ProxyAuth {
+    credentials: Vec<(String, String)>,
+}
+
+impl ProxyAuth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, user: impl Into<String>, pass: impl Into<String>) {
+        self.credentials.push((user.into(), pass.into()));
+    }
+
+    /// Load `user:pass` pairs from a file, one per line. Blank lines and
+    /// lines starting with `#` are ignored.
+    pub fn load_file(&mut self, path: &Path) -> Result<()> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read auth file {}", path.display()))?;
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, pass) = line
+                .split_once(':')
+                .with_context(|| format!("{}:{}: expected user:pass", path.display(), index + 1))?;
+            self.add(user, pass);
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.credentials.is_empty()
+    }
+
+    /// Check the `proxy-authorization` header among already-parsed,
+    /// lowercase-keyed request headers.
+    pub fn is_authorized(&self, headers: &[(String, String)]) -> bool {
+        let Some((_, value)) = headers.iter().find(|(k, _)| k == "proxy-authorization") else {
+            return false;
+        };
+        let Some((user, pass)) = decode_basic(value) else {
+            return false;
+        };
+        self.verify(&user, &pass)
+    }
+
+    /// Check a user/password pair directly, e.g. from a SOCKS5 handshake.
+    pub fn verify(&self, user: &str, pass: &str) -> bool {
+        // Check every pair so timing doesn't reveal which user matched.
+        self.credentials.iter().fold(false, |matched, (u, p)| {
+            let hit = constant_time_eq(u.as_bytes(), user.as_bytes())
+                & constant_time_eq(p.as_bytes(), pass.as_bytes());
+            matched | hit
+        })
+    }
+}
+
+fn decode_basic(value: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, pass) = decoded.split_once(':')?;
+    Some((user.to_string(), pass.to_string()))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(value: &str) -> Vec<(String, String)> {
+        vec![("proxy-authorization".to_string(), value.to_string())]
+    }
+
+    fn auth() -> ProxyAuth {
+        let mut auth = ProxyAuth::new();
+        auth.add("alice", "s3cret:with-colon");
+        auth.add("bob", "hunter2");
+        auth
+    }
+
+    #[test]
+    fn test_accepts_any_configured_pair() {
+        // alice:s3cret:with-colon / bob:hunter2
+        assert!(auth().is_authorized(&header("Basic YWxpY2U6czNjcmV0OndpdGgtY29sb24=")));
+        assert!(auth().is_authorized(&header("basic Ym9iOmh1bnRlcjI=")));
+    }
+
+    #[test]
+    fn test_rejects_wrong_password() {
+        // bob:wrong
+        assert!(!auth().is_authorized(&header("Basic Ym9iOndyb25n")));
+    }
+
+    #[test]
+    fn test_rejects_missing_or_malformed_header() {
+        assert!(!auth().is_authorized(&[]));
+        assert!(!auth().is_authorized(&header("Bearer Ym9iOmh1bnRlcjI=")));
+        assert!(!auth().is_authorized(&header("Basic not-base64!")));
+    }
+
+    #[test]
+    fn test_load_file_skips_comments_and_blank_lines() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, b"# users\n\nbob:hunter2\n").unwrap();
+
+        let mut auth = ProxyAuth::new();
+        auth.load_file(file.path()).unwrap();
+        assert!(auth.is_authorized(&header("Basic Ym9iOmh1bnRlcjI=")));
+    }
+
+    #[test]
+    fn test_load_file_rejects_line_without_colon() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, b"bob\n").unwrap();
+
+        assert!(ProxyAuth::new().load_file(file.path()).is_err());
+    }
+}
@@ -0,0 +1,220 @@
+//! `--circuit-breaker-threshold`: stop sending traffic to an upstream host
+//! that keeps failing. After `threshold` consecutive failures within
+//! `window`, the host's breaker opens and requests to it get a fast `503`
+//! for `cooldown`. Then one probe is let through (half-open): if it
+//! succeeds the breaker closes, and if it fails it opens for another
+//! cooldown.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::constants;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Consecutive failures, counted from the first in the current window.
+    Closed {
+        failures: u32,
+        since: Instant,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A probe was let through at `since`; others wait for its result.
+    HalfOpen {
+        since: Instant,
+    },
+}
+
+/// Per-host breakers, shared by every connection.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    hosts: Mutex<HashMap<String, State>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            window,
+            cooldown,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a request to `host` may go ahead. `false` means its breaker
+    /// is open and the caller should answer `503` without connecting.
+    pub fn allow(&self, host: &str) -> bool {
+        self.allow_at(host, Instant::now())
+    }
+
+    /// Report that a request to `host` got through.
+    pub fn record_success(&self, host: &str) {
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(State::Open { .. } | State::HalfOpen { .. }) =
+            hosts.remove(&host.to_ascii_lowercase())
+        {
+            info!("Circuit for {} closed: the upstream recovered", host);
+        }
+    }
+
+    /// Report that a request to `host` failed to reach it.
+    pub fn record_failure(&self, host: &str) {
+        self.record_failure_at(host, Instant::now());
+    }
+
+    fn allow_at(&self, host: &str, now: Instant) -> bool {
+        let mut hosts = self.hosts.lock().unwrap();
+        let Some(state) = hosts.get_mut(&host.to_ascii_lowercase()) else {
+            return true;
+        };
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if now < until => false,
+            // A probe that never reported back (its client went away)
+            // doesn't hold the breaker shut for good.
+            State::HalfOpen { since } if now < since + self.cooldown => false,
+            State::Open { .. } | State::HalfOpen { .. } => {
+                debug!("Circuit for {} half-open: letting a probe through", host);
+                *state = State::HalfOpen { since: now };
+                true
+            }
+        }
+    }
+
+    fn record_failure_at(&self, host: &str, now: Instant) {
+        let mut hosts = self.hosts.lock().unwrap();
+        if hosts.len() >= constants::MAX_CIRCUIT_BREAKER_HOSTS {
+            hosts.retain(|_, state| !self.is_stale(state, now));
+        }
+        let state = hosts
+            .entry(host.to_ascii_lowercase())
+            .or_insert(State::Closed {
+                failures: 0,
+                since: now,
+            });
+        match *state {
+            // Requests let through before it opened are still finishing.
+            State::Open { .. } => {}
+            State::HalfOpen { .. } => {
+                warn!(
+                    "Circuit for {} reopened: the probe failed; failing fast for {:?}",
+                    host, self.cooldown
+                );
+                *state = State::Open {
+                    until: now + self.cooldown,
+                };
+            }
+            State::Closed { failures, since } => {
+                let (failures, since) = if now.duration_since(since) < self.window {
+                    (failures + 1, since)
+                } else {
+                    (1, now)
+                };
+                *state = if failures >= self.threshold {
+                    warn!(
+                        "Circuit for {} opened after {} consecutive failures; failing fast for {:?}",
+                        host, failures, self.cooldown
+                    );
+                    State::Open {
+                        until: now + self.cooldown,
+                    }
+                } else {
+                    State::Closed { failures, since }
+                };
+            }
+        }
+    }
+
+    /// Whether forgetting `state` changes nothing: its failures have aged
+    /// out of the window, or its cooldown is long over.
+    fn is_stale(&self, state: &State, now: Instant) -> bool {
+        match *state {
+            State::Closed { since, .. } => now.duration_since(since) >= self.window,
+            State::Open { until } => now.saturating_duration_since(until) >= self.cooldown,
+            State::HalfOpen { since } => now.duration_since(since) >= self.cooldown * 2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(3, 10 * SECOND, 30 * SECOND)
+    }
+
+    #[test]
+    fn test_opens_after_threshold_consecutive_failures() {
+        let breaker = breaker();
+        let start = Instant::now();
+        for i in 0..2u32 {
+            breaker.record_failure_at("Example.com", start + i * SECOND);
+            assert!(breaker.allow_at("example.com", start + i * SECOND));
+        }
+        breaker.record_failure_at("example.com", start + 2 * SECOND);
+        assert!(!breaker.allow_at("EXAMPLE.com", start + 3 * SECOND));
+        assert!(
+            breaker.allow_at("other.example", start + 3 * SECOND),
+            "Breakers are per host"
+        );
+    }
+
+    #[test]
+    fn test_success_or_window_resets_failures() {
+        let breaker = breaker();
+        let start = Instant::now();
+        breaker.record_failure_at("example.com", start);
+        breaker.record_failure_at("example.com", start);
+        breaker.record_success("example.com");
+        breaker.record_failure_at("example.com", start);
+        assert!(breaker.allow_at("example.com", start));
+
+        // Failures spread wider than the window don't add up.
+        breaker.record_failure_at("example.com", start + 11 * SECOND);
+        breaker.record_failure_at("example.com", start + 22 * SECOND);
+        assert!(breaker.allow_at("example.com", start + 22 * SECOND));
+    }
+
+    #[test]
+    fn test_half_open_probe_closes_or_reopens() {
+        let breaker = breaker();
+        let start = Instant::now();
+        for _ in 0..3 {
+            breaker.record_failure_at("example.com", start);
+        }
+        let cooled = start + 30 * SECOND;
+
+        // One probe at a time once the cooldown is over.
+        assert!(breaker.allow_at("example.com", cooled));
+        assert!(!breaker.allow_at("example.com", cooled));
+        breaker.record_failure_at("example.com", cooled);
+        assert!(!breaker.allow_at("example.com", cooled + SECOND));
+
+        let cooled = cooled + 30 * SECOND;
+        assert!(breaker.allow_at("example.com", cooled));
+        breaker.record_success("example.com");
+        assert!(breaker.allow_at("example.com", cooled));
+        assert!(breaker.allow_at("example.com", cooled));
+    }
+
+    #[test]
+    fn test_lost_probe_does_not_hold_breaker_shut() {
+        let breaker = breaker();
+        let start = Instant::now();
+        for _ in 0..3 {
+            breaker.record_failure_at("example.com", start);
+        }
+        assert!(breaker.allow_at("example.com", start + 30 * SECOND));
+        assert!(!breaker.allow_at("example.com", start + 59 * SECOND));
+        assert!(breaker.allow_at("example.com", start + 60 * SECOND));
+    }
+}
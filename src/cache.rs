@@ -0,0 +1,465 @@
+//! `--cache-size`: an in-memory LRU cache of `GET` responses, shared by
+//! every connection. Only complete `200` responses a shared cache may keep
+//! are stored: not `no-store` or `private`, without `Set-Cookie` or `Vary`,
+//! and no larger than an eighth of the cache. A stored response is served
+//! without contacting the upstream while fresh by `Cache-Control`
+//! (`s-maxage`, then `max-age`) or `Expires`. Once stale, one with an
+//! `ETag` is revalidated with `If-None-Match` and served again on `304`.
+
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+/// A stored response. The status is always `200`.
+#[derive(Debug)]
+pub struct CachedResponse {
+    /// The upstream's headers, as received.
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+    stored: Instant,
+    /// The upstream's `Age` when it was stored.
+    initial_age: Duration,
+    lifetime: Duration,
+}
+
+impl CachedResponse {
+    fn new(headers: HeaderMap, body: Vec<u8>, lifetime: Duration) -> Self {
+        let initial_age = Duration::from_secs(
+            header_str(&headers, header::AGE)
+                .and_then(|age| age.trim().parse().ok())
+                .unwrap_or(0),
+        );
+        Self {
+            headers,
+            body,
+            stored: Instant::now(),
+            initial_age,
+            lifetime,
+        }
+    }
+
+    /// How old the response is, for the `Age` header.
+    pub fn age(&self) -> Duration {
+        self.initial_age + self.stored.elapsed()
+    }
+
+    fn is_fresh(&self) -> bool {
+        self.age() < self.lifetime
+    }
+
+    fn size(&self, key: &str) -> usize {
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        key.len() + headers + self.body.len()
+    }
+}
+
+/// What the cache holds for a URL.
+#[derive(Debug)]
+pub enum Lookup {
+    Fresh(Arc<CachedResponse>),
+    /// Stale, but can be revalidated with `If-None-Match: etag`.
+    Stale {
+        etag: String,
+    },
+    Miss,
+}
+
+#[derive(Debug)]
+struct Slot {
+    response: Arc<CachedResponse>,
+    size: usize,
+    /// `Store::clock` when last looked up, for LRU eviction.
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Store {
+    slots: HashMap<String, Slot>,
+    bytes: usize,
+    clock: u64,
+}
+
+#[derive(Debug)]
+pub struct ResponseCache {
+    capacity: usize,
+    store: Mutex<Store>,
+}
+
+impl ResponseCache {
+    /// A cache holding up to `capacity` bytes of URLs, headers, and bodies.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            store: Mutex::new(Store::default()),
+        }
+    }
+
+    /// The largest body worth collecting for `store`.
+    pub fn max_entry_size(&self) -> usize {
+        self.capacity / 8
+    }
+
+    pub fn lookup(&self, key: &str) -> Lookup {
+        let mut store = self.store.lock().unwrap();
+        store.clock += 1;
+        let clock = store.clock;
+        let Some(slot) = store.slots.get_mut(key) else {
+            return Lookup::Miss;
+        };
+        slot.used = clock;
+        if slot.response.is_fresh() {
+            return Lookup::Fresh(slot.response.clone());
+        }
+        match header_str(&slot.response.headers, header::ETAG) {
+            Some(etag) => Lookup::Stale {
+                etag: etag.to_string(),
+            },
+            None => {
+                let slot = store.slots.remove(key).unwrap();
+                store.bytes -= slot.size;
+                Lookup::Miss
+            }
+        }
+    }
+
+    /// Whether a `status` response with `headers` may be stored at all. Its
+    /// body must still fit `max_entry_size`.
+    pub fn is_storable(&self, status: u16, headers: &HeaderMap) -> bool {
+        status == 200
+            && !headers.contains_key(header::SET_COOKIE)
+            && !headers.contains_key(header::VARY)
+            && headers
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok()?.parse::<usize>().ok())
+                .is_none_or(|length| length <= self.max_entry_size())
+            && lifetime(headers)
+                .is_some_and(|lifetime| !lifetime.is_zero() || headers.contains_key(header::ETAG))
+    }
+
+    /// Store a complete response for `key`, evicting the least recently
+    /// used entries to make room.
+    pub fn store(&self, key: &str, headers: HeaderMap, body: Vec<u8>) {
+        let Some(lifetime) = lifetime(&headers) else {
+            return;
+        };
+        if body.len() > self.max_entry_size() {
+            return;
+        }
+        let response = Arc::new(CachedResponse::new(headers, body, lifetime));
+        self.insert(key, response);
+    }
+
+    /// Refresh a stale entry from the headers of the upstream's `304`,
+    /// returning it to be served.
+    pub fn revalidated(&self, key: &str, not_modified: &HeaderMap) -> Option<Arc<CachedResponse>> {
+        let stale = self.store.lock().unwrap().slots.get(key)?.response.clone();
+        let mut headers = stale.headers.clone();
+        for name in [
+            header::CACHE_CONTROL,
+            header::EXPIRES,
+            header::DATE,
+            header::ETAG,
+            header::AGE,
+        ] {
+            if let Some(value) = not_modified.get(&name) {
+                headers.insert(name, value.clone());
+            }
+        }
+        // A `304` may withdraw permission to store.
+        let Some(lifetime) = lifetime(&headers) else {
+            self.remove(key);
+            return Some(stale);
+        };
+        let response = Arc::new(CachedResponse::new(headers, stale.body.clone(), lifetime));
+        self.insert(key, response.clone());
+        Some(response)
+    }
+
+    fn insert(&self, key: &str, response: Arc<CachedResponse>) {
+        let size = response.size(key);
+        let mut store = self.store.lock().unwrap();
+        if let Some(old) = store.slots.remove(key) {
+            store.bytes -= old.size;
+        }
+        // Linear in the entry count, which stays small next to the bytes
+        // each entry holds.
+        while store.bytes + size > self.capacity {
+            let Some(oldest) = store
+                .slots
+                .iter()
+                .min_by_key(|(_, slot)| slot.used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            let slot = store.slots.remove(&oldest).unwrap();
+            store.bytes -= slot.size;
+            debug!("Evicted {} from the response cache", oldest);
+        }
+        store.clock += 1;
+        let used = store.clock;
+        store.bytes += size;
+        store.slots.insert(
+            key.to_string(),
+            Slot {
+                response,
+                size,
+                used,
+            },
+        );
+    }
+
+    fn remove(&self, key: &str) {
+        let mut store = self.store.lock().unwrap();
+        if let Some(slot) = store.slots.remove(key) {
+            store.bytes -= slot.size;
+        }
+    }
+}
+
+/// How long a response stays fresh, or `None` if it must not be stored.
+/// `no-cache` and a missing or past expiry mean zero: stored, but
+/// revalidated before every use.
+fn lifetime(headers: &HeaderMap) -> Option<Duration> {
+    let mut max_age = None;
+    let mut s_maxage = None;
+    let mut no_cache = false;
+    for value in headers.get_all(header::CACHE_CONTROL) {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        for directive in value.split(',') {
+            let (name, argument) = match directive.split_once('=') {
+                Some((name, argument)) => (name.trim(), Some(argument.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            let seconds = || argument?.parse::<u64>().ok().map(Duration::from_secs);
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" | "private" => return None,
+                "no-cache" => no_cache = true,
+                "max-age" => max_age = seconds().or(Some(Duration::ZERO)),
+                "s-maxage" => s_maxage = seconds().or(Some(Duration::ZERO)),
+                _ => {}
+            }
+        }
+    }
+    if no_cache {
+        return Some(Duration::ZERO);
+    }
+    if let Some(lifetime) = s_maxage.or(max_age) {
+        return Some(lifetime);
+    }
+    let Some(expires) = headers.get(header::EXPIRES) else {
+        return Some(Duration::ZERO);
+    };
+    // An invalid date (often `0`) means already expired.
+    let Some(expires) = expires.to_str().ok().and_then(parse_http_date) else {
+        return Some(Duration::ZERO);
+    };
+    let date = header_str(headers, header::DATE)
+        .and_then(parse_http_date)
+        .unwrap_or_else(SystemTime::now);
+    Some(expires.duration_since(date).unwrap_or_default())
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers
+        .get(name)
+        .and_then(|v: &HeaderValue| v.to_str().ok())
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Parse an IMF-fixdate (`Sun, 06 Nov 1994 08:49:37 GMT`), the only format
+/// servers may send. Obsolete formats are treated as invalid.
+fn parse_http_date(s: &str) -> Option<SystemTime> {
+    let (_, rest) = s.trim().split_once(", ")?;
+    let fields: Vec<&str> = rest.split(' ').collect();
+    let [day, month, year, time, "GMT"] = fields[..] else {
+        return None;
+    };
+    let day: u32 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|m| *m == month)? as u32 + 1;
+    let year: i64 = year.parse().ok()?;
+    let mut time = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (Some(Some(hours)), Some(Some(minutes)), Some(Some(seconds)), None) =
+        (time.next(), time.next(), time.next(), time.next())
+    else {
+        return None;
+    };
+    if !(1..=31).contains(&day) || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let secs = days * 86_400 + hours * 3600 + minutes * 60 + seconds;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// (year, month, day) to days since 1970-01-01, per Howard Hinnant's
+/// `days_from_civil` algorithm.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    header::HeaderName::from_static(name),
+                    HeaderValue::from_static(value),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_http_date() {
+        let date = parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert_eq!(date, UNIX_EPOCH + Duration::from_secs(784_111_777));
+        assert_eq!(
+            parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"),
+            Some(UNIX_EPOCH)
+        );
+        for bad in [
+            "0",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun, 06 Nov 1994 08:49:37",
+        ] {
+            assert_eq!(parse_http_date(bad), None, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_lifetime_from_headers() {
+        let lifetime_of = |pairs| lifetime(&headers(pairs));
+        assert_eq!(
+            lifetime_of(&[("cache-control", "public, max-age=60")]),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            lifetime_of(&[("cache-control", "max-age=60, s-maxage=10")]),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(lifetime_of(&[("cache-control", "no-store")]), None);
+        assert_eq!(
+            lifetime_of(&[("cache-control", "private, max-age=60")]),
+            None
+        );
+        assert_eq!(
+            lifetime_of(&[("cache-control", "no-cache, max-age=60")]),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            lifetime_of(&[
+                ("date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                ("expires", "Sun, 06 Nov 1994 09:49:37 GMT"),
+            ]),
+            Some(Duration::from_secs(3600))
+        );
+        assert_eq!(lifetime_of(&[("expires", "0")]), Some(Duration::ZERO));
+        assert_eq!(lifetime_of(&[]), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_fresh_hit_then_stale_lookup() {
+        let cache = ResponseCache::new(1 << 20);
+        cache.store(
+            "http://a.test/",
+            headers(&[("cache-control", "max-age=60")]),
+            b"fresh".to_vec(),
+        );
+        cache.store(
+            "http://b.test/",
+            headers(&[("cache-control", "no-cache"), ("etag", "\"v1\"")]),
+            b"stale".to_vec(),
+        );
+        cache.store("http://c.test/", headers(&[]), b"gone".to_vec());
+
+        assert!(matches!(
+            cache.lookup("http://a.test/"),
+            Lookup::Fresh(response) if response.body == b"fresh"
+        ));
+        assert!(matches!(
+            cache.lookup("http://b.test/"),
+            Lookup::Stale { etag } if etag == "\"v1\""
+        ));
+        // Stale with nothing to revalidate with is dropped.
+        assert!(matches!(cache.lookup("http://c.test/"), Lookup::Miss));
+    }
+
+    #[test]
+    fn test_revalidation_refreshes_entry() {
+        let cache = ResponseCache::new(1 << 20);
+        cache.store(
+            "http://a.test/",
+            headers(&[("cache-control", "no-cache"), ("etag", "\"v1\"")]),
+            b"body".to_vec(),
+        );
+        let refreshed = cache
+            .revalidated(
+                "http://a.test/",
+                &headers(&[("cache-control", "max-age=60")]),
+            )
+            .unwrap();
+        assert_eq!(refreshed.body, b"body");
+        assert!(matches!(cache.lookup("http://a.test/"), Lookup::Fresh(_)));
+    }
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let cache = ResponseCache::new(8 * 200);
+        let fresh = || headers(&[("cache-control", "max-age=60")]);
+        for key in ["http://a.test/", "http://b.test/", "http://c.test/"] {
+            cache.store(key, fresh(), vec![b'x'; 180]);
+        }
+        for _ in 0..6 {
+            cache.lookup("http://a.test/");
+        }
+        // Fills the cache; `b`, the least recently used, makes room.
+        for i in 0..6 {
+            cache.store(&format!("http://{}.fill/", i), fresh(), vec![b'x'; 180]);
+            cache.lookup("http://a.test/");
+        }
+        assert!(matches!(cache.lookup("http://a.test/"), Lookup::Fresh(_)));
+        assert!(matches!(cache.lookup("http://b.test/"), Lookup::Miss));
+    }
+
+    #[test]
+    fn test_is_storable() {
+        let cache = ResponseCache::new(8 * 1024);
+        let ok = |pairs| cache.is_storable(200, &headers(pairs));
+        assert!(ok(&[("cache-control", "max-age=60")]));
+        assert!(ok(&[("etag", "\"v1\"")]));
+        assert!(!ok(&[]), "Nothing fresh and nothing to revalidate with");
+        assert!(!ok(&[("cache-control", "no-store")]));
+        assert!(!ok(&[
+            ("cache-control", "max-age=60"),
+            ("set-cookie", "a=b")
+        ]));
+        assert!(!ok(&[("cache-control", "max-age=60"), ("vary", "accept")]));
+        assert!(!ok(&[
+            ("cache-control", "max-age=60"),
+            ("content-length", "2048")
+        ]));
+        assert!(!cache.is_storable(404, &headers(&[("cache-control", "max-age=60")])));
+    }
+}
@@ -0,0 +1,929 @@
+use crate::access_log::AccessLog;
+use crate::auth::ProxyAuth;
+use crate::breaker::CircuitBreaker;
+use crate::cache::ResponseCache;
+use crate::constants;
+use crate::dns::Resolver;
+use crate::protocol::mitm::MitmAuthority;
+use crate::readiness::ReadinessProbe;
+use crate::routes::{NoProxyPattern, Route, RouteTable};
+use http::header::{HeaderName, HeaderValue};
+use http::{Method, Version};
+use reqwest::Url;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+/// Runtime options for a proxy instance. `main.rs` builds this from the
+/// command line; `Default` matches running the binary with no flags.
+#[derive(Debug, Clone, Default)]
+pub struct ProxyConfig {
+    /// Peek the TLS ClientHello at the start of each CONNECT tunnel and log
+    /// its SNI. The bytes are replayed to the upstream unchanged.
+    pub log_tls_sni: bool,
+    /// Log request and response bodies at trace level, up to this many
+    /// bytes each, along with the request headers (credentials redacted).
+    /// `None` logs no bodies.
+    pub trace_bodies: Option<usize>,
+    /// Request bodies larger than this many bytes are buffered in a temp
+    /// file instead of memory. `None` keeps every body in memory.
+    pub spill_to_disk_threshold: Option<usize>,
+    /// Write a Common Log Format line here for every completed request.
+    pub access_log: Option<AccessLog>,
+    /// Require `Proxy-Authorization: Basic` matching one of these
+    /// credentials on every request.
+    pub proxy_auth: Option<ProxyAuth>,
+    /// Cap on the total size of a request's header section; larger ones get
+    /// `431`. `None` means `MAX_HEADER_BYTES`.
+    pub max_header_bytes: Option<usize>,
+    /// Cap on the request line's length; longer ones get `414`. `None`
+    /// means `MAX_REQUEST_LINE_LEN`.
+    pub max_request_line: Option<usize>,
+    /// Deadline for receiving the request line and all headers, however
+    /// steadily they trickle in. `None` leaves only the connection timeout.
+    pub header_read_timeout: Option<Duration>,
+    /// Close the connection once a read from the client has waited this
+    /// long for bytes, or a write has waited this long for the client to
+    /// take them. Unlike the fixed per-connection timeout, slow but steady
+    /// transfers are not cut off; connections are still capped at
+    /// `IDLE_CONNECTION_MAX_LIFETIME`. CONNECT tunnels use
+    /// `tunnel_idle_timeout` instead. `None` keeps the fixed timeout.
+    pub idle_timeout: Option<Duration>,
+    /// Only these methods are served; anything else gets `405`. `None`
+    /// allows every method. CONNECT must be listed for tunnels to work.
+    pub allowed_methods: Option<Vec<Method>>,
+    /// Methods refused with `405` even if `allowed_methods` lists them.
+    pub denied_methods: Vec<Method>,
+    /// Serve `TRACE` and `TRACK`, which are otherwise refused because they
+    /// reflect request headers (cross-site tracing).
+    pub allow_trace: bool,
+    /// Answer `505` to `HTTP/1.0` requests, serving only `HTTP/1.1`.
+    pub reject_http10: bool,
+    /// Answer `400` to absolute-form requests whose `Host` header names a
+    /// different authority than the target. Otherwise the header is
+    /// replaced with the target's authority.
+    pub reject_host_mismatch: bool,
+    /// Forward absolute-form targets with a `user:pass@` part. Otherwise
+    /// they get `400`, since credentials in URLs end up in logs.
+    pub allow_url_userinfo: bool,
+    /// Upstream responses whose Content-Type matches one of these
+    /// (`type/subtype` or `type/*`) are replaced with `block_status`.
+    pub blocked_content_types: Vec<String>,
+    /// Status sent in place of a blocked response. `None` means 403.
+    pub block_status: Option<u16>,
+    /// Decode gzip/deflate upstream bodies when the client's
+    /// `Accept-Encoding` doesn't admit them.
+    pub decompress: bool,
+    /// Gzip uncompressed text and JSON responses of at least this many
+    /// bytes for clients that accept it. `None` relays bodies as the
+    /// upstream encoded them.
+    pub compress_min_size: Option<usize>,
+    /// Responses for request paths matching these rules are marked
+    /// deprecated so clients get advance warning.
+    pub deprecations: Vec<PathDeprecation>,
+    /// Add a bundle of common security headers (`nosniff`, frame and
+    /// referrer policy, and HSTS for https upstreams) to forwarded responses
+    /// that don't already set them.
+    pub security_headers: bool,
+    /// Report how long the proxy took, from accepting the request to
+    /// sending the response head, in an `X-Proxy-Latency-Ms` header.
+    pub add_latency_header: bool,
+    /// Limit on a whole upstream HTTP exchange. `None` means
+    /// `UPSTREAM_TIMEOUT`.
+    pub upstream_timeout: Option<Duration>,
+    /// Limit on connecting to an upstream, for HTTP requests and tunnels
+    /// alike. `None` means `UPSTREAM_CONNECT_TIMEOUT`.
+    pub connect_timeout: Option<Duration>,
+    /// Stop relaying an upstream response body after this many bytes. A
+    /// response that declares a larger Content-Length gets `502` instead.
+    /// `None` relays bodies of any size.
+    pub max_response_size: Option<u64>,
+    /// Serve repeated `GET`s from this shared response cache while the
+    /// upstream allows. `None` always asks the upstream.
+    pub cache: Option<Arc<ResponseCache>>,
+    /// Follow up to this many upstream redirects, checking every target
+    /// against SSRF protection like the original request. `0` relays 3xx
+    /// responses to the client untouched.
+    pub follow_redirects: usize,
+    /// Retry an idempotent request this many more times when the upstream
+    /// can't be reached, or drops a bodiless request before answering.
+    /// Requests sent with `early_hints` are not retried.
+    pub retries: u32,
+    /// Let https upstreams negotiate HTTP/2. Responses still reach the
+    /// client as HTTP/1.1.
+    pub upstream_http2: bool,
+    /// Rewrite `http://` targets to `https://` before forwarding them, so
+    /// plaintext egress leaves encrypted. CONNECT tunnels are unaffected.
+    pub force_upstream_https: bool,
+    /// Hosts exempt from `force_upstream_https`, matched case-insensitively
+    /// against the target's host.
+    pub force_https_except: Vec<String>,
+    /// TLS server names to use for `https` targets by host: the connection
+    /// still goes to the host's verified addresses, but SNI and certificate
+    /// verification use the override's name.
+    pub upstream_sni: Vec<UpstreamSni>,
+    /// Headers dropped from, then set on, every request sent upstream,
+    /// after hop-by-hop headers are stripped.
+    pub remove_request_headers: Vec<RemoveHeader>,
+    pub set_request_headers: Vec<SetHeader>,
+    /// The same for forwarded responses, before any headers the proxy adds.
+    pub remove_response_headers: Vec<RemoveHeader>,
+    pub set_response_headers: Vec<SetHeader>,
+    /// Upstream statuses answered with a page of our own instead of the
+    /// upstream's body. The first matching rule wins.
+    pub intercept_statuses: Vec<InterceptStatus>,
+    /// Send forwarded requests over a direct HTTP/1.1 connection and relay
+    /// any `103 Early Hints` to the client before the final response.
+    pub early_hints: bool,
+    /// Refuse proxied requests with `403` outside this daily window.
+    /// `None` serves at any hour.
+    pub allowed_hours: Option<AllowedHours>,
+    /// Let the SSRF check pass private, loopback, and link-local targets.
+    /// Cloud metadata endpoints stay blocked.
+    pub allow_private_addresses: bool,
+    /// Serve connection and traffic counters as JSON at `/stats`.
+    pub admin: bool,
+    /// Serve Prometheus counters at `/metrics`.
+    pub metrics: bool,
+    /// Lowercase name of a request header whose value labels the request's
+    /// log lines and per-tenant metrics. `None` disables tenant labels.
+    pub tenant_header: Option<String>,
+    /// Peers whose `tenant_header` is believed. Empty trusts every client.
+    pub tenant_trusted_peers: Vec<IpAddr>,
+    /// Decrypt CONNECT tunnels with leaves issued by this CA and handle the
+    /// request inside as HTTP. `None` relays tunnels untouched.
+    pub mitm: Option<Arc<MitmAuthority>>,
+    /// Close a CONNECT tunnel after this long with no bytes in either
+    /// direction. `None` lets idle tunnels stay open.
+    pub tunnel_idle_timeout: Option<Duration>,
+    /// Ports a CONNECT tunnel may be opened to. Defaults to 443 only, so the
+    /// proxy can't be used to reach mail servers or scan arbitrary ports.
+    pub connect_allowed_ports: PortRanges,
+    /// Reach upstreams through this HTTP proxy instead of connecting
+    /// directly. Credentials in the URL are sent as `Proxy-Authorization`.
+    pub upstream_proxy: Option<Url>,
+    /// Hosts that skip `upstream_proxy` and are reached directly.
+    pub no_proxy: Vec<NoProxyPattern>,
+    /// Forward origin-form requests (`GET /path`) to this base URL as a
+    /// reverse proxy, with the SSRF check waived for it. Every path goes to
+    /// the upstream, so the proxy's own `/health`, `/ready`, `/metrics`, and
+    /// `/stats` are not served. `None` serves only absolute-form requests.
+    pub reverse_upstream: Option<Url>,
+    /// Leading path segment(s) removed from reverse-proxied requests before
+    /// they are appended to `reverse_upstream`'s path.
+    pub strip_prefix: Option<String>,
+    /// Looks up upstream names: `--hosts-override` pins first, then the
+    /// `--resolver` server, then the system resolver.
+    pub resolver: Arc<Resolver>,
+    /// Make every upstream connection from this local address, for hosts
+    /// with several. `None` lets the OS pick.
+    pub egress_bind: Option<IpAddr>,
+    /// Answer `503` without connecting to upstream hosts that keep failing.
+    /// `None` always tries the upstream.
+    pub circuit_breaker: Option<Arc<CircuitBreaker>>,
+    /// Answer `/ready` with `503` while this target refuses connections.
+    /// `None` makes `/ready` the same as `/health`.
+    pub ready_probe: Option<Arc<ReadinessProbe>>,
+    /// Per-host `DIRECT`/`PROXY`/`BLOCK` rules from `--routes`. Read through
+    /// `route_for()`.
+    pub routes: RouteTable,
+    /// Delay 403/405 rejections of blocked requests by this long to tie up
+    /// scanners. `None` answers immediately.
+    pub tarpit: Option<Duration>,
+    /// Capacity of the client read and write buffers and of each tunnel
+    /// direction's copy buffer. Read through `io_buffer_size()`.
+    pub io_buffer_size: Option<usize>,
+    /// Disable Nagle's algorithm on client sockets and on upstream sockets
+    /// opened outside reqwest (which always does).
+    pub tcp_nodelay: bool,
+    /// Start TCP keepalive probes after a socket has been idle this long,
+    /// on client and upstream sockets alike. `None` leaves client and
+    /// tunnel sockets at the OS default and pooled upstream connections at
+    /// 60 seconds.
+    pub tcp_keepalive: Option<Duration>,
+}
+
+impl ProxyConfig {
+    /// `io_buffer_size`, defaulting to `IO_BUFFER_SIZE` and clamped to
+    /// `MIN_IO_BUFFER_SIZE..=MAX_IO_BUFFER_SIZE`.
+    pub fn io_buffer_size(&self) -> usize {
+        self.io_buffer_size
+            .unwrap_or(constants::IO_BUFFER_SIZE)
+            .clamp(constants::MIN_IO_BUFFER_SIZE, constants::MAX_IO_BUFFER_SIZE)
+    }
+
+    /// Apply `tcp_nodelay` and `tcp_keepalive` to a client or upstream
+    /// socket. Failures are logged: the connection still works without them.
+    pub fn configure_socket(&self, stream: &TcpStream) {
+        if self.tcp_nodelay {
+            if let Err(e) = stream.set_nodelay(true) {
+                debug!("Failed to set TCP_NODELAY: {}", e);
+            }
+        }
+        if let Some(idle) = self.tcp_keepalive {
+            let keepalive = socket2::TcpKeepalive::new().with_time(idle);
+            if let Err(e) = socket2::SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+                debug!("Failed to enable TCP keepalive: {}", e);
+            }
+        }
+    }
+
+    /// Whether `method` may be served under `allowed_methods`,
+    /// `denied_methods`, and the default `TRACE`/`TRACK` block.
+    pub fn method_allowed(&self, method: &Method) -> bool {
+        if !self.allow_trace && constants::CROSS_SITE_TRACING_METHODS.contains(&method.as_str()) {
+            return false;
+        }
+        !self.denied_methods.contains(method)
+            && self
+                .allowed_methods
+                .as_ref()
+                .is_none_or(|allowed| allowed.contains(method))
+    }
+
+    /// The `Allow` value for a `405`: the `allowed_methods` that
+    /// `method_allowed` still passes. `None` without an allowlist, since
+    /// every method but the refused ones may then be served.
+    pub fn allow_header(&self) -> Option<String> {
+        let allowed = self.allowed_methods.as_ref()?;
+        let methods: Vec<&str> = allowed
+            .iter()
+            .filter(|method| self.method_allowed(method))
+            .map(Method::as_str)
+            .collect();
+        Some(methods.join(", "))
+    }
+
+    /// Whether a request line's `version` may be served under
+    /// `reject_http10`.
+    pub fn http_version_allowed(&self, version: Version) -> bool {
+        !(self.reject_http10 && version == Version::HTTP_10)
+    }
+
+    /// Where traffic for `host` goes: the first matching `routes` rule, or
+    /// through `upstream_proxy` if none match. Directly without one, or if
+    /// `host` is in `no_proxy`.
+    pub fn route_for(&self, host: &str) -> Route {
+        match self.routes.route_for(host) {
+            Some(route) => route.clone(),
+            None => match &self.upstream_proxy {
+                Some(proxy) if !self.no_proxy.iter().any(|p| p.matches(host)) => {
+                    Route::Proxy(proxy.clone())
+                }
+                _ => Route::Direct,
+            },
+        }
+    }
+}
+
+/// A set of ports parsed from a comma-separated list such as `443,8000-8100`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortRanges(Vec<RangeInclusive<u16>>);
+
+impl PortRanges {
+    pub fn contains(&self, port: u16) -> bool {
+        self.0.iter().any(|range| range.contains(&port))
+    }
+}
+
+impl Default for PortRanges {
+    fn default() -> Self {
+        Self(vec![443..=443])
+    }
+}
+
+impl std::str::FromStr for PortRanges {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_port = |p: &str| {
+            p.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("invalid port: {}", p.trim()))
+        };
+        let mut ranges = Vec::new();
+        for part in s.split(',').filter(|p| !p.trim().is_empty()) {
+            let range = match part.split_once('-') {
+                Some((start, end)) => {
+                    let (start, end) = (parse_port(start)?, parse_port(end)?);
+                    if start > end {
+                        return Err(format!("port range is reversed: {}", part.trim()));
+                    }
+                    start..=end
+                }
+                None => {
+                    let port = parse_port(part)?;
+                    port..=port
+                }
+            };
+            ranges.push(range);
+        }
+        if ranges.is_empty() {
+            return Err("no ports given".to_string());
+        }
+        Ok(Self(ranges))
+    }
+}
+
+/// A daily window such as `09:00-17:00` outside which requests are refused.
+/// Times are in `utc_offset`; a window that ends before it starts runs past
+/// midnight (`22:00-06:00`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllowedHours {
+    /// Minutes after midnight.
+    start: u32,
+    end: u32,
+    utc_offset: UtcOffset,
+}
+
+impl AllowedHours {
+    pub fn with_utc_offset(self, utc_offset: UtcOffset) -> Self {
+        Self { utc_offset, ..self }
+    }
+
+    pub fn is_open_at(&self, now: SystemTime) -> bool {
+        let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64
+            + self.utc_offset.0 as i64 * 60;
+        let minute = (secs.rem_euclid(86_400) / 60) as u32;
+        if self.start < self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl std::fmt::Display for AllowedHours {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02} {}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60,
+            self.utc_offset
+        )
+    }
+}
+
+impl std::str::FromStr for AllowedHours {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("expected HH:MM-HH:MM, got {}", s))?;
+        let parse_time = |t: &str| {
+            let t = t.trim();
+            let minutes = t
+                .split_once(':')
+                .and_then(|(h, m)| Some((h.parse::<u32>().ok()?, m.parse::<u32>().ok()?)))
+                .filter(|&(h, m)| m < 60 && (h < 24 || (h == 24 && m == 0)))
+                .map(|(h, m)| h * 60 + m);
+            minutes.ok_or_else(|| format!("invalid time of day: {}", t))
+        };
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        if start % (24 * 60) == end % (24 * 60) {
+            return Err(format!("window is empty: {}", s.trim()));
+        }
+        Ok(Self {
+            start: start % (24 * 60),
+            end,
+            utc_offset: UtcOffset::default(),
+        })
+    }
+}
+
+/// A fixed offset from UTC, parsed from `UTC`, `+02:00`, or `-0530`.
+/// Named time zones and daylight saving are not supported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UtcOffset(i32);
+
+impl std::fmt::Display for UtcOffset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0 == 0 {
+            return f.write_str("UTC");
+        }
+        let sign = if self.0 < 0 { '-' } else { '+' };
+        let minutes = self.0.unsigned_abs();
+        write!(f, "UTC{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+    }
+}
+
+impl std::str::FromStr for UtcOffset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let offset = s
+            .strip_prefix("UTC")
+            .or_else(|| s.strip_prefix("utc"))
+            .unwrap_or(s);
+        if offset.is_empty() || offset == "Z" {
+            return Ok(Self(0));
+        }
+        let invalid = || format!("invalid UTC offset: {}", s);
+        let (sign, rest) = match offset.split_at(1) {
+            ("+", rest) => (1, rest),
+            ("-", rest) => (-1, rest),
+            _ => return Err(invalid()),
+        };
+        let digits = rest.replace(':', "");
+        if !matches!(digits.len(), 2 | 4) || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let (hours, minutes) = digits.split_at(2);
+        let hours: i32 = hours.parse().map_err(|_| invalid())?;
+        let minutes: i32 = match minutes {
+            "" => 0,
+            minutes => minutes.parse().map_err(|_| invalid())?,
+        };
+        if hours > 14 || minutes >= 60 {
+            return Err(invalid());
+        }
+        Ok(Self(sign * (hours * 60 + minutes)))
+    }
+}
+
+/// Parse a duration such as `500ms`, `1.5s`, `2m`, or `1h`. A bare number is
+/// seconds, so values written for the older whole-second flags still work.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let unit_start = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(unit_start);
+    let value: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration: {}", s))?;
+    let nanos_per_unit = match unit.trim() {
+        "ms" => 1e6,
+        "" | "s" => 1e9,
+        "m" => 60e9,
+        "h" => 3600e9,
+        _ => {
+            return Err(format!(
+                "invalid duration unit in {}: expected ms, s, m, or h",
+                s
+            ))
+        }
+    };
+    // Round in nanoseconds so `0.3s` doesn't come out a hair short.
+    let nanos = (value * nanos_per_unit).round();
+    if nanos > u64::MAX as f64 {
+        return Err(format!("duration too large: {}", s));
+    }
+    Ok(Duration::from_nanos(nanos as u64))
+}
+
+/// Adds `Deprecation: true`, and `Sunset` when a date is given, to responses
+/// for matching request paths. Parsed from `PATTERN[=SUNSET]`, where a
+/// trailing `*` in the pattern makes it a prefix match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathDeprecation {
+    pub pattern: String,
+    /// An HTTP-date, e.g. `Wed, 31 Dec 2025 23:59:59 GMT`.
+    pub sunset: Option<String>,
+}
+
+impl PathDeprecation {
+    pub fn matches(&self, path: &str) -> bool {
+        match self.pattern.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == self.pattern,
+        }
+    }
+}
+
+impl std::str::FromStr for PathDeprecation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, sunset) = match s.split_once('=') {
+            Some((pattern, sunset)) => (pattern, Some(sunset.trim())),
+            None => (s, None),
+        };
+        if !pattern.starts_with('/') {
+            return Err(format!("path pattern must start with '/': {}", pattern));
+        }
+        if sunset.is_some_and(str::is_empty) {
+            return Err(format!("empty sunset date for {}", pattern));
+        }
+        Ok(Self {
+            pattern: pattern.to_string(),
+            sunset: sunset.map(str::to_string),
+        })
+    }
+}
+
+/// Connect to `host` but present and verify `name` over TLS. Parsed from
+/// `HOST=NAME`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamSni {
+    pub host: String,
+    pub name: String,
+}
+
+impl std::str::FromStr for UpstreamSni {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, name) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected HOST=NAME, got {}", s))?;
+        let (host, name) = (host.trim(), name.trim().to_ascii_lowercase());
+        if host.is_empty() || name.is_empty() {
+            return Err(format!("expected HOST=NAME, got {}", s));
+        }
+        // The name replaces the target URL's host, so it must survive as one
+        // unchanged.
+        let valid = Url::parse(&format!("https://{}/", name))
+            .is_ok_and(|url| url.host_str() == Some(name.as_str()));
+        if !valid {
+            return Err(format!("invalid server name: {}", name));
+        }
+        Ok(Self {
+            host: host.to_string(),
+            name,
+        })
+    }
+}
+
+/// A header `--set-header` or `--set-response-header` adds, replacing any
+/// the message already has. Parsed from `NAME:VALUE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetHeader {
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+impl std::str::FromStr for SetHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected NAME:VALUE, got {}", s))?;
+        Ok(Self {
+            name: rewritable_header(name)?,
+            value: HeaderValue::from_str(value.trim())
+                .map_err(|_| format!("invalid value for {}: {}", name.trim(), value))?,
+        })
+    }
+}
+
+/// A header `--remove-header` or `--remove-response-header` drops.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoveHeader(pub HeaderName);
+
+impl std::str::FromStr for RemoveHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        rewritable_header(s).map(Self)
+    }
+}
+
+/// An `--intercept-status` rule: upstream responses with a matching status
+/// are replaced with `body`, keeping their status unless `status` is set.
+/// Parsed from `CODE[=STATUS]:FILE`, where `CODE` is a status such as `503`
+/// or a class such as `5xx`; the file is read when the rule is parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterceptStatus {
+    /// The status, or with `class`, its first digit times 100.
+    code: u16,
+    class: bool,
+    pub status: Option<u16>,
+    pub content_type: &'static str,
+    pub body: Arc<[u8]>,
+}
+
+impl InterceptStatus {
+    pub fn matches(&self, status: u16) -> bool {
+        if self.class {
+            status / 100 == self.code / 100
+        } else {
+            status == self.code
+        }
+    }
+}
+
+impl std::str::FromStr for InterceptStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (codes, path) = s
+            .split_once(':')
+            .filter(|(_, path)| !path.is_empty())
+            .ok_or_else(|| format!("expected CODE[=STATUS]:FILE, got {}", s))?;
+        let (code, status) = match codes.split_once('=') {
+            Some((code, status)) => (code, Some(status)),
+            None => (codes, None),
+        };
+        let parse_status = |s: &str| {
+            s.parse::<u16>()
+                .ok()
+                .filter(|code| (100..=599).contains(code))
+                .ok_or_else(|| format!("invalid status code: {}", s))
+        };
+        let (code, class) = match code.to_ascii_lowercase().strip_suffix("xx") {
+            Some(digit) => (parse_status(&format!("{}00", digit))?, true),
+            None => (parse_status(code)?, false),
+        };
+        let status = status.map(parse_status).transpose()?;
+        if status.is_some_and(|status| status < 200) {
+            return Err(format!("cannot answer with an interim status: {}", s));
+        }
+        let body = std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        let content_type = match std::path::Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_ascii_lowercase)
+            .as_deref()
+        {
+            Some("html" | "htm") => "text/html; charset=utf-8",
+            Some("json") => "application/json",
+            _ => "text/plain; charset=utf-8",
+        };
+        Ok(Self {
+            code,
+            class,
+            status,
+            content_type,
+            body: body.into(),
+        })
+    }
+}
+
+/// Parse a header name for a rewrite rule. Headers that frame the message,
+/// route it, carry the request ID, or are hop-by-hop are managed by the proxy
+/// and can't be rewritten.
+fn rewritable_header(name: &str) -> Result<HeaderName, String> {
+    let name = name.trim();
+    let header = HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("invalid header name: {}", name))?;
+    if constants::PROTECTED_HEADERS.contains(&header.as_str())
+        || crate::protocol::http::is_hop_by_hop_header(header.as_str())
+    {
+        return Err(format!(
+            "{} is managed by the proxy and can't be rewritten",
+            header
+        ));
+    }
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration_units() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("0.3s"), Ok(Duration::from_millis(300)));
+        // Bare numbers are seconds, as the flags used to take.
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_parse_duration_rejects_bad_input() {
+        for bad in ["", "ms", "1.2.3s", "-1s", "5 days", "1e3s", "10x"] {
+            assert!(parse_duration(bad).is_err(), "Expected {:?} to fail", bad);
+        }
+    }
+
+    #[test]
+    fn test_header_rewrite_rules_parse() {
+        let rule: SetHeader = " X-Env : prod ".parse().unwrap();
+        assert_eq!(rule.name, "x-env");
+        assert_eq!(rule.value, "prod");
+        let rule: SetHeader = "X-Empty:".parse().unwrap();
+        assert_eq!(rule.value, "");
+        assert_eq!(
+            "Cookie".parse::<RemoveHeader>().unwrap(),
+            RemoveHeader(HeaderName::from_static("cookie"))
+        );
+
+        for bad in ["no-colon", "bad name:x", "x-ok:line\nbreak"] {
+            assert!(bad.parse::<SetHeader>().is_err(), "{:?} should fail", bad);
+        }
+        for protected in ["Host", "Content-Length", "x-request-id", "Connection"] {
+            assert!(protected.parse::<RemoveHeader>().is_err());
+            assert!(format!("{}:x", protected).parse::<SetHeader>().is_err());
+        }
+    }
+
+    #[test]
+    fn test_intercept_status_parse() {
+        let mut page = tempfile::Builder::new().suffix(".html").tempfile().unwrap();
+        std::io::Write::write_all(&mut page, b"<h1>Down</h1>").unwrap();
+        let path = page.path().display();
+
+        let rule: InterceptStatus = format!("503:{}", path).parse().unwrap();
+        assert!(rule.matches(503) && !rule.matches(502));
+        assert_eq!(rule.status, None);
+        assert_eq!(rule.content_type, "text/html; charset=utf-8");
+        assert_eq!(&*rule.body, b"<h1>Down</h1>");
+
+        let rule: InterceptStatus = format!("5XX=502:{}", path).parse().unwrap();
+        assert!(rule.matches(500) && rule.matches(599) && !rule.matches(404));
+        assert_eq!(rule.status, Some(502));
+
+        for bad in [
+            "503".to_string(),
+            "503:".to_string(),
+            format!("99:{}", path),
+            format!("6xx:{}", path),
+            format!("503=100:{}", path),
+            "503:/nonexistent/page.html".to_string(),
+        ] {
+            assert!(
+                bad.parse::<InterceptStatus>().is_err(),
+                "{:?} should fail",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_upstream_sni_parse() {
+        let sni: UpstreamSni = "10.0.0.5=API.internal.example".parse().unwrap();
+        assert_eq!(sni.host, "10.0.0.5");
+        assert_eq!(sni.name, "api.internal.example");
+        for bad in [
+            "10.0.0.5",
+            "=api.example",
+            "10.0.0.5=",
+            "10.0.0.5=a b",
+            "h=x/y",
+        ] {
+            assert!(
+                bad.parse::<UpstreamSni>().is_err(),
+                "Expected {:?} to fail",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_path_deprecation_parse_and_match() {
+        let rule: PathDeprecation = "/api/v1/*=Wed, 31 Dec 2025 23:59:59 GMT".parse().unwrap();
+        assert_eq!(
+            rule.sunset.as_deref(),
+            Some("Wed, 31 Dec 2025 23:59:59 GMT")
+        );
+        assert!(rule.matches("/api/v1/users"));
+        assert!(!rule.matches("/api/v2/users"));
+
+        let exact: PathDeprecation = "/legacy".parse().unwrap();
+        assert_eq!(exact.sunset, None);
+        assert!(exact.matches("/legacy"));
+        assert!(!exact.matches("/legacy/more"));
+    }
+
+    #[test]
+    fn test_allowed_hours_window() {
+        let at =
+            |h: u64, m: u64| UNIX_EPOCH + Duration::from_secs(19_000 * 86_400 + h * 3600 + m * 60);
+
+        let office: AllowedHours = "09:00-17:00".parse().unwrap();
+        assert!(!office.is_open_at(at(8, 59)));
+        assert!(office.is_open_at(at(9, 0)));
+        assert!(office.is_open_at(at(16, 59)));
+        assert!(!office.is_open_at(at(17, 0)));
+
+        let overnight: AllowedHours = "22:00-06:00".parse().unwrap();
+        assert!(overnight.is_open_at(at(23, 30)));
+        assert!(overnight.is_open_at(at(5, 59)));
+        assert!(!overnight.is_open_at(at(12, 0)));
+
+        // 07:30 UTC is 09:30 at UTC+02:00.
+        let shifted = office.with_utc_offset("+02:00".parse().unwrap());
+        assert!(shifted.is_open_at(at(7, 30)));
+        assert!(!shifted.is_open_at(at(15, 30)));
+        assert_eq!(shifted.to_string(), "09:00-17:00 UTC+02:00");
+    }
+
+    #[test]
+    fn test_allowed_hours_and_offset_reject_bad_input() {
+        for bad in [
+            "",
+            "09:00",
+            "9-17",
+            "09:00-09:00",
+            "25:00-26:00",
+            "09:60-17:00",
+        ] {
+            assert!(
+                bad.parse::<AllowedHours>().is_err(),
+                "Expected {:?} to fail",
+                bad
+            );
+        }
+        assert_eq!("UTC".parse::<UtcOffset>(), Ok(UtcOffset(0)));
+        assert_eq!("-0530".parse::<UtcOffset>(), Ok(UtcOffset(-330)));
+        assert_eq!("UTC+01".parse::<UtcOffset>(), Ok(UtcOffset(60)));
+        for bad in ["CET", "+1", "+15:00", "+01:75"] {
+            assert!(
+                bad.parse::<UtcOffset>().is_err(),
+                "Expected {:?} to fail",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_io_buffer_size_defaults_and_clamps() {
+        let size = |io_buffer_size| {
+            ProxyConfig {
+                io_buffer_size,
+                ..Default::default()
+            }
+            .io_buffer_size()
+        };
+        assert_eq!(size(None), constants::IO_BUFFER_SIZE);
+        assert_eq!(size(Some(64 * 1024)), 64 * 1024);
+        assert_eq!(size(Some(0)), constants::MIN_IO_BUFFER_SIZE);
+        assert_eq!(size(Some(usize::MAX)), constants::MAX_IO_BUFFER_SIZE);
+    }
+
+    #[tokio::test]
+    async fn test_configure_socket_sets_tcp_options() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+
+        ProxyConfig::default().configure_socket(&accepted);
+        assert!(!accepted.nodelay().unwrap());
+        assert!(!socket2::SockRef::from(&accepted).keepalive().unwrap());
+
+        ProxyConfig {
+            tcp_nodelay: true,
+            tcp_keepalive: Some(Duration::from_secs(45)),
+            ..Default::default()
+        }
+        .configure_socket(&accepted);
+        assert!(accepted.nodelay().unwrap());
+        let socket = socket2::SockRef::from(&accepted);
+        assert!(socket.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            socket.tcp_keepalive_time().unwrap(),
+            Duration::from_secs(45)
+        );
+    }
+
+    #[test]
+    fn test_port_ranges_parse_and_match() {
+        let ports: PortRanges = "443, 8000-8100,9443".parse().unwrap();
+        assert!(ports.contains(443));
+        assert!(ports.contains(8000));
+        assert!(ports.contains(8050));
+        assert!(ports.contains(8100));
+        assert!(ports.contains(9443));
+        assert!(!ports.contains(25));
+        assert!(!ports.contains(8101));
+
+        let default = PortRanges::default();
+        assert!(default.contains(443));
+        assert!(!default.contains(80));
+    }
+
+    #[test]
+    fn test_port_ranges_rejects_bad_input() {
+        assert!("".parse::<PortRanges>().is_err());
+        assert!("https".parse::<PortRanges>().is_err());
+        assert!("70000".parse::<PortRanges>().is_err());
+        assert!("9000-8000".parse::<PortRanges>().is_err());
+    }
+
+    #[test]
+    fn test_path_deprecation_rejects_bad_input() {
+        assert!("api/v1/*".parse::<PathDeprecation>().is_err());
+        assert!("/api/v1/*=".parse::<PathDeprecation>().is_err());
+    }
+
+    #[test]
+    fn test_allow_header_follows_effective_policy() {
+        let purge = Method::from_bytes(b"PURGE").unwrap();
+        let mut config = ProxyConfig {
+            denied_methods: vec![purge.clone()],
+            ..Default::default()
+        };
+        assert_eq!(config.allow_header(), None);
+
+        config.allowed_methods = Some(vec![Method::GET, purge, Method::TRACE]);
+        assert_eq!(config.allow_header().as_deref(), Some("GET"));
+        config.allow_trace = true;
+        assert_eq!(config.allow_header().as_deref(), Some("GET, TRACE"));
+    }
+}
@@ -1,15 +1,113 @@
-pub const BAD_GATEWAY_RESPONSE: &[u8] = b"HTTP/1.1 502 Bad Gateway\r\n\r\n";
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
 pub const BAD_REQUEST_RESPONSE: &[u8] = b"HTTP/1.1 400 Bad Request\r\n\r\n";
-pub const FORBIDDEN_RESPONSE: &[u8] = b"HTTP/1.1 403 Forbidden\r\n\r\n";
+/// Followed by an `Allow` header listing the permitted methods.
+pub const METHOD_NOT_ALLOWED_STATUS_LINE: &[u8] = b"HTTP/1.1 405 Method Not Allowed\r\n";
+/// Refused unless `--allow-trace` is given: they echo the request back,
+/// exposing headers such as cookies to script (cross-site tracing).
+pub const CROSS_SITE_TRACING_METHODS: &[&str] = &["TRACE", "TRACK"];
+pub const REQUEST_HEADER_FIELDS_TOO_LARGE_RESPONSE: &[u8] =
+    b"HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n";
+pub const URI_TOO_LONG_RESPONSE: &[u8] = b"HTTP/1.1 414 URI Too Long\r\n\r\n";
+pub const HTTP_VERSION_NOT_SUPPORTED_RESPONSE: &[u8] =
+    b"HTTP/1.1 505 HTTP Version Not Supported\r\n\r\n";
+pub const REQUEST_TIMEOUT_RESPONSE: &[u8] =
+    b"HTTP/1.1 408 Request Timeout\r\nConnection: close\r\n\r\n";
+pub const PROXY_AUTH_REQUIRED_RESPONSE: &[u8] =
+    b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"rhoxy\"\r\n\r\n";
+pub const CONTINUE_RESPONSE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
 pub const CONNECTION_ESTABLISHED_RESPONSE: &[u8] = b"HTTP/1.1 200 Connection Established\r\n\r\n";
 
+/// Lowercase, matching how request headers are stored after parsing.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+pub const LATENCY_HEADER: &str = "x-proxy-latency-ms";
+/// Headers `--set-header` and friends may not touch: they frame or route the
+/// message, or tie it to its logs.
+pub const PROTECTED_HEADERS: &[&str] = &[
+    "host",
+    "content-length",
+    "content-encoding",
+    "transfer-encoding",
+    REQUEST_ID_HEADER,
+    LATENCY_HEADER,
+];
+
 pub const HEALTH_ENDPOINT_PATH: &str = "/health";
 pub const HEALTH_CHECK_RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK";
 
+pub const READY_ENDPOINT_PATH: &str = "/ready";
+pub const NOT_READY_RESPONSE: &[u8] =
+    b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 9\r\n\r\nNOT READY";
+/// How long a `--ready-probe` result is reused before probing again.
+pub const READY_PROBE_CACHE_TTL: Duration = Duration::from_secs(5);
+pub const READY_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub const METRICS_ENDPOINT_PATH: &str = "/metrics";
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+pub const STATS_ENDPOINT_PATH: &str = "/stats";
+
+/// Distinct `--tenant-header` values tracked before further tenants are
+/// counted together as `(other)`, keeping the metric series bounded.
+pub const MAX_TENANT_LABELS: usize = 100;
+pub const MAX_TENANT_LABEL_LEN: usize = 64;
+/// Longest client-supplied `X-Request-Id` honored; UUIDs and typical trace
+/// IDs fit comfortably.
+pub const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Cloud instance metadata endpoints, refused even with
+/// `--allow-private-addresses`.
+pub const METADATA_ADDRESSES: &[IpAddr] = &[
+    // AWS, GCP, Azure, OpenStack and most others.
+    IpAddr::V4(Ipv4Addr::new(169, 254, 169, 254)),
+    // AWS over IPv6.
+    IpAddr::V6(Ipv6Addr::new(0xfd00, 0xec2, 0, 0, 0, 0, 0, 0x254)),
+    // Alibaba Cloud.
+    IpAddr::V4(Ipv4Addr::new(100, 100, 100, 200)),
+];
+
 pub const CONNECTION_TIMEOUT_SECS: u64 = 60;
+/// With `--idle-timeout`, the cap on a connection's whole lifetime that
+/// replaces `CONNECTION_TIMEOUT_SECS`.
+pub const IDLE_CONNECTION_MAX_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// First and longest pause after `accept()` fails for lack of resources
+/// (e.g. file descriptors); the pause doubles while failures continue.
+pub const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+pub const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+/// How long `Server` lets in-flight connections finish once shut down; the
+/// binary's `--shutdown-grace` defaults to the same.
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+/// How long `--log-sni` waits for the rest of a ClientHello once a CONNECT
+/// client has started one; the tunnel carries on without the SNI after that.
+pub const SNI_READ_TIMEOUT: Duration = Duration::from_secs(5);
+pub const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
+pub const UPSTREAM_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause before the first `--retries` attempt; each further attempt waits
+/// this much longer.
+pub const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+/// Content types `--compress-responses` gzips; other types are typically
+/// already compressed.
+pub const COMPRESSIBLE_CONTENT_TYPES: &[&str] = &["text/*", "application/json"];
+/// How long a `--resolver` DNS server gets to answer a lookup.
+pub const DNS_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a connect attempt gets before the next address is tried
+/// alongside it (RFC 8305's recommended default).
+pub const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
 pub const MAX_CONCURRENT_CONNECTIONS: usize = 1024;
+/// Hosts the circuit breaker tracks before it forgets settled ones.
+pub const MAX_CIRCUIT_BREAKER_HOSTS: usize = 10_000;
 
+/// Default for `--max-request-line`.
 pub const MAX_REQUEST_LINE_LEN: usize = 8192;
 pub const MAX_HEADER_LINE_LEN: usize = 8192;
 pub const MAX_HEADER_COUNT: usize = 100;
+/// Default cap on the whole header section, summed across lines.
+pub const MAX_HEADER_BYTES: usize = 64 * 1024;
 pub const MAX_BODY_SIZE: usize = 10 * 1024 * 1024; // 10 MiB
+
+/// Default capacity of client read/write buffers and tunnel copy buffers,
+/// and the bounds `--io-buffer-size` is clamped to.
+pub const IO_BUFFER_SIZE: usize = 8 * 1024;
+pub const MIN_IO_BUFFER_SIZE: usize = 1024;
+pub const MAX_IO_BUFFER_SIZE: usize = 1024 * 1024;
@@ -0,0 +1,164 @@
+//! Byte-counting wrappers for any reader or writer. Around the client they
+//! give the access log and metrics what actually crossed the wire (request
+//! line, headers, and body in; status line, headers, and body out); around a
+//! tunnel's halves they give its byte totals.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
+
+/// Counts bytes handed to the caller, whether through `AsyncRead` or
+/// `AsyncBufRead`. Bytes buffered by `inner` but never consumed are not
+/// counted.
+#[derive(Debug)]
+pub struct CountingReader<R> {
+    inner: R,
+    bytes: u64,
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, bytes: 0 }
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            self.bytes += (buf.filled().len() - before) as u64;
+        }
+        poll
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for CountingReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().inner).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.bytes += amt as u64;
+        Pin::new(&mut self.inner).consume(amt);
+    }
+}
+
+/// Counts bytes accepted by `inner`. Behind a `BufWriter` these are only
+/// on the wire once flushed, which every response path does.
+#[derive(Debug)]
+pub struct CountingWriter<W> {
+    inner: W,
+    bytes: u64,
+}
+
+impl<W> CountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, bytes: 0 }
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.bytes += written as u64;
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    #[tokio::test]
+    async fn test_reader_counts_consumed_bytes_only() {
+        let mut reader = CountingReader::new(BufReader::new(Cursor::new(
+            b"GET / HTTP/1.1\r\nbody-and-more".to_vec(),
+        )));
+
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        assert_eq!(reader.bytes(), 16);
+
+        let mut body = [0u8; 4];
+        reader.read_exact(&mut body).await.unwrap();
+        assert_eq!(reader.bytes(), 20, "Buffered but unread bytes don't count");
+    }
+
+    #[tokio::test]
+    async fn test_counts_both_directions_of_a_socket() {
+        // A small duplex buffer forces partial reads and writes.
+        let (ours, mut peer) = tokio::io::duplex(16);
+        let (read_half, write_half) = tokio::io::split(ours);
+        let mut reader = CountingReader::new(BufReader::new(read_half));
+        let mut writer = CountingWriter::new(write_half);
+
+        let inbound = vec![b'a'; 1000];
+        let outbound = vec![b'b'; 700];
+        let peer_side = async {
+            peer.write_all(&inbound).await.unwrap();
+            peer.shutdown().await.unwrap();
+            let mut received = vec![0u8; outbound.len()];
+            peer.read_exact(&mut received).await.unwrap();
+            received
+        };
+        let our_side = async {
+            let mut sink = Vec::new();
+            tokio::io::copy_buf(&mut reader, &mut sink).await.unwrap();
+            writer.write_all(&outbound).await.unwrap();
+            writer.flush().await.unwrap();
+            sink
+        };
+        let (received, sink) = tokio::join!(peer_side, our_side);
+
+        assert_eq!(sink, inbound);
+        assert_eq!(received, outbound);
+        assert_eq!(reader.bytes(), 1000);
+        assert_eq!(writer.bytes(), 700);
+    }
+
+    #[tokio::test]
+    async fn test_writer_counts_written_bytes() {
+        let mut writer = CountingWriter::new(Vec::new());
+        writer.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+        writer.write_all(b"hello").await.unwrap();
+        assert_eq!(writer.bytes(), 24);
+    }
+}
@@ -0,0 +1,336 @@
+//! `--hosts-override` and `--resolver`: where upstream names are looked up.
+//! An override pins a name to fixed addresses; other names go to the
+//! configured DNS server, queried directly over UDP for A and AAAA records,
+//! or to the system resolver without one. The SSRF checks run on whatever
+//! addresses come back, exactly as for system lookups.
+
+use anyhow::Result;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tracing::debug;
+
+use crate::constants;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+/// Largest reply read; longer ones arrive truncated over UDP anyway.
+const MAX_RESPONSE: usize = 4096;
+
+/// Resolve `HOST` to `IP` instead of asking DNS. Parsed from `HOST=IP`;
+/// repeat a host to give it several addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostOverride {
+    pub host: String,
+    pub ip: IpAddr,
+}
+
+impl std::str::FromStr for HostOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, ip) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected HOST=IP, got {}", s))?;
+        let host = host.trim().trim_end_matches('.').to_ascii_lowercase();
+        if host.is_empty() {
+            return Err(format!("expected HOST=IP, got {}", s));
+        }
+        let ip = ip
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid IP address in {}", s))?;
+        Ok(Self { host, ip })
+    }
+}
+
+/// A DNS server to query, from `IP` or `IP:PORT` (port 53 by default).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DnsServer(pub SocketAddr);
+
+impl std::str::FromStr for DnsServer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let addr = s
+            .parse::<SocketAddr>()
+            .or_else(|_| s.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+            .map_err(|_| format!("expected IP or IP:PORT, got {}", s))?;
+        Ok(Self(addr))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Resolver {
+    overrides: Vec<HostOverride>,
+    server: Option<SocketAddr>,
+}
+
+impl Resolver {
+    pub fn new(overrides: Vec<HostOverride>, server: Option<DnsServer>) -> Self {
+        Self {
+            overrides,
+            server: server.map(|DnsServer(addr)| addr),
+        }
+    }
+
+    /// Addresses for `host`, each with `port`. IP literals (bracketed or
+    /// not) are returned as they are.
+    pub async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let literal = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = literal.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+
+        let name = host.trim_end_matches('.');
+        let pinned: Vec<SocketAddr> = self
+            .overrides
+            .iter()
+            .filter(|o| o.host.eq_ignore_ascii_case(name))
+            .map(|o| SocketAddr::new(o.ip, port))
+            .collect();
+        if !pinned.is_empty() {
+            debug!("Resolved {} from --hosts-override", host);
+            return Ok(pinned);
+        }
+
+        match self.server {
+            Some(server) => {
+                let query = query_server(server, name);
+                let ips = tokio::time::timeout(constants::DNS_QUERY_TIMEOUT, query)
+                    .await
+                    .map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("DNS server {} did not answer for {}", server, name),
+                        )
+                    })??;
+                Ok(ips
+                    .into_iter()
+                    .map(|ip| SocketAddr::new(ip, port))
+                    .collect())
+            }
+            None => Ok(tokio::net::lookup_host((name, port)).await?.collect()),
+        }
+    }
+}
+
+/// Ask `server` for `name`'s A and AAAA records at once. Either query
+/// failing is tolerated as long as the other returns addresses.
+async fn query_server(server: SocketAddr, name: &str) -> io::Result<Vec<IpAddr>> {
+    let (v4, v6) = tokio::join!(
+        query_type(server, name, TYPE_A),
+        query_type(server, name, TYPE_AAAA)
+    );
+    match (v4, v6) {
+        (Ok(mut v4), Ok(v6)) => {
+            v4.extend(v6);
+            Ok(v4)
+        }
+        (Ok(ips), Err(e)) | (Err(e), Ok(ips)) if !ips.is_empty() => {
+            debug!("DNS lookup of {} partly failed: {}", name, e);
+            Ok(ips)
+        }
+        (Err(e), _) | (_, Err(e)) => Err(e),
+    }
+}
+
+async fn query_type(server: SocketAddr, name: &str, qtype: u16) -> io::Result<Vec<IpAddr>> {
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    // Connected, so replies from anyone but the server are discarded.
+    socket.connect(server).await?;
+
+    let id = u16::from_be_bytes(*uuid::Uuid::new_v4().as_bytes().first_chunk().unwrap());
+    socket.send(&encode_query(id, name, qtype)?).await?;
+    let mut buf = vec![0u8; MAX_RESPONSE];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        // A reply to some earlier, abandoned query; keep waiting for ours.
+        if len >= 2 && buf[..2] != id.to_be_bytes() {
+            continue;
+        }
+        return parse_response(&buf[..len], qtype);
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// A recursive query for `name` (RFC 1035 section 4.1).
+fn encode_query(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(18 + name.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired; one question, no other records.
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(invalid(format!("invalid DNS name: {}", name)));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// The `qtype` addresses in a response's answer section. Records of other
+/// types, such as the CNAMEs leading to the addresses, are skipped.
+fn parse_response(msg: &[u8], qtype: u16) -> io::Result<Vec<IpAddr>> {
+    let truncated = || invalid("truncated DNS response");
+    let u16_at = |at: usize| -> io::Result<u16> {
+        msg.get(at..at + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(truncated)
+    };
+
+    let flags = u16_at(2)?;
+    if flags & 0x8000 == 0 {
+        return Err(invalid("DNS reply is not a response"));
+    }
+    match flags & 0x000f {
+        0 => {}
+        // NXDOMAIN: the name doesn't exist, so there's nothing to connect to.
+        3 => return Ok(Vec::new()),
+        rcode => return Err(invalid(format!("DNS server answered rcode {}", rcode))),
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+
+    let mut at = 12;
+    for _ in 0..questions {
+        at = skip_name(msg, at)? + 4;
+    }
+    let mut ips = Vec::new();
+    for _ in 0..answers {
+        at = skip_name(msg, at)?;
+        let rtype = u16_at(at)?;
+        let class = u16_at(at + 2)?;
+        let rdlen = u16_at(at + 8)? as usize;
+        let rdata = msg.get(at + 10..at + 10 + rdlen).ok_or_else(truncated)?;
+        at += 10 + rdlen;
+        if rtype != qtype || class != CLASS_IN {
+            continue;
+        }
+        match (rtype, rdata.len()) {
+            (TYPE_A, 4) => ips.push(IpAddr::from(<[u8; 4]>::try_from(rdata).unwrap())),
+            (TYPE_AAAA, 16) => ips.push(IpAddr::from(<[u8; 16]>::try_from(rdata).unwrap())),
+            _ => return Err(invalid("malformed address record")),
+        }
+    }
+    Ok(ips)
+}
+
+/// The offset just past the (possibly compressed) name at `at`.
+fn skip_name(msg: &[u8], mut at: usize) -> io::Result<usize> {
+    loop {
+        let len = *msg.get(at).ok_or_else(|| invalid("truncated DNS name"))?;
+        match len {
+            0 => return Ok(at + 1),
+            // A pointer ends the name.
+            len if len & 0xc0 == 0xc0 => return Ok(at + 2),
+            len => at += 1 + len as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_host_override_and_server() {
+        let parsed: HostOverride = "API.Example.com.=10.0.0.5".parse().unwrap();
+        assert_eq!(parsed.host, "api.example.com");
+        assert_eq!(parsed.ip, "10.0.0.5".parse::<IpAddr>().unwrap());
+        assert!("example.com".parse::<HostOverride>().is_err());
+        assert!("example.com=not-an-ip".parse::<HostOverride>().is_err());
+
+        let server: DnsServer = "10.0.0.53".parse().unwrap();
+        assert_eq!(server.0, "10.0.0.53:53".parse().unwrap());
+        let server: DnsServer = "[::1]:5353".parse().unwrap();
+        assert_eq!(server.0, "[::1]:5353".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_overrides_win_and_literals_pass_through() {
+        let resolver = Resolver::new(
+            vec![
+                "pinned.test=192.0.2.1".parse().unwrap(),
+                "pinned.test=2001:db8::1".parse().unwrap(),
+            ],
+            None,
+        );
+        assert_eq!(
+            resolver.lookup("Pinned.Test", 80).await.unwrap(),
+            vec![
+                "192.0.2.1:80".parse().unwrap(),
+                "[2001:db8::1]:80".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            resolver.lookup("[::1]", 443).await.unwrap(),
+            vec!["[::1]:443".parse().unwrap()]
+        );
+    }
+
+    /// Answer every query from `socket` with a CNAME (to check it is
+    /// skipped) followed by one address record of the queried type.
+    async fn serve_dns(socket: UdpSocket) {
+        let mut buf = [0u8; 512];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let query = &buf[..len];
+            let qtype = u16::from_be_bytes([query[len - 4], query[len - 3]]);
+            let mut reply = query[..2].to_vec();
+            reply.extend_from_slice(&[0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0]);
+            reply.extend_from_slice(&query[12..]);
+            // CNAME, pointing at the question's name.
+            reply.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 12]);
+            reply.extend_from_slice(&[0xc0, 12]);
+            reply.extend_from_slice(&qtype.to_be_bytes());
+            reply.extend_from_slice(&[0, 1, 0, 0, 0, 60]);
+            if qtype == TYPE_A {
+                reply.extend_from_slice(&[0, 4, 192, 0, 2, 7]);
+            } else {
+                reply.extend_from_slice(&[0, 16]);
+                reply.extend_from_slice(&"2001:db8::7".parse::<Ipv6Addr>().unwrap().octets());
+            }
+            socket.send_to(&reply, peer).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_configured_server_is_queried() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = socket.local_addr().unwrap();
+        tokio::spawn(serve_dns(socket));
+
+        let resolver = Resolver::new(Vec::new(), Some(DnsServer(server)));
+        let mut addrs = resolver.lookup("www.example.test", 8080).await.unwrap();
+        addrs.sort();
+        assert_eq!(
+            addrs,
+            vec![
+                "192.0.2.7:8080".parse().unwrap(),
+                "[2001:db8::7]:8080".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_response_rejects_truncation() {
+        let query = encode_query(7, "example.test", TYPE_A).unwrap();
+        let mut reply = query.clone();
+        reply[2] = 0x81;
+        reply[7] = 1;
+        assert!(parse_response(&reply, TYPE_A).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use std::fmt;
+
+/// Failures the proxy reports with a stable code, so log-based alerting can
+/// key off `code=...` instead of matching on free-form messages.
+#[derive(Debug)]
+pub enum ProxyError {
+    /// The client sent something that isn't valid HTTP.
+    MalformedRequest(String),
+    /// A header line or body exceeded a configured limit.
+    RequestTooLarge(String),
+    /// The request line was longer than `--max-request-line`.
+    UriTooLong(String),
+    /// The request line named an HTTP version the proxy doesn't serve.
+    VersionNotSupported(String),
+    /// The header section had too many lines or too many bytes in total.
+    HeaderTooLarge(String),
+    /// The request line and headers weren't complete within
+    /// `--header-read-timeout`.
+    HeaderTimeout(String),
+    /// The request target could not be parsed into something we can reach.
+    InvalidTarget(String),
+    /// The target resolved to a private or otherwise forbidden address.
+    SsrfBlocked(String),
+    /// The upstream did not respond in time.
+    UpstreamTimeout(String),
+    /// The upstream could not be connected to or failed mid-request.
+    UpstreamUnreachable(String),
+}
+
+impl ProxyError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProxyError::MalformedRequest(_) => "MALFORMED_REQUEST",
+            ProxyError::RequestTooLarge(_) => "REQUEST_TOO_LARGE",
+            ProxyError::UriTooLong(_) => "URI_TOO_LONG",
+            ProxyError::VersionNotSupported(_) => "VERSION_NOT_SUPPORTED",
+            ProxyError::HeaderTooLarge(_) => "HEADER_TOO_LARGE",
+            ProxyError::HeaderTimeout(_) => "HEADER_TIMEOUT",
+            ProxyError::InvalidTarget(_) => "INVALID_TARGET",
+            ProxyError::SsrfBlocked(_) => "SSRF_BLOCKED",
+            ProxyError::UpstreamTimeout(_) => "UPSTREAM_TIMEOUT",
+            ProxyError::UpstreamUnreachable(_) => "UPSTREAM_UNREACHABLE",
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::MalformedRequest(msg)
+            | ProxyError::RequestTooLarge(msg)
+            | ProxyError::UriTooLong(msg)
+            | ProxyError::VersionNotSupported(msg)
+            | ProxyError::HeaderTooLarge(msg)
+            | ProxyError::HeaderTimeout(msg)
+            | ProxyError::InvalidTarget(msg)
+            | ProxyError::SsrfBlocked(msg)
+            | ProxyError::UpstreamTimeout(msg)
+            | ProxyError::UpstreamUnreachable(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+/// Stable code for any error returned from `handle_connection`. Errors that
+/// weren't raised as a `ProxyError` are classified as client I/O failures
+/// when they wrap an `io::Error`, and `INTERNAL` otherwise.
+pub fn error_code(err: &anyhow::Error) -> &'static str {
+    if let Some(proxy_err) = err.downcast_ref::<ProxyError>() {
+        return proxy_err.code();
+    }
+    if err.downcast_ref::<std::io::Error>().is_some() {
+        return "CLIENT_IO";
+    }
+    "INTERNAL"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_code_for_proxy_error() {
+        let err = anyhow::Error::from(ProxyError::UpstreamTimeout("slow".into()));
+        assert_eq!(error_code(&err), "UPSTREAM_TIMEOUT");
+        assert_eq!(err.to_string(), "slow");
+    }
+
+    #[test]
+    fn test_error_code_for_io_error() {
+        let err = anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::BrokenPipe));
+        assert_eq!(error_code(&err), "CLIENT_IO");
+    }
+
+    #[test]
+    fn test_error_code_for_other_error() {
+        let err = anyhow::anyhow!("something else");
+        assert_eq!(error_code(&err), "INTERNAL");
+    }
+}
@@ -0,0 +1,225 @@
+//! `--idle-timeout`: a client reader that fails once a read has waited too
+//! long for the client to send anything, and a writer that fails once a
+//! write has waited too long for the client to take anything. The clock
+//! only runs while a read or write is pending and restarts whenever bytes
+//! move, so a slow transfer that keeps making progress is never cut off.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+#[derive(Debug)]
+pub struct IdleReader<R> {
+    inner: R,
+    timeout: Option<Duration>,
+    /// Armed when a read first has to wait; dropped when one completes.
+    idle: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> IdleReader<R> {
+    /// `None` never times out.
+    pub fn new(inner: R, timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            timeout,
+            idle: None,
+        }
+    }
+
+    /// Stop timing reads, for connections that become tunnels and have
+    /// their own idle handling.
+    pub fn disarm(&mut self) {
+        self.timeout = None;
+        self.idle = None;
+    }
+}
+
+#[derive(Debug)]
+pub struct IdleWriter<W> {
+    inner: W,
+    timeout: Option<Duration>,
+    /// Armed when a write first has to wait; dropped when one completes.
+    idle: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W> IdleWriter<W> {
+    /// `None` never times out.
+    pub fn new(inner: W, timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            timeout,
+            idle: None,
+        }
+    }
+
+    /// Stop timing writes, as `IdleReader::disarm`.
+    pub fn disarm(&mut self) {
+        self.timeout = None;
+        self.idle = None;
+    }
+
+    /// Finish a write-side poll: completion resets the clock, waiting runs it.
+    fn settle<T>(
+        &mut self,
+        poll: Poll<io::Result<T>>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<T>> {
+        match poll {
+            Poll::Ready(result) => {
+                self.idle = None;
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                poll_expired(&mut self.idle, self.timeout, "client read nothing", cx).map(Err)
+            }
+        }
+    }
+}
+
+/// Poll the idle timer for a read or write that is still waiting, arming
+/// it first if needed.
+fn poll_expired(
+    idle: &mut Option<Pin<Box<Sleep>>>,
+    timeout: Option<Duration>,
+    stalled: &str,
+    cx: &mut Context<'_>,
+) -> Poll<io::Error> {
+    let Some(timeout) = timeout else {
+        return Poll::Pending;
+    };
+    let sleep = idle.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+    match sleep.as_mut().poll(cx) {
+        Poll::Ready(()) => {
+            *idle = None;
+            Poll::Ready(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{} for {:?}", stalled, timeout),
+            ))
+        }
+        Poll::Pending => Poll::Pending,
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for IdleReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                this.idle = None;
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                poll_expired(&mut this.idle, this.timeout, "client sent nothing", cx).map(Err)
+            }
+        }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for IdleReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_fill_buf(cx) {
+            Poll::Ready(result) => {
+                this.idle = None;
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                poll_expired(&mut this.idle, this.timeout, "client sent nothing", cx).map(Err)
+            }
+        }
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.get_mut().inner).consume(amt);
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for IdleWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.settle(poll, cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_flush(cx);
+        this.settle(poll, cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_shutdown(cx);
+        this.settle(poll, cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+
+    #[tokio::test]
+    async fn test_idle_reader_resets_on_progress_and_fails_when_idle() {
+        let (client, mut peer) = tokio::io::duplex(64);
+        let mut reader = IdleReader::new(BufReader::new(client), Some(Duration::from_millis(300)));
+
+        let drip = tokio::spawn(async move {
+            for byte in b"slow" {
+                tokio::time::sleep(Duration::from_millis(150)).await;
+                peer.write_all(&[*byte]).await.unwrap();
+            }
+            // Keep the stream open, but silent.
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            drop(peer);
+        });
+
+        let mut received = [0u8; 4];
+        reader.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"slow");
+
+        let err = reader.read_u8().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        drip.abort();
+    }
+
+    #[tokio::test]
+    async fn test_disarmed_idle_reader_waits() {
+        let (client, mut peer) = tokio::io::duplex(64);
+        let mut reader = IdleReader::new(client, Some(Duration::from_millis(300)));
+        reader.disarm();
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            peer.write_all(b"x").await.unwrap();
+        });
+        assert_eq!(reader.read_u8().await.unwrap(), b'x');
+    }
+
+    #[tokio::test]
+    async fn test_idle_writer_fails_when_client_stops_reading() {
+        let (client, mut peer) = tokio::io::duplex(64);
+        let mut writer = IdleWriter::new(client, Some(Duration::from_millis(300)));
+
+        // The peer takes a little, then stops reading without closing.
+        let mut taken = [0u8; 64];
+        writer.write_all(&[0u8; 64]).await.unwrap();
+        peer.read_exact(&mut taken).await.unwrap();
+
+        let err = writer.write_all(&[0u8; 1024]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        drop(peer);
+    }
+}
@@ -1,9 +1,27 @@
+pub mod access_log;
+pub mod auth;
+pub mod breaker;
+pub mod cache;
+pub mod config;
 pub mod constants;
+pub mod counting;
+pub mod dns;
+pub mod error;
+pub mod idle;
+pub mod logging;
+pub mod metrics;
 pub mod protocol;
+pub mod readiness;
+pub mod routes;
+pub mod server;
+
+pub use server::{serve, Server};
 
 #[cfg(feature = "_test-support")]
 pub mod test_support {
     use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+    use std::time::SystemTime;
 
     static BYPASS_SSRF: AtomicBool = AtomicBool::new(false);
 
@@ -14,11 +32,51 @@ pub mod test_support {
     pub(crate) fn is_ssrf_bypassed() -> bool {
         BYPASS_SSRF.load(Ordering::SeqCst)
     }
+
+    static ACCEPT_INVALID_UPSTREAM_CERTS: AtomicBool = AtomicBool::new(false);
+
+    /// Let tests stand up https upstreams with certificates no system root
+    /// vouches for.
+    pub fn set_accept_invalid_upstream_certs(enabled: bool) {
+        ACCEPT_INVALID_UPSTREAM_CERTS.store(enabled, Ordering::SeqCst);
+    }
+
+    pub(crate) fn accepts_invalid_upstream_certs() -> bool {
+        ACCEPT_INVALID_UPSTREAM_CERTS.load(Ordering::SeqCst)
+    }
+
+    static UPSTREAM_ROOT_CERTIFICATE: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+
+    /// Trust this PEM certificate as a root for upstream TLS, alongside the
+    /// system roots, or stop trusting it with `None`.
+    pub fn set_upstream_root_certificate(pem: Option<Vec<u8>>) {
+        *UPSTREAM_ROOT_CERTIFICATE.lock().unwrap() = pem;
+    }
+
+    pub(crate) fn upstream_root_certificate() -> Option<Vec<u8>> {
+        UPSTREAM_ROOT_CERTIFICATE.lock().unwrap().clone()
+    }
+
+    static PINNED_CLOCK: Mutex<Option<SystemTime>> = Mutex::new(None);
+
+    /// Make time-based policy see `now` instead of the system clock, or go
+    /// back to the system clock with `None`.
+    pub fn pin_clock(now: Option<SystemTime>) {
+        *PINNED_CLOCK.lock().unwrap() = now;
+    }
+
+    pub(crate) fn pinned_clock() -> Option<SystemTime> {
+        *PINNED_CLOCK.lock().unwrap()
+    }
 }
 
-use ::http::Method;
+use ::http::{Method, Version};
 use anyhow::Result;
+use counting::{CountingReader, CountingWriter};
+use error::ProxyError;
+use idle::{IdleReader, IdleWriter};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tracing::Instrument;
 
 pub async fn read_line_bounded<R>(reader: &mut R, buf: &mut String, max_len: usize) -> Result<()>
 where
@@ -35,10 +93,11 @@ where
         if let Some(pos) = available.iter().position(|&b| b == b'\n') {
             let to_consume = pos + 1;
             if total + to_consume > max_len {
-                return Err(anyhow::anyhow!(
+                return Err(ProxyError::RequestTooLarge(format!(
                     "Line exceeds maximum length of {} bytes",
                     max_len
-                ));
+                ))
+                .into());
             }
             bytes.extend_from_slice(&available[..to_consume]);
             reader.consume(to_consume);
@@ -47,42 +106,89 @@ where
 
         let len = available.len();
         if total + len > max_len {
-            return Err(anyhow::anyhow!(
+            return Err(ProxyError::RequestTooLarge(format!(
                 "Line exceeds maximum length of {} bytes",
                 max_len
-            ));
+            ))
+            .into());
         }
         bytes.extend_from_slice(available);
         reader.consume(len);
         total += len;
     }
 
-    *buf = String::from_utf8(bytes).map_err(|e| anyhow::anyhow!("Invalid UTF-8: {}", e))?;
+    *buf = String::from_utf8(bytes)
+        .map_err(|e| ProxyError::MalformedRequest(format!("Invalid UTF-8: {}", e)))?;
     Ok(())
 }
 
-pub async fn extract_request_parts<R>(reader: &mut R) -> Result<(Method, String)>
+/// Read and split the request line, failing with `UriTooLong` past
+/// `max_len` bytes and `VersionNotSupported` for anything but HTTP/1.0 and
+/// HTTP/1.1.
+pub async fn extract_request_parts<R>(
+    reader: &mut R,
+    max_len: usize,
+) -> Result<(Method, String, Version)>
 where
     R: AsyncBufReadExt + Unpin,
 {
     let mut first_line = String::new();
-    read_line_bounded(
-        &mut *reader,
-        &mut first_line,
-        constants::MAX_REQUEST_LINE_LEN,
-    )
-    .await?;
+    read_line_bounded(&mut *reader, &mut first_line, max_len)
+        .await
+        .map_err(|e| match e.downcast::<ProxyError>() {
+            Ok(ProxyError::RequestTooLarge(_)) => ProxyError::UriTooLong(format!(
+                "Request line exceeds maximum length of {} bytes",
+                max_len
+            ))
+            .into(),
+            Ok(other) => other.into(),
+            Err(e) => e,
+        })?;
     let first_line = first_line.trim();
 
     let parts: Vec<&str> = first_line.split_whitespace().collect();
     if parts.len() != 3 {
-        return Err(anyhow::anyhow!("Invalid request line: {}", first_line));
+        return Err(
+            ProxyError::MalformedRequest(format!("Invalid request line: {}", first_line)).into(),
+        );
     }
 
-    let method = Method::from_bytes(parts[0].as_bytes())?;
+    let method = Method::from_bytes(parts[0].as_bytes())
+        .map_err(|e| ProxyError::MalformedRequest(format!("Invalid method: {}", e)))?;
     let url_string = parts[1].to_string();
+    // CR and LF already split the line above; anything else that survives
+    // (NUL, ESC, DEL...) has no business in a target we forward.
+    if url_string.chars().any(|c| c.is_ascii_control()) {
+        return Err(ProxyError::MalformedRequest(format!(
+            "Control character in request target: {:?}",
+            url_string
+        ))
+        .into());
+    }
+    let version = parse_http_version(parts[2])?;
 
-    Ok((method, url_string))
+    Ok((method, url_string, version))
+}
+
+/// `HTTP/1.0` or `HTTP/1.1`. Other well-formed versions are unsupported
+/// rather than malformed: HTTP/2 and later never use this request line.
+fn parse_http_version(token: &str) -> Result<Version> {
+    let digits = token
+        .strip_prefix("HTTP/")
+        .map(str::as_bytes)
+        .filter(|d| d.len() == 3 && d[0].is_ascii_digit() && d[1] == b'.' && d[2].is_ascii_digit());
+    match digits {
+        Some(b"1.0") => Ok(Version::HTTP_10),
+        Some(b"1.1") => Ok(Version::HTTP_11),
+        Some(_) => Err(ProxyError::VersionNotSupported(format!(
+            "Unsupported HTTP version: {}",
+            token
+        ))
+        .into()),
+        None => {
+            Err(ProxyError::MalformedRequest(format!("Invalid HTTP version: {}", token)).into())
+        }
+    }
 }
 
 pub fn is_private_address(host: &str) -> bool {
@@ -95,15 +201,32 @@ pub fn is_private_address(host: &str) -> bool {
         return true;
     }
 
-    // Strip IPv6 zone ID (e.g., "fe80::1%eth0" → "fe80::1") since Rust's
-    // IpAddr parser rejects the % suffix.
-    let host = host.split('%').next().unwrap_or(host);
+    parse_ip_host(host).is_some_and(|addr| is_private_ip(&addr))
+}
 
-    if let Ok(addr) = host.parse::<std::net::IpAddr>() {
-        return is_private_ip(&addr);
-    }
+/// The address a host names, if it is an IP literal. An IPv6 zone ID
+/// (e.g., "fe80::1%eth0" → "fe80::1") is stripped since Rust's IpAddr parser
+/// rejects the % suffix.
+fn parse_ip_host(host: &str) -> Option<std::net::IpAddr> {
+    host.split('%').next().unwrap_or(host).parse().ok()
+}
 
-    false
+/// Whether the SSRF check refuses `host`: a cloud metadata endpoint always,
+/// and any other private address unless `--allow-private-addresses` is set.
+pub fn is_blocked_address(host: &str, allow_private: bool) -> bool {
+    parse_ip_host(host).is_some_and(|addr| is_metadata_ip(&addr))
+        || (!allow_private && is_private_address(host))
+}
+
+/// Like `is_blocked_address`, for a resolved address.
+pub fn is_blocked_ip(ip: &std::net::IpAddr, allow_private: bool) -> bool {
+    is_metadata_ip(ip) || (!allow_private && is_private_ip(ip))
+}
+
+/// Cloud instance metadata services, which hand out credentials and so stay
+/// unreachable even when private addresses are allowed.
+pub fn is_metadata_ip(ip: &std::net::IpAddr) -> bool {
+    constants::METADATA_ADDRESSES.contains(&ip.to_canonical())
 }
 
 pub fn is_private_ip(ip: &std::net::IpAddr) -> bool {
@@ -139,29 +262,33 @@ fn is_private_ipv4(addr: &std::net::Ipv4Addr) -> bool {
     addr.is_loopback() || addr.is_private() || addr.is_link_local() || addr.is_unspecified()
 }
 
+/// Resolve `host` and refuse it if any address it resolves to is blocked,
+/// so a public name can't be rebound to a private address. With
+/// `allow_private`, only metadata endpoints are refused.
 pub async fn resolve_and_verify_non_private(
+    resolver: &dns::Resolver,
     host: &str,
     port: u16,
+    allow_private: bool,
 ) -> Result<Vec<std::net::SocketAddr>> {
-    let addrs: Vec<std::net::SocketAddr> = tokio::net::lookup_host(format!("{}:{}", host, port))
-        .await?
-        .collect();
+    let addrs = resolver.lookup(host, port).await?;
 
     if addrs.is_empty() {
-        return Err(anyhow::anyhow!(
+        return Err(ProxyError::UpstreamUnreachable(format!(
             "DNS resolution returned no addresses for {}:{}",
-            host,
-            port
-        ));
+            host, port
+        ))
+        .into());
     }
 
     for addr in &addrs {
-        if is_private_ip(&addr.ip()) {
-            return Err(anyhow::anyhow!(
-                "DNS rebinding detected: {} resolved to private IP {}",
+        if is_blocked_ip(&addr.ip(), allow_private) {
+            return Err(ProxyError::SsrfBlocked(format!(
+                "DNS rebinding detected: {} resolved to blocked IP {}",
                 host,
                 addr.ip()
-            ));
+            ))
+            .into());
         }
     }
 
@@ -175,6 +302,60 @@ pub fn is_health_check(url: &str) -> bool {
     path == constants::HEALTH_ENDPOINT_PATH
 }
 
+/// Like `is_health_check`, for the readiness endpoint `/ready`.
+pub fn is_ready_check(url: &str) -> bool {
+    let path = url.split('?').next().unwrap_or(url);
+    path == constants::READY_ENDPOINT_PATH
+}
+
+/// Like `is_health_check`, for the proxy's own `/metrics`.
+pub fn is_metrics_request(url: &str) -> bool {
+    let path = url.split('?').next().unwrap_or(url);
+    path == constants::METRICS_ENDPOINT_PATH
+}
+
+/// Like `is_health_check`, for the `--admin` `/stats` endpoint.
+pub fn is_stats_request(url: &str) -> bool {
+    let path = url.split('?').next().unwrap_or(url);
+    path == constants::STATS_ENDPOINT_PATH
+}
+
+pub async fn handle_metrics<W>(writer: &mut W) -> Result<u64>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    write_local_response(writer, constants::METRICS_CONTENT_TYPE, &metrics::render()).await
+}
+
+pub async fn handle_stats<W>(writer: &mut W) -> Result<u64>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    let body = serde_json::to_string(&metrics::stats())?;
+    write_local_response(writer, "application/json", &body).await
+}
+
+/// Answer a request for one of the proxy's own endpoints with `200` and
+/// `body`, returning the body length for the access log.
+async fn write_local_response<W>(writer: &mut W, content_type: &str, body: &str) -> Result<u64>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    writer
+        .write_all(
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+                content_type,
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .await?;
+    writer.write_all(body.as_bytes()).await?;
+    writer.flush().await?;
+    Ok(body.len() as u64)
+}
+
 pub async fn handle_health_check<W>(writer: &mut W) -> Result<()>
 where
     W: AsyncWriteExt + Unpin,
@@ -184,43 +365,419 @@ where
     Ok(())
 }
 
+/// Answer `/ready` like `/health`, except with `503` while the
+/// `--ready-probe` target can't be reached. Returns the status sent.
+pub async fn handle_ready_check<W>(writer: &mut W, config: &config::ProxyConfig) -> Result<u16>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    let ready = match &config.ready_probe {
+        Some(probe) => probe.is_ready(config.egress_bind).await,
+        None => true,
+    };
+    let (response, status) = if ready {
+        (constants::HEALTH_CHECK_RESPONSE, 200)
+    } else {
+        (constants::NOT_READY_RESPONSE, 503)
+    };
+    writer.write_all(response).await?;
+    writer.flush().await?;
+    Ok(status)
+}
+
+/// The time `allowed_hours` is checked against.
+pub(crate) fn now() -> std::time::SystemTime {
+    #[cfg(feature = "_test-support")]
+    if let Some(now) = test_support::pinned_clock() {
+        return now;
+    }
+    std::time::SystemTime::now()
+}
+
+/// A fresh ID for correlating one request's log lines, upstream request, and
+/// response.
+pub fn new_request_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
 pub async fn handle_connection<W, R>(
     writer: &mut W,
     reader: &mut R,
     peer_addr: Option<std::net::SocketAddr>,
+    config: &config::ProxyConfig,
+) -> Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+    R: AsyncBufReadExt + Unpin,
+{
+    let _connection = metrics::track_connection();
+    let request_id = new_request_id();
+    let span = tracing::info_span!(
+        "request",
+        request_id = tracing::field::Empty,
+        peer = peer_addr.map(tracing::field::display),
+        method = tracing::field::Empty,
+        target = tracing::field::Empty,
+        protocol = tracing::field::Empty,
+        tenant = tracing::field::Empty
+    );
+    let mut writer = CountingWriter::new(IdleWriter::new(writer, config.idle_timeout));
+    let mut reader = CountingReader::new(IdleReader::new(reader, config.idle_timeout));
+    let result = serve_request(&mut writer, &mut reader, peer_addr, config, &request_id)
+        .instrument(span)
+        .await;
+    metrics::record_client_bytes(reader.bytes(), writer.bytes());
+    result
+}
+
+async fn serve_request<W, R>(
+    writer: &mut CountingWriter<IdleWriter<W>>,
+    reader: &mut CountingReader<IdleReader<R>>,
+    peer_addr: Option<std::net::SocketAddr>,
+    config: &config::ProxyConfig,
+    generated_id: &str,
 ) -> Result<()>
 where
     W: AsyncWriteExt + Unpin,
     R: AsyncBufReadExt + Unpin,
 {
-    let (method, url_string) = match extract_request_parts(reader).await {
-        Ok(parts) => parts,
+    let started = std::time::Instant::now();
+    let head = read_request_head(reader, config);
+    let head = match config.header_read_timeout {
+        Some(limit) => tokio::time::timeout(limit, head).await.unwrap_or_else(|_| {
+            Err(ProxyError::HeaderTimeout(format!(
+                "Request headers not complete within {:?}",
+                limit
+            ))
+            .into())
+        }),
+        None => head.await,
+    };
+    let (method, url_string, version, headers) = match head {
+        Ok(head) => head,
+        // A failed read leaves no client to answer; just close.
+        Err(e) if is_client_io_error(&e) => return Err(e),
         Err(e) => {
-            match peer_addr {
-                Some(addr) => tracing::warn!("[{addr}] Malformed request: {e}"),
-                None => tracing::warn!("Malformed request: {e}"),
-            }
-            let _ = writer.write_all(constants::BAD_REQUEST_RESPONSE).await;
-            let _ = writer.flush().await;
-            return Ok(());
+            tracing::Span::current().record("request_id", tracing::field::display(generated_id));
+            return reject_malformed(writer, peer_addr, &e).await;
         }
     };
 
+    // Honor an ID the client already assigned so its traces line up with ours.
+    let request_id = match client_request_id(&headers) {
+        Some(client_id) => {
+            tracing::debug!("Using client-supplied request ID {}", client_id);
+            client_id.to_owned()
+        }
+        None => generated_id.to_owned(),
+    };
     let protocol = protocol::Protocol::from_method(&method);
+    tracing::Span::current()
+        .record("request_id", tracing::field::display(&request_id))
+        .record("method", method.as_str())
+        .record("target", url_string.as_str())
+        .record("protocol", tracing::field::display(&protocol));
+    let tenant = tenant_label(&headers, peer_addr, config).map(str::to_owned);
+    if let Some(tenant) = &tenant {
+        tracing::Span::current().record("tenant", tenant.as_str());
+    }
 
     match peer_addr {
         Some(addr) => tracing::info!("[{addr}::{protocol}] {url_string}"),
         None => tracing::info!("[{protocol}] {url_string}"),
     }
 
-    if is_health_check(&url_string) {
-        return handle_health_check(writer).await;
+    let authorized = config
+        .proxy_auth
+        .as_ref()
+        .is_none_or(|auth| auth.is_authorized(&headers));
+
+    // The health and readiness endpoints, and metrics and stats when turned
+    // on, stay open so probes and scrapers don't need credentials. A reverse proxy hands
+    // every path to its upstream, which may well have its own `/health`.
+    let local = config.reverse_upstream.is_none();
+    let outcome = if local && is_health_check(&url_string) {
+        handle_health_check(writer).await?;
+        protocol::Outcome::status(200)
+    } else if local && is_ready_check(&url_string) {
+        protocol::Outcome::status(handle_ready_check(writer, config).await?)
+    } else if local && config.metrics && is_metrics_request(&url_string) {
+        protocol::Outcome {
+            status: 200,
+            bytes_sent: handle_metrics(writer).await?,
+        }
+    } else if local && config.admin && is_stats_request(&url_string) {
+        protocol::Outcome {
+            status: 200,
+            bytes_sent: handle_stats(writer).await?,
+        }
+    } else if !config.method_allowed(&method) {
+        metrics::record_block(
+            metrics::BlockReason::MethodNotAllowed,
+            format_args!("{method} request to {url_string}"),
+            "method not allowed",
+        );
+        tarpit(config).await;
+        reject_method(writer, config).await?;
+        protocol::Outcome::status(405)
+    } else if let Some(hours) = config.allowed_hours.filter(|h| !h.is_open_at(now())) {
+        metrics::record_block(
+            metrics::BlockReason::OutsideAllowedHours,
+            format_args!("{method} request to {url_string}"),
+            format_args!("outside allowed hours {hours}"),
+        );
+        tarpit(config).await;
+        let accept = headers
+            .iter()
+            .find(|(k, _)| k == "accept")
+            .map(|(_, v)| v.as_str());
+        protocol::http::write_error_response(
+            writer,
+            403,
+            &format!("Requests are only served {}", hours),
+            accept,
+            Some(&request_id),
+        )
+        .await?
+    } else if !authorized {
+        tracing::warn!("Rejected unauthenticated {protocol} request to {url_string}");
+        writer
+            .write_all(constants::PROXY_AUTH_REQUIRED_RESPONSE)
+            .await?;
+        writer.flush().await?;
+        protocol::Outcome::status(407)
+    } else {
+        let head = protocol::RequestHead {
+            method: method.clone(),
+            target: url_string.clone(),
+            version,
+            headers,
+            received: started,
+        };
+        let tunnels = matches!(protocol, protocol::Protocol::Https)
+            || protocol::http::is_websocket_upgrade(&head.headers);
+        if tunnels {
+            // Tunnels answer to `--tunnel-idle-timeout` instead.
+            reader.get_mut().disarm();
+            writer.get_mut().disarm();
+        }
+        let outcome = protocol
+            .handle_request(writer, reader, head, config, &request_id, peer_addr)
+            .await?;
+        metrics::record_bytes_proxied(outcome.bytes_sent);
+        outcome
+    };
+
+    if let Some(access_log) = &config.access_log {
+        access_log.record(&access_log::AccessLogEntry {
+            client: peer_addr.map(|addr| addr.ip()),
+            method: method.as_str(),
+            target: &url_string,
+            status: outcome.status,
+            bytes_sent: outcome.bytes_sent,
+            duration: started.elapsed(),
+            bytes_read: reader.bytes(),
+            bytes_written: writer.bytes(),
+        });
+    }
+    if let Some(tenant) = &tenant {
+        metrics::record_tenant_request(tenant, reader.bytes(), writer.bytes());
+    }
+    tracing::info!(
+        status = outcome.status,
+        duration_ms = started.elapsed().as_millis() as u64,
+        "Request completed"
+    );
+
+    Ok(())
+}
+
+/// Read the request line and headers.
+async fn read_request_head<R>(
+    reader: &mut R,
+    config: &config::ProxyConfig,
+) -> Result<(Method, String, Version, Vec<(String, String)>)>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let max_request_line = config
+        .max_request_line
+        .unwrap_or(constants::MAX_REQUEST_LINE_LEN);
+    let (method, url_string, version) =
+        extract_request_parts(&mut *reader, max_request_line).await?;
+    if !config.http_version_allowed(version) {
+        return Err(ProxyError::VersionNotSupported(format!(
+            "{:?} requests are refused by --reject-http10",
+            version
+        ))
+        .into());
+    }
+    let max_header_bytes = config
+        .max_header_bytes
+        .unwrap_or(constants::MAX_HEADER_BYTES);
+    let headers = protocol::http::parse_request_headers(reader, max_header_bytes).await?;
+    Ok((method, url_string, version, headers))
+}
+
+/// The request's `--tenant-header` value, if it's set, comes from a trusted
+/// peer, and is a short label of letters, digits, `-`, `_`, and `.` (so it
+/// is safe as a metric label).
+fn tenant_label<'a>(
+    headers: &'a [(String, String)],
+    peer_addr: Option<std::net::SocketAddr>,
+    config: &config::ProxyConfig,
+) -> Option<&'a str> {
+    let name = config.tenant_header.as_deref()?;
+    let trusted = config.tenant_trusted_peers.is_empty()
+        || peer_addr.is_some_and(|addr| config.tenant_trusted_peers.contains(&addr.ip()));
+    if !trusted {
+        return None;
     }
+    let value = headers.iter().find(|(k, _)| k == name)?.1.trim();
+    let valid = !value.is_empty()
+        && value.len() <= constants::MAX_TENANT_LABEL_LEN
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+    if !valid {
+        tracing::debug!("Ignoring invalid {} value {:?}", name, value);
+        return None;
+    }
+    Some(value)
+}
+
+/// The client's own `X-Request-Id`, if it is a short ID of letters, digits,
+/// `-`, `_`, `.`, and `:` (so it is safe to log and forward as is).
+pub(crate) fn client_request_id(headers: &[(String, String)]) -> Option<&str> {
+    let value = headers
+        .iter()
+        .find(|(k, _)| k == constants::REQUEST_ID_HEADER)?
+        .1
+        .trim();
+    let valid = !value.is_empty()
+        && value.len() <= constants::MAX_REQUEST_ID_LEN
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'));
+    if !valid {
+        tracing::debug!("Ignoring invalid request ID {:?}", value);
+        return None;
+    }
+    Some(value)
+}
+
+/// Serve one SOCKS5 client. Tunnels get the same port, SSRF, and auth
+/// checks as HTTP CONNECT and are access-logged as `CONNECT`.
+pub async fn handle_socks_connection<W, R>(
+    writer: &mut W,
+    reader: &mut R,
+    peer_addr: Option<std::net::SocketAddr>,
+    config: &config::ProxyConfig,
+) -> Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+    R: AsyncBufReadExt + Unpin,
+{
+    let _connection = metrics::track_connection();
+    let request_id = new_request_id();
+    let span = tracing::info_span!("request", request_id = %request_id);
+    let mut writer = CountingWriter::new(writer);
+    let mut reader = CountingReader::new(reader);
+    let result = async {
+        let started = std::time::Instant::now();
+        let Some(session) =
+            protocol::socks::handle_connection(&mut writer, &mut reader, config).await?
+        else {
+            return Ok(());
+        };
+
+        metrics::record_bytes_proxied(session.outcome.bytes_sent);
+
+        match peer_addr {
+            Some(addr) => tracing::info!("[{addr}::SOCKS5] {}", session.target),
+            None => tracing::info!("[SOCKS5] {}", session.target),
+        }
+
+        if let Some(access_log) = &config.access_log {
+            access_log.record(&access_log::AccessLogEntry {
+                client: peer_addr.map(|addr| addr.ip()),
+                method: Method::CONNECT.as_str(),
+                target: &session.target,
+                status: session.outcome.status,
+                bytes_sent: session.outcome.bytes_sent,
+                duration: started.elapsed(),
+                bytes_read: reader.bytes(),
+                bytes_written: writer.bytes(),
+            });
+        }
+        Ok(())
+    }
+    .instrument(span)
+    .await;
+    metrics::record_client_bytes(reader.bytes(), writer.bytes());
+    result
+}
+
+/// Hold a denied request for `config.tarpit` before its rejection is sent,
+/// so scanners spend that long on every probe.
+pub(crate) async fn tarpit(config: &config::ProxyConfig) {
+    if let Some(delay) = config.tarpit {
+        tracing::debug!("Tarpitting rejection for {}ms", delay.as_millis());
+        tokio::time::sleep(delay).await;
+    }
+}
 
-    protocol
-        .handle_request(writer, reader, method, url_string)
+/// Answer `405`, listing what may be served in `Allow` when an allowlist
+/// bounds it.
+pub(crate) async fn reject_method<W>(writer: &mut W, config: &config::ProxyConfig) -> Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    writer
+        .write_all(constants::METHOD_NOT_ALLOWED_STATUS_LINE)
         .await?;
+    if let Some(allow) = config.allow_header() {
+        writer
+            .write_all(format!("Allow: {}\r\n", allow).as_bytes())
+            .await?;
+    }
+    writer.write_all(b"\r\n").await?;
+    writer.flush().await?;
+    Ok(())
+}
 
+/// Whether `e` came from reading or writing the client connection rather
+/// than from what the client sent.
+pub(crate) fn is_client_io_error(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<ProxyError>().is_none() && e.downcast_ref::<std::io::Error>().is_some()
+}
+
+/// Answer 400 for a request line or header block we couldn't parse, 414 for
+/// an overlong request line, 505 for an unsupported HTTP version, 431 for a
+/// header section over its limits, or 408 for one that took too long. The
+/// error is logged here, so the connection itself still ends `Ok`.
+async fn reject_malformed<W>(
+    writer: &mut W,
+    peer_addr: Option<std::net::SocketAddr>,
+    e: &anyhow::Error,
+) -> Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    let code = error::error_code(e);
+    match peer_addr {
+        Some(addr) => tracing::warn!(code, "[{addr}] Rejected request: {e}"),
+        None => tracing::warn!(code, "Rejected request: {e}"),
+    }
+    let response = match e.downcast_ref::<ProxyError>() {
+        Some(ProxyError::UriTooLong(_)) => constants::URI_TOO_LONG_RESPONSE,
+        Some(ProxyError::VersionNotSupported(_)) => constants::HTTP_VERSION_NOT_SUPPORTED_RESPONSE,
+        Some(ProxyError::HeaderTooLarge(_)) => constants::REQUEST_HEADER_FIELDS_TOO_LARGE_RESPONSE,
+        Some(ProxyError::HeaderTimeout(_)) => constants::REQUEST_TIMEOUT_RESPONSE,
+        _ => constants::BAD_REQUEST_RESPONSE,
+    };
+    let _ = writer.write_all(response).await;
+    let _ = writer.flush().await;
     Ok(())
 }
 
@@ -234,7 +791,9 @@ mod tests {
         let request = "GET /path HTTP/1.1\r\n";
         let mut reader = Cursor::new(request);
 
-        let result = extract_request_parts(&mut reader).await.unwrap();
+        let result = extract_request_parts(&mut reader, constants::MAX_REQUEST_LINE_LEN)
+            .await
+            .unwrap();
         assert_eq!(result.0, Method::GET);
         assert_eq!(result.1, "/path");
     }
@@ -244,7 +803,9 @@ mod tests {
         let request = "POST /api/users HTTP/1.1\r\n";
         let mut reader = Cursor::new(request);
 
-        let result = extract_request_parts(&mut reader).await.unwrap();
+        let result = extract_request_parts(&mut reader, constants::MAX_REQUEST_LINE_LEN)
+            .await
+            .unwrap();
         assert_eq!(result.0, Method::POST);
         assert_eq!(result.1, "/api/users");
     }
@@ -254,7 +815,9 @@ mod tests {
         let request = "CONNECT example.com:443 HTTP/1.1\r\n";
         let mut reader = Cursor::new(request);
 
-        let result = extract_request_parts(&mut reader).await.unwrap();
+        let result = extract_request_parts(&mut reader, constants::MAX_REQUEST_LINE_LEN)
+            .await
+            .unwrap();
         assert_eq!(result.0, Method::CONNECT);
         assert_eq!(result.1, "example.com:443");
     }
@@ -264,7 +827,9 @@ mod tests {
         let request = "GET https://example.com/path HTTP/1.1\r\n";
         let mut reader = Cursor::new(request);
 
-        let result = extract_request_parts(&mut reader).await.unwrap();
+        let result = extract_request_parts(&mut reader, constants::MAX_REQUEST_LINE_LEN)
+            .await
+            .unwrap();
         assert_eq!(result.0, Method::GET);
         assert_eq!(result.1, "https://example.com/path");
     }
@@ -274,7 +839,9 @@ mod tests {
         let request = "INVALID /path HTTP/1.1\r\n";
         let mut reader = Cursor::new(request);
 
-        let result = extract_request_parts(&mut reader).await.unwrap();
+        let result = extract_request_parts(&mut reader, constants::MAX_REQUEST_LINE_LEN)
+            .await
+            .unwrap();
         assert_eq!(result.0.as_str(), "INVALID");
         assert_eq!(result.1, "/path");
     }
@@ -284,7 +851,7 @@ mod tests {
         let request = "GET /path\r\n";
         let mut reader = Cursor::new(request);
 
-        let result = extract_request_parts(&mut reader).await;
+        let result = extract_request_parts(&mut reader, constants::MAX_REQUEST_LINE_LEN).await;
         assert!(result.is_err());
         assert!(result
             .unwrap_err()
@@ -297,7 +864,7 @@ mod tests {
         let request = "GET /path HTTP/1.1 extra\r\n";
         let mut reader = Cursor::new(request);
 
-        let result = extract_request_parts(&mut reader).await;
+        let result = extract_request_parts(&mut reader, constants::MAX_REQUEST_LINE_LEN).await;
         assert!(result.is_err());
         assert!(result
             .unwrap_err()
@@ -310,7 +877,7 @@ mod tests {
         let request = "\r\n";
         let mut reader = Cursor::new(request);
 
-        let result = extract_request_parts(&mut reader).await;
+        let result = extract_request_parts(&mut reader, constants::MAX_REQUEST_LINE_LEN).await;
         assert!(result.is_err());
         assert!(result
             .unwrap_err()
@@ -352,11 +919,50 @@ mod tests {
         let request = format!("GET /{} HTTP/1.1\r\n", long_path);
         let mut reader = Cursor::new(request);
 
-        let result = extract_request_parts(&mut reader).await;
-        assert!(
-            result.is_err(),
-            "Should reject request lines exceeding size limit"
-        );
+        let err = extract_request_parts(&mut reader, constants::MAX_REQUEST_LINE_LEN)
+            .await
+            .unwrap_err();
+        assert_eq!(error::error_code(&err), "URI_TOO_LONG");
+    }
+
+    #[tokio::test]
+    async fn test_extract_request_parts_checks_version() {
+        for (line, version) in [
+            ("GET / HTTP/1.0\r\n", Version::HTTP_10),
+            ("GET / HTTP/1.1\r\n", Version::HTTP_11),
+        ] {
+            let mut reader = Cursor::new(line);
+            let parts = extract_request_parts(&mut reader, constants::MAX_REQUEST_LINE_LEN)
+                .await
+                .unwrap();
+            assert_eq!(parts.2, version);
+        }
+        for (line, code) in [
+            ("GET / HTTP/2.0\r\n", "VERSION_NOT_SUPPORTED"),
+            ("GET / HTTP/0.9\r\n", "VERSION_NOT_SUPPORTED"),
+            ("GET / GARBAGE\r\n", "MALFORMED_REQUEST"),
+            ("GET / HTTP/1.10\r\n", "MALFORMED_REQUEST"),
+            ("GET / http/1.1\r\n", "MALFORMED_REQUEST"),
+        ] {
+            let mut reader = Cursor::new(line);
+            let err = extract_request_parts(&mut reader, constants::MAX_REQUEST_LINE_LEN)
+                .await
+                .unwrap_err();
+            assert_eq!(error::error_code(&err), code, "{:?}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_extract_request_parts_rejects_control_characters() {
+        for target in ["/a\0b", "http://example.com/\x1b[2J", "/path\x7f"] {
+            let request = format!("GET {} HTTP/1.1\r\n", target);
+            let mut reader = Cursor::new(request);
+
+            let err = extract_request_parts(&mut reader, constants::MAX_REQUEST_LINE_LEN)
+                .await
+                .unwrap_err();
+            assert_eq!(error::error_code(&err), "MALFORMED_REQUEST", "{:?}", target);
+        }
     }
 
     #[tokio::test]
@@ -364,7 +970,9 @@ mod tests {
         let request = "  GET   /path   HTTP/1.1  \r\n";
         let mut reader = Cursor::new(request);
 
-        let result = extract_request_parts(&mut reader).await.unwrap();
+        let result = extract_request_parts(&mut reader, constants::MAX_REQUEST_LINE_LEN)
+            .await
+            .unwrap();
         assert_eq!(result.0, Method::GET);
         assert_eq!(result.1, "/path");
     }
@@ -527,10 +1135,199 @@ mod tests {
 
     #[tokio::test]
     async fn test_resolve_and_verify_blocks_localhost() {
-        let result = resolve_and_verify_non_private("localhost", 80).await;
+        let result =
+            resolve_and_verify_non_private(&dns::Resolver::default(), "localhost", 80, false).await;
         assert!(
             result.is_err(),
             "Should block hostnames resolving to private IPs"
         );
+        let result =
+            resolve_and_verify_non_private(&dns::Resolver::default(), "localhost", 80, true).await;
+        assert!(result.is_ok(), "Private IPs are allowed on request");
+    }
+
+    #[test]
+    fn test_allow_private_still_blocks_metadata() {
+        assert!(!is_blocked_address("10.0.0.1", true));
+        assert!(!is_blocked_address("localhost", true));
+        assert!(!is_blocked_address("fe80::1%eth0", true));
+        assert!(is_blocked_address("169.254.169.254", true));
+        assert!(is_blocked_address("::ffff:169.254.169.254", true));
+        assert!(is_blocked_address("fd00:ec2::254", true));
+        assert!(is_blocked_address("100.100.100.200", false));
+        assert!(is_blocked_address("10.0.0.1", false));
+        assert!(!is_blocked_address("8.8.8.8", false));
+    }
+
+    #[tokio::test]
+    async fn test_handle_connection_rejects_malformed_header_with_400() {
+        let request = "GET http://example.com/ HTTP/1.1\r\nno colon\r\n\r\n";
+        let mut reader = Cursor::new(request);
+        let mut writer = Vec::new();
+
+        let result = handle_connection(
+            &mut writer,
+            &mut reader,
+            None,
+            &config::ProxyConfig::default(),
+        )
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(writer, constants::BAD_REQUEST_RESPONSE);
+    }
+
+    /// Collects formatted log output so tests can assert on span fields.
+    #[derive(Clone, Default)]
+    struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Serve `request` and return the log lines it produced.
+    async fn logs_for(request: &'static str) -> String {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let mut reader = Cursor::new(request);
+        handle_connection(
+            &mut Vec::new(),
+            &mut reader,
+            None,
+            &config::ProxyConfig::default(),
+        )
+        .await
+        .unwrap();
+        let logs = String::from_utf8_lossy(&logs.0.lock().unwrap()).into_owned();
+        logs
+    }
+
+    #[tokio::test]
+    async fn test_request_logs_carry_client_request_id() {
+        let logs = logs_for("GET /health HTTP/1.1\r\nX-Request-Id: client-abc-123\r\n\r\n").await;
+        assert!(
+            logs.lines()
+                .any(|line| line.contains("request{request_id=client-abc-123 ")),
+            "Expected the client's request ID on the span, got: {}",
+            logs
+        );
+        assert_eq!(logs.matches("request_id=").count(), logs.lines().count());
+    }
+
+    #[tokio::test]
+    async fn test_request_logs_replace_unusable_client_request_id() {
+        let logs = logs_for("GET /health HTTP/1.1\r\nX-Request-Id: <script>\r\n\r\n").await;
+        assert!(!logs.contains("request_id=<script>"), "Got: {}", logs);
+        assert!(logs.contains("request{request_id="), "Got: {}", logs);
+    }
+
+    #[test]
+    fn test_client_request_id_limits_length_and_charset() {
+        let id = |value: &str| vec![(constants::REQUEST_ID_HEADER.to_string(), value.to_string())];
+        assert_eq!(
+            client_request_id(&id("trace-1.a_b:c")),
+            Some("trace-1.a_b:c")
+        );
+        assert_eq!(client_request_id(&id("")), None);
+        assert_eq!(client_request_id(&id("two words")), None);
+        assert_eq!(client_request_id(&id("line\u{7f}break")), None);
+        assert_eq!(
+            client_request_id(&id(&"a".repeat(constants::MAX_REQUEST_ID_LEN + 1))),
+            None
+        );
+        assert_eq!(client_request_id(&[]), None);
+    }
+
+    /// Yields `data`, then fails as a reset connection would.
+    struct FailingReader(&'static [u8]);
+
+    impl tokio::io::AsyncRead for FailingReader {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            if self.0.is_empty() {
+                return std::task::Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()));
+            }
+            let n = self.0.len().min(buf.remaining());
+            buf.put_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_connection_closes_on_read_error_without_response() {
+        let mut reader =
+            tokio::io::BufReader::new(FailingReader(b"GET http://example.com/ HTTP/1.1\r\nHo"));
+        let mut writer = Vec::new();
+
+        let err = handle_connection(
+            &mut writer,
+            &mut reader,
+            None,
+            &config::ProxyConfig::default(),
+        )
+        .await
+        .expect_err("A read error should be returned, not answered");
+
+        assert_eq!(error::error_code(&err), "CLIENT_IO");
+        assert!(
+            writer.is_empty(),
+            "Nothing should be sent to a broken client"
+        );
+    }
+
+    #[test]
+    fn test_tenant_label_requires_trusted_peer_and_valid_value() {
+        let headers = vec![("x-tenant".to_string(), "acme-prod".to_string())];
+        let peer: std::net::SocketAddr = "192.0.2.10:4000".parse().unwrap();
+        let mut config = config::ProxyConfig {
+            tenant_header: Some("x-tenant".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            tenant_label(&headers, Some(peer), &config),
+            Some("acme-prod")
+        );
+
+        config.tenant_trusted_peers = vec!["192.0.2.1".parse().unwrap()];
+        assert_eq!(tenant_label(&headers, Some(peer), &config), None);
+        config.tenant_trusted_peers.push(peer.ip());
+        assert_eq!(
+            tenant_label(&headers, Some(peer), &config),
+            Some("acme-prod")
+        );
+
+        // Malformed values are ignored, so none can pose as the overflow bucket.
+        for bad in [
+            "",
+            "has space",
+            "quote\"",
+            &"a".repeat(65),
+            metrics::OTHER_TENANT,
+        ] {
+            let headers = vec![("x-tenant".to_string(), bad.to_string())];
+            assert_eq!(
+                tenant_label(&headers, Some(peer), &config),
+                None,
+                "{:?}",
+                bad
+            );
+        }
     }
 }
@@ -0,0 +1,195 @@
+//! `--log-format json`: one JSON object per log line, for log pipelines.
+//! Each line carries the event's fields plus those of every span it is in,
+//! so lines logged while serving a request include the request's ID, peer,
+//! method, target, and protocol.
+
+use serde_json::{Map, Value};
+use std::fmt;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Formats events as JSON objects. Use with `JsonFields` so span fields are
+/// stored as JSON too.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonFormat;
+
+/// Stores span fields as a JSON object, merging in fields recorded later.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonFields;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut line = Map::new();
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+        line.insert("timestamp".into(), timestamp.into());
+        line.insert("level".into(), event.metadata().level().as_str().into());
+        // `target` is the request's; name the logging module separately.
+        line.insert("module".into(), event.metadata().target().into());
+
+        for span in ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+        {
+            let extensions = span.extensions();
+            let Some(fields) = extensions.get::<FormattedFields<N>>() else {
+                continue;
+            };
+            if let Ok(fields) = serde_json::from_str::<Map<String, Value>>(&fields.fields) {
+                line.extend(fields);
+            }
+        }
+        event.record(&mut JsonVisitor(&mut line));
+
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut object = Map::new();
+        fields.record(&mut JsonVisitor(&mut object));
+        write!(writer, "{}", Value::Object(object))
+    }
+
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &tracing::span::Record<'_>,
+    ) -> fmt::Result {
+        let mut object: Map<String, Value> =
+            serde_json::from_str(&current.fields).unwrap_or_default();
+        fields.record(&mut JsonVisitor(&mut object));
+        current.fields = Value::Object(object).to_string();
+        Ok(())
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{:?}", value).into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ProxyConfig;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::BufReader;
+
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_logs_as_json_with_request_fields() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .event_format(JsonFormat)
+            .fmt_fields(JsonFields)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let mut reader = BufReader::new(Cursor::new(
+            b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec(),
+        ));
+        let mut writer = Vec::new();
+        crate::handle_connection(
+            &mut writer,
+            &mut reader,
+            Some("203.0.113.9:5000".parse().unwrap()),
+            &ProxyConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<Map<String, Value>> = logs
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("Every line is a JSON object"))
+            .collect();
+        let completed = lines
+            .iter()
+            .find(|line| line.contains_key("status"))
+            .unwrap_or_else(|| panic!("No completion line in: {}", logs));
+        for key in [
+            "timestamp",
+            "level",
+            "module",
+            "message",
+            "request_id",
+            "method",
+            "target",
+            "protocol",
+            "duration_ms",
+        ] {
+            assert!(
+                completed.contains_key(key),
+                "Missing {} in {:?}",
+                key,
+                completed
+            );
+        }
+        assert_eq!(completed["peer"], "203.0.113.9:5000");
+        assert_eq!(completed["method"], "GET");
+        assert_eq!(completed["target"], "/health");
+        assert_eq!(completed["status"], 200);
+        assert!(completed["duration_ms"].is_u64());
+    }
+}
@@ -0,0 +1,298 @@
+//! Process-wide counters, served at `/metrics` in the Prometheus text format
+//! and, with `--admin`, as JSON at `/stats`.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Instant;
+
+use crate::constants;
+
+/// Why the proxy refused a request. Each reason is a `reason` label on
+/// `rhoxy_blocked_requests_total`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockReason {
+    /// The target is, or resolves to, a private address.
+    SsrfPrivate,
+    /// A CONNECT or SOCKS5 port outside `connect_allowed_ports`.
+    PortNotAllowed,
+    /// A method outside `allowed_methods`.
+    MethodNotAllowed,
+    /// An upstream response with a blocked Content-Type.
+    ContentType,
+    /// A request outside the `allowed_hours` window.
+    OutsideAllowedHours,
+    /// A target a `--routes` rule says to `BLOCK`.
+    Route,
+}
+
+impl BlockReason {
+    pub const ALL: [BlockReason; 6] = [
+        BlockReason::SsrfPrivate,
+        BlockReason::PortNotAllowed,
+        BlockReason::MethodNotAllowed,
+        BlockReason::ContentType,
+        BlockReason::OutsideAllowedHours,
+        BlockReason::Route,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            BlockReason::SsrfPrivate => "ssrf_private",
+            BlockReason::PortNotAllowed => "port_not_allowed",
+            BlockReason::MethodNotAllowed => "method_not_allowed",
+            BlockReason::ContentType => "content_type",
+            BlockReason::OutsideAllowedHours => "outside_allowed_hours",
+            BlockReason::Route => "route",
+        }
+    }
+}
+
+static BLOCKED: [AtomicU64; BlockReason::ALL.len()] =
+    [const { AtomicU64::new(0) }; BlockReason::ALL.len()];
+
+/// Count a refused request and log it at `warn` with its `reason`. Every
+/// deny path goes through here so the log and the counters agree.
+pub fn record_block(reason: BlockReason, subject: impl Display, detail: impl Display) {
+    BLOCKED[reason as usize].fetch_add(1, Ordering::Relaxed);
+    tracing::warn!(reason = reason.label(), "Blocked {}: {}", subject, detail);
+}
+
+pub fn blocked_count(reason: BlockReason) -> u64 {
+    BLOCKED[reason as usize].load(Ordering::Relaxed)
+}
+
+static ACTIVE_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+static TOTAL_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+static BYTES_PROXIED: AtomicU64 = AtomicU64::new(0);
+static CLIENT_BYTES_READ: AtomicU64 = AtomicU64::new(0);
+static CLIENT_BYTES_WRITTEN: AtomicU64 = AtomicU64::new(0);
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// Start the uptime clock. Without this it starts at the first connection.
+pub fn start_clock() {
+    LazyLock::force(&STARTED);
+}
+
+/// Keeps a client connection counted as active until dropped.
+pub struct ConnectionGuard(());
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn track_connection() -> ConnectionGuard {
+    start_clock();
+    ACTIVE_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    TOTAL_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    ConnectionGuard(())
+}
+
+/// Count body or tunnel bytes relayed to a client from an upstream.
+pub fn record_bytes_proxied(bytes: u64) {
+    BYTES_PROXIED.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Count everything a connection read from and wrote to its client,
+/// headers included.
+pub fn record_client_bytes(read: u64, written: u64) {
+    CLIENT_BYTES_READ.fetch_add(read, Ordering::Relaxed);
+    CLIENT_BYTES_WRITTEN.fetch_add(written, Ordering::Relaxed);
+}
+
+/// Requests and client bytes attributed to one `--tenant-header` value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TenantUsage {
+    pub requests: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+/// Label for tenants past `MAX_TENANT_LABELS`. The parentheses fall outside
+/// the characters a tenant header value may use, so no real tenant shares it.
+pub const OTHER_TENANT: &str = "(other)";
+
+static TENANTS: LazyLock<Mutex<BTreeMap<String, TenantUsage>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+/// Attribute a request and its client bytes to `tenant`. Once
+/// `MAX_TENANT_LABELS` tenants have been seen, new ones are folded into
+/// `OTHER_TENANT`.
+pub fn record_tenant_request(tenant: &str, read: u64, written: u64) {
+    let mut tenants = TENANTS.lock().unwrap_or_else(|e| e.into_inner());
+    let label = if tenants.contains_key(tenant) || tenants.len() < constants::MAX_TENANT_LABELS {
+        tenant
+    } else {
+        OTHER_TENANT
+    };
+    let usage = tenants.entry(label.to_string()).or_default();
+    usage.requests += 1;
+    usage.bytes_read += read;
+    usage.bytes_written += written;
+}
+
+pub fn tenant_usage(tenant: &str) -> Option<TenantUsage> {
+    let tenants = TENANTS.lock().unwrap_or_else(|e| e.into_inner());
+    tenants.get(tenant).copied()
+}
+
+/// A snapshot of the connection and traffic counters served at `/stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Stats {
+    pub active_connections: u64,
+    pub total_connections: u64,
+    pub uptime_seconds: u64,
+    pub bytes_proxied: u64,
+    pub client_bytes_read: u64,
+    pub client_bytes_written: u64,
+}
+
+pub fn stats() -> Stats {
+    Stats {
+        active_connections: ACTIVE_CONNECTIONS.load(Ordering::Relaxed),
+        total_connections: TOTAL_CONNECTIONS.load(Ordering::Relaxed),
+        uptime_seconds: STARTED.elapsed().as_secs(),
+        bytes_proxied: BYTES_PROXIED.load(Ordering::Relaxed),
+        client_bytes_read: CLIENT_BYTES_READ.load(Ordering::Relaxed),
+        client_bytes_written: CLIENT_BYTES_WRITTEN.load(Ordering::Relaxed),
+    }
+}
+
+/// Every counter in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut out = String::new();
+    out.push_str("# HELP rhoxy_blocked_requests_total Requests refused by policy.\n");
+    out.push_str("# TYPE rhoxy_blocked_requests_total counter\n");
+    for reason in BlockReason::ALL {
+        let _ = writeln!(
+            out,
+            "rhoxy_blocked_requests_total{{reason=\"{}\"}} {}",
+            reason.label(),
+            blocked_count(reason)
+        );
+    }
+    out.push_str(
+        "# HELP rhoxy_client_bytes_total Bytes read from or written to clients, headers included.\n",
+    );
+    out.push_str("# TYPE rhoxy_client_bytes_total counter\n");
+    for (direction, counter) in [
+        ("read", &CLIENT_BYTES_READ),
+        ("written", &CLIENT_BYTES_WRITTEN),
+    ] {
+        let _ = writeln!(
+            out,
+            "rhoxy_client_bytes_total{{direction=\"{}\"}} {}",
+            direction,
+            counter.load(Ordering::Relaxed)
+        );
+    }
+
+    let tenants = TENANTS.lock().unwrap_or_else(|e| e.into_inner());
+    if !tenants.is_empty() {
+        out.push_str("# HELP rhoxy_tenant_requests_total Requests by --tenant-header value.\n");
+        out.push_str("# TYPE rhoxy_tenant_requests_total counter\n");
+        for (tenant, usage) in tenants.iter() {
+            let _ = writeln!(
+                out,
+                "rhoxy_tenant_requests_total{{tenant=\"{}\"}} {}",
+                tenant, usage.requests
+            );
+        }
+        out.push_str(
+            "# HELP rhoxy_tenant_client_bytes_total Client bytes by --tenant-header value.\n",
+        );
+        out.push_str("# TYPE rhoxy_tenant_client_bytes_total counter\n");
+        for (tenant, usage) in tenants.iter() {
+            for (direction, bytes) in [("read", usage.bytes_read), ("written", usage.bytes_written)]
+            {
+                let _ = writeln!(
+                    out,
+                    "rhoxy_tenant_client_bytes_total{{tenant=\"{}\",direction=\"{}\"}} {}",
+                    tenant, direction, bytes
+                );
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_block_increments_counter() {
+        // Counters are process-wide and other tests block concurrently, so
+        // only check that ours moved.
+        let before = blocked_count(BlockReason::PortNotAllowed);
+        record_block(
+            BlockReason::PortNotAllowed,
+            "tunnel to example.com:25",
+            "test",
+        );
+        assert!(blocked_count(BlockReason::PortNotAllowed) > before);
+    }
+
+    #[test]
+    fn test_connection_guard_tracks_active_and_total() {
+        // Process-wide like the block counters, so compare with our own
+        // snapshot taken while the guard is held.
+        let before = stats();
+        let guard = track_connection();
+        let during = stats();
+        assert!(during.total_connections > before.total_connections);
+        assert!(during.active_connections >= 1);
+        drop(guard);
+
+        record_bytes_proxied(10);
+        record_client_bytes(3, 4);
+        let after = stats();
+        assert!(after.bytes_proxied >= before.bytes_proxied + 10);
+        assert!(after.client_bytes_read >= before.client_bytes_read + 3);
+        assert!(after.client_bytes_written >= before.client_bytes_written + 4);
+    }
+
+    #[test]
+    fn test_tenant_labels_are_bounded() {
+        // The tenant table is process-wide; this is the only unit test that
+        // writes to it, so it can fill the table.
+        for i in 0..constants::MAX_TENANT_LABELS + 10 {
+            record_tenant_request(&format!("tenant-{}", i), 1, 2);
+        }
+        record_tenant_request("tenant-0", 1, 2);
+
+        assert_eq!(
+            tenant_usage("tenant-0"),
+            Some(TenantUsage {
+                requests: 2,
+                bytes_read: 2,
+                bytes_written: 4,
+            })
+        );
+        let overflow = format!("tenant-{}", constants::MAX_TENANT_LABELS + 5);
+        assert_eq!(tenant_usage(&overflow), None);
+        assert_eq!(tenant_usage(OTHER_TENANT).unwrap().requests, 10);
+        assert!(render().contains("rhoxy_tenant_requests_total{tenant=\"(other)\"} 10\n"));
+    }
+
+    #[test]
+    fn test_render_lists_every_counter() {
+        let text = render();
+        assert!(text.contains("# TYPE rhoxy_blocked_requests_total counter\n"));
+        for reason in BlockReason::ALL {
+            assert!(
+                text.contains(&format!(
+                    "rhoxy_blocked_requests_total{{reason=\"{}\"}} ",
+                    reason.label()
+                )),
+                "Missing {} in: {}",
+                reason.label(),
+                text
+            );
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use anyhow::Result;
+use std::fmt;
+use std::io::SeekFrom;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// A fully-buffered request body, kept either in memory or in an anonymous
+/// temp file once it grows past the spill threshold. Both forms can be
+/// turned into a `reqwest::Body` more than once so the request stays
+/// replayable.
+#[derive(Debug)]
+pub enum RequestBody {
+    Memory(Vec<u8>),
+    Spilled { file: File, len: u64 },
+}
+
+impl RequestBody {
+    pub fn len(&self) -> u64 {
+        match self {
+            RequestBody::Memory(bytes) => bytes.len() as u64,
+            RequestBody::Spilled { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_spilled(&self) -> bool {
+        matches!(self, RequestBody::Spilled { .. })
+    }
+
+    /// Build a body for reqwest. Spilled bodies are streamed from the start
+    /// of the temp file through a cloned handle.
+    pub async fn to_reqwest_body(&self) -> Result<reqwest::Body> {
+        match self {
+            RequestBody::Memory(bytes) => Ok(reqwest::Body::from(bytes.clone())),
+            RequestBody::Spilled { file, .. } => {
+                let mut file = file.try_clone().await?;
+                file.seek(SeekFrom::Start(0)).await?;
+                Ok(reqwest::Body::from(file))
+            }
+        }
+    }
+
+    /// The first `max` bytes, for `--trace-bodies`. Spilled bodies are read
+    /// through a cloned handle.
+    pub async fn preview(&self, max: usize) -> Result<BodyPreview> {
+        let mut preview = BodyPreview::new(max);
+        match self {
+            RequestBody::Memory(bytes) => preview.push(bytes),
+            RequestBody::Spilled { file, len } => {
+                let mut file = file.try_clone().await?;
+                file.seek(SeekFrom::Start(0)).await?;
+                file.take(max as u64)
+                    .read_to_end(&mut preview.prefix)
+                    .await?;
+                preview.len = *len;
+            }
+        }
+        Ok(preview)
+    }
+}
+
+/// The first `max` bytes of a body and its total length, collected as it
+/// streams past so nothing beyond the prefix is held. Displays as UTF-8
+/// when the prefix is text and as hex otherwise.
+#[derive(Debug)]
+pub struct BodyPreview {
+    max: usize,
+    prefix: Vec<u8>,
+    len: u64,
+}
+
+impl BodyPreview {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            prefix: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) {
+        let room = self.max.saturating_sub(self.prefix.len());
+        self.prefix
+            .extend_from_slice(&chunk[..room.min(chunk.len())]);
+        self.len += chunk.len() as u64;
+    }
+}
+
+impl fmt::Display for BodyPreview {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes", self.len)?;
+        if self.len > self.prefix.len() as u64 {
+            write!(f, ", first {}", self.prefix.len())?;
+        }
+        let text = match std::str::from_utf8(&self.prefix) {
+            Ok(text) => Some(text),
+            // The cap split a character; the rest is still text.
+            Err(e) if e.error_len().is_none() => {
+                std::str::from_utf8(&self.prefix[..e.valid_up_to()]).ok()
+            }
+            Err(_) => None,
+        };
+        match text {
+            Some(text) => write!(f, ": {:?}", text),
+            None => {
+                f.write_str(" (hex): ")?;
+                self.prefix.iter().try_for_each(|b| write!(f, "{:02x}", b))
+            }
+        }
+    }
+}
+
+/// Accumulates body bytes in memory until `spill_threshold` is exceeded,
+/// then moves everything to an anonymous temp file. The OS removes the
+/// file once the last handle is dropped, on success or error alike.
+pub struct BodyBuffer {
+    spill_threshold: Option<usize>,
+    memory: Vec<u8>,
+    file: Option<File>,
+    len: u64,
+}
+
+impl BodyBuffer {
+    pub fn new(spill_threshold: Option<usize>) -> Self {
+        Self {
+            spill_threshold,
+            memory: Vec::new(),
+            file: None,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.len += data.len() as u64;
+
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(data).await?;
+            return Ok(());
+        }
+
+        self.memory.extend_from_slice(data);
+        if let Some(threshold) = self.spill_threshold {
+            if self.memory.len() > threshold {
+                let mut file = File::from_std(tempfile::tempfile()?);
+                file.write_all(&self.memory).await?;
+                self.memory = Vec::new();
+                self.file = Some(file);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn finish(self) -> Result<RequestBody> {
+        match self.file {
+            Some(mut file) => {
+                file.flush().await?;
+                Ok(RequestBody::Spilled {
+                    file,
+                    len: self.len,
+                })
+            }
+            None => Ok(RequestBody::Memory(self.memory)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_body_buffer_stays_in_memory_under_threshold() {
+        let mut buffer = BodyBuffer::new(Some(16));
+        buffer.write(b"small").await.unwrap();
+
+        let body = buffer.finish().await.unwrap();
+        assert!(!body.is_spilled());
+        assert_eq!(body.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_body_buffer_spills_over_threshold() {
+        let mut buffer = BodyBuffer::new(Some(4));
+        buffer.write(b"abc").await.unwrap();
+        buffer.write(b"defgh").await.unwrap();
+
+        let body = buffer.finish().await.unwrap();
+        assert!(body.is_spilled());
+        assert_eq!(body.len(), 8);
+
+        let RequestBody::Spilled { file, .. } = &body else {
+            unreachable!();
+        };
+        let mut file = file.try_clone().await.unwrap();
+        file.seek(SeekFrom::Start(0)).await.unwrap();
+        let mut contents = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut file, &mut contents)
+            .await
+            .unwrap();
+        assert_eq!(contents, b"abcdefgh");
+    }
+
+    #[test]
+    fn test_body_preview_caps_prefix() {
+        let mut preview = BodyPreview::new(8);
+        preview.push(b"small");
+        assert_eq!(preview.to_string(), r#"5 bytes: "small""#);
+
+        preview.push(b" and then some");
+        assert_eq!(preview.to_string(), r#"19 bytes, first 8: "small an""#);
+
+        let mut preview = BodyPreview::new(4);
+        preview.push(&[0x00, 0xff, 0x10, 0x20, 0x30]);
+        assert_eq!(preview.to_string(), "5 bytes, first 4 (hex): 00ff1020");
+
+        // A cap landing inside a multi-byte character keeps the text.
+        let mut preview = BodyPreview::new(2);
+        preview.push("héllo".as_bytes());
+        assert_eq!(preview.to_string(), r#"6 bytes, first 2: "h""#);
+    }
+
+    #[tokio::test]
+    async fn test_spilled_body_preview_reads_prefix() {
+        let mut buffer = BodyBuffer::new(Some(4));
+        buffer.write(b"spilled body").await.unwrap();
+        let body = buffer.finish().await.unwrap();
+
+        let preview = body.preview(7).await.unwrap();
+        assert_eq!(preview.to_string(), r#"12 bytes, first 7: "spilled""#);
+    }
+
+    #[tokio::test]
+    async fn test_body_buffer_without_threshold_never_spills() {
+        let mut buffer = BodyBuffer::new(None);
+        buffer.write(&vec![0u8; 64 * 1024]).await.unwrap();
+
+        let body = buffer.finish().await.unwrap();
+        assert!(!body.is_spilled());
+    }
+}
@@ -0,0 +1,151 @@
+use flate2::write::{GzDecoder, GzEncoder, ZlibDecoder};
+use flate2::Compression;
+use std::io::Write;
+
+/// Incremental decoder for a `Content-Encoding` we know how to undo. Feed it
+/// upstream chunks as they arrive; each call returns whatever plaintext is
+/// ready so the response can still be streamed.
+pub enum Decoder {
+    Gzip(GzDecoder<Vec<u8>>),
+    Deflate(ZlibDecoder<Vec<u8>>),
+}
+
+impl Decoder {
+    /// `None` for encodings other than `gzip`/`x-gzip` and `deflate`.
+    pub fn for_encoding(content_encoding: &str) -> Option<Self> {
+        match content_encoding.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Decoder::Gzip(GzDecoder::new(Vec::new()))),
+            "deflate" => Some(Decoder::Deflate(ZlibDecoder::new(Vec::new()))),
+            _ => None,
+        }
+    }
+
+    pub fn decode(&mut self, chunk: &[u8]) -> std::io::Result<Vec<u8>> {
+        let output = match self {
+            Decoder::Gzip(decoder) => {
+                decoder.write_all(chunk)?;
+                decoder.get_mut()
+            }
+            Decoder::Deflate(decoder) => {
+                decoder.write_all(chunk)?;
+                decoder.get_mut()
+            }
+        };
+        Ok(std::mem::take(output))
+    }
+
+    /// Flush the tail of the stream. Errors if the body was truncated.
+    pub fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Decoder::Gzip(decoder) => decoder.finish(),
+            Decoder::Deflate(decoder) => decoder.finish(),
+        }
+    }
+}
+
+/// Incremental gzip encoder for `--compress-responses`, the inverse of
+/// `Decoder`. Output comes out in compressed blocks, so some calls return
+/// nothing.
+pub struct Encoder(GzEncoder<Vec<u8>>);
+
+impl Encoder {
+    pub fn gzip() -> Self {
+        Encoder(GzEncoder::new(Vec::new(), Compression::default()))
+    }
+
+    pub fn encode(&mut self, chunk: &[u8]) -> std::io::Result<Vec<u8>> {
+        self.0.write_all(chunk)?;
+        Ok(std::mem::take(self.0.get_mut()))
+    }
+
+    /// The rest of the compressed stream and its trailer.
+    pub fn finish(self) -> std::io::Result<Vec<u8>> {
+        self.0.finish()
+    }
+}
+
+/// Whether an `Accept-Encoding` value admits `encoding`, either by name or
+/// via `*`. An explicit `q=0` counts as refusal.
+pub fn accepts_encoding(accept_encoding: &str, encoding: &str) -> bool {
+    accept_encoding.split(',').any(|item| {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim();
+        let refused = parts.any(|param| {
+            param
+                .trim()
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        !refused && (coding == "*" || coding.eq_ignore_ascii_case(encoding))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+
+    fn decode_in_pieces(mut decoder: Decoder, encoded: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for piece in encoded.chunks(7) {
+            out.extend(decoder.decode(piece).unwrap());
+        }
+        out.extend(decoder.finish().unwrap());
+        out
+    }
+
+    #[test]
+    fn test_gzip_round_trip_in_pieces() {
+        let plain = b"hello hello hello hello".repeat(50);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&plain).unwrap();
+        let encoded = encoder.finish().unwrap();
+
+        let decoder = Decoder::for_encoding("GZIP").unwrap();
+        assert_eq!(decode_in_pieces(decoder, &encoded), plain);
+    }
+
+    #[test]
+    fn test_deflate_round_trip_in_pieces() {
+        let plain = b"abcabcabcabc".repeat(40);
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&plain).unwrap();
+        let encoded = encoder.finish().unwrap();
+
+        let decoder = Decoder::for_encoding("deflate").unwrap();
+        assert_eq!(decode_in_pieces(decoder, &encoded), plain);
+    }
+
+    #[test]
+    fn test_encoder_output_decodes_to_input() {
+        let plain = b"compress me please ".repeat(100);
+        let mut encoder = Encoder::gzip();
+        let mut encoded = Vec::new();
+        for piece in plain.chunks(13) {
+            encoded.extend(encoder.encode(piece).unwrap());
+        }
+        encoded.extend(encoder.finish().unwrap());
+        assert!(encoded.len() < plain.len());
+
+        let decoder = Decoder::for_encoding("gzip").unwrap();
+        assert_eq!(decode_in_pieces(decoder, &encoded), plain);
+    }
+
+    #[test]
+    fn test_unknown_encoding_has_no_decoder() {
+        assert!(Decoder::for_encoding("br").is_none());
+        assert!(Decoder::for_encoding("identity").is_none());
+    }
+
+    #[test]
+    fn test_accepts_encoding() {
+        assert!(accepts_encoding("gzip, deflate, br", "gzip"));
+        assert!(accepts_encoding("br;q=1.0, GZIP;q=0.5", "gzip"));
+        assert!(accepts_encoding("*", "deflate"));
+        assert!(!accepts_encoding("br", "gzip"));
+        assert!(!accepts_encoding("gzip;q=0", "gzip"));
+        assert!(!accepts_encoding("identity", "deflate"));
+    }
+}
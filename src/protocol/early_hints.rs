@@ -0,0 +1,151 @@
+//! `--early-hints`: send a request over a dedicated HTTP/1.1 connection so
+//! `103 Early Hints` from the upstream can be relayed to the client ahead of
+//! the final response. reqwest swallows interim responses, so this path
+//! drives hyper directly and hands the final response back as a
+//! `reqwest::Response` for the usual forwarding.
+
+use anyhow::{Context, Result};
+use http::header::{HeaderMap, HeaderValue, HOST};
+use hyper::client::conn::http1::SendRequest;
+use hyper_util::rt::TokioIo;
+use reqwest::Url;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+use crate::config::ProxyConfig;
+use crate::constants;
+use crate::protocol::happy_eyeballs;
+
+const EARLY_HINTS_STATUS_LINE: &[u8] = b"HTTP/1.1 103 Early Hints\r\n";
+
+/// Send `request` (origin-form, headers already filtered) to one of `addrs`,
+/// the pre-verified addresses for `url`'s host, writing each `103` to
+/// `writer` as it arrives. The upstream timeout covers everything up to the
+/// final response's head.
+pub(super) async fn send<W>(
+    writer: &mut W,
+    mut request: http::Request<reqwest::Body>,
+    url: &Url,
+    addrs: &[SocketAddr],
+    config: &ProxyConfig,
+) -> Result<reqwest::Response>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    let host = url.host_str().context("URL has no host")?;
+    if !request.headers().contains_key(HOST) {
+        let authority = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        request
+            .headers_mut()
+            .insert(HOST, HeaderValue::from_str(&authority)?);
+    }
+
+    let (hints_tx, mut hints_rx) = mpsc::unbounded_channel();
+    hyper::ext::on_informational(&mut request, move |interim| {
+        if interim.status().as_u16() == 103 {
+            let _ = hints_tx.send(interim.headers().clone());
+        }
+    });
+
+    let exchange = async {
+        let mut sender = connect(url, host, addrs, config).await?;
+        anyhow::Ok(sender.send_request(request).await?)
+    };
+    let relay = async {
+        tokio::pin!(exchange);
+        loop {
+            tokio::select! {
+                // Hints always precede the final response on the wire, so
+                // drain them first.
+                biased;
+                Some(hints) = hints_rx.recv() => write_early_hints(writer, &hints).await?,
+                response = &mut exchange => {
+                    while let Ok(hints) = hints_rx.try_recv() {
+                        write_early_hints(writer, &hints).await?;
+                    }
+                    return response;
+                }
+            }
+        }
+    };
+    let timeout = config
+        .upstream_timeout
+        .unwrap_or(constants::UPSTREAM_TIMEOUT);
+    let response = tokio::time::timeout(timeout, relay).await??;
+    Ok(response.map(reqwest::Body::wrap).into())
+}
+
+async fn connect(
+    url: &Url,
+    host: &str,
+    addrs: &[SocketAddr],
+    config: &ProxyConfig,
+) -> Result<SendRequest<reqwest::Body>> {
+    if addrs.is_empty() {
+        anyhow::bail!("No verified address for {}", host);
+    }
+    let connect_timeout = config
+        .connect_timeout
+        .unwrap_or(constants::UPSTREAM_CONNECT_TIMEOUT);
+    let stream = tokio::time::timeout(
+        connect_timeout,
+        happy_eyeballs::connect(addrs, config.egress_bind),
+    )
+    .await??;
+    stream.set_nodelay(true)?;
+    config.configure_socket(&stream);
+
+    if url.scheme() != "https" {
+        return handshake(stream).await;
+    }
+    #[allow(unused_mut)]
+    let mut tls = native_tls::TlsConnector::builder();
+    #[cfg(feature = "_test-support")]
+    if crate::test_support::accepts_invalid_upstream_certs() {
+        tls.danger_accept_invalid_certs(true);
+    }
+    #[cfg(feature = "_test-support")]
+    if let Some(pem) = crate::test_support::upstream_root_certificate() {
+        tls.add_root_certificate(native_tls::Certificate::from_pem(&pem)?);
+    }
+    let tls = tokio_native_tls::TlsConnector::from(tls.build()?);
+    let stream = tokio::time::timeout(connect_timeout, tls.connect(host, stream)).await??;
+    handshake(stream).await
+}
+
+/// Start an HTTP/1.1 connection over `io`, driven in the background until
+/// the response body is done.
+async fn handshake<T>(io: T) -> Result<SendRequest<reqwest::Body>>
+where
+    T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(io)).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("Early hints upstream connection ended: {}", e);
+        }
+    });
+    Ok(sender)
+}
+
+async fn write_early_hints<W>(writer: &mut W, hints: &HeaderMap) -> Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    debug!("Relaying 103 Early Hints with {} headers", hints.len());
+    writer.write_all(EARLY_HINTS_STATUS_LINE).await?;
+    for (key, value) in hints {
+        writer.write_all(key.as_str().as_bytes()).await?;
+        writer.write_all(b": ").await?;
+        writer.write_all(value.as_bytes()).await?;
+        writer.write_all(b"\r\n").await?;
+    }
+    writer.write_all(b"\r\n").await?;
+    writer.flush().await?;
+    Ok(())
+}
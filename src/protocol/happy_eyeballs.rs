@@ -0,0 +1,188 @@
+//! Happy Eyeballs (RFC 8305) connection racing for upstreams with several
+//! addresses. Attempts alternate between address families and start
+//! `HAPPY_EYEBALLS_DELAY` apart, or as soon as the previous one fails, so
+//! a dead IPv6 route costs a short stagger instead of a full connect
+//! timeout. The first connection to succeed is used; the rest are dropped.
+//! With `--egress-bind`, every attempt is made from that source address.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::task::JoinSet;
+use tracing::debug;
+
+use crate::constants;
+
+/// Connect to the first of `addrs` that answers, from `source` if given.
+/// The caller bounds the whole race with its connect timeout.
+pub(crate) async fn connect(addrs: &[SocketAddr], source: Option<IpAddr>) -> io::Result<TcpStream> {
+    // A socket bound to one family can't reach the other.
+    let addrs: Vec<SocketAddr> = addrs
+        .iter()
+        .copied()
+        .filter(|addr| source.is_none_or(|source| source.is_ipv4() == addr.is_ipv4()))
+        .collect();
+    if let (Some(source), true) = (source, addrs.is_empty()) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no address of the same family as {} to connect to", source),
+        ));
+    }
+
+    let mut queue = interleave_families(&addrs).into_iter().peekable();
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+
+    loop {
+        if let Some(addr) = queue.next() {
+            attempts.spawn(async move {
+                connect_from(addr, source).await.map_err(|e| {
+                    debug!("Connect attempt to {} failed: {}", addr, e);
+                    e
+                })
+            });
+        }
+        if attempts.is_empty() {
+            break;
+        }
+
+        let stagger = tokio::time::sleep(constants::HAPPY_EYEBALLS_DELAY);
+        tokio::select! {
+            Some(joined) = attempts.join_next() => match joined {
+                Ok(Ok(stream)) => return Ok(stream),
+                // The next attempt starts right away.
+                Ok(Err(e)) => last_error = Some(e),
+                Err(e) => last_error = Some(io::Error::other(e)),
+            },
+            _ = stagger, if queue.peek().is_some() => {}
+        }
+    }
+
+    Err(last_error
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to")))
+}
+
+/// Connect to `addr`, binding the socket to `source` first if given.
+pub(crate) async fn connect_from(
+    addr: SocketAddr,
+    source: Option<IpAddr>,
+) -> io::Result<TcpStream> {
+    let Some(source) = source else {
+        return TcpStream::connect(addr).await;
+    };
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.bind(SocketAddr::new(source, 0))?;
+    socket.connect(addr).await
+}
+
+/// Order `addrs` so families alternate, starting with the family of the
+/// first address (the resolver's preference).
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return Vec::new();
+    };
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .iter()
+        .partition(|addr| addr.is_ipv6() == first.is_ipv6());
+
+    let mut ordered = Vec::with_capacity(addrs.len());
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    /// A loopback address nothing is listening on.
+    fn refused_addr() -> SocketAddr {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_interleave_families_alternates_from_first() {
+        let addrs: Vec<SocketAddr> = [
+            "[2001:db8::1]:443",
+            "[2001:db8::2]:443",
+            "[2001:db8::3]:443",
+            "192.0.2.1:443",
+        ]
+        .iter()
+        .map(|a| a.parse().unwrap())
+        .collect();
+        let ordered: Vec<String> = interleave_families(&addrs)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            ordered,
+            [
+                "[2001:db8::1]:443",
+                "192.0.2.1:443",
+                "[2001:db8::2]:443",
+                "[2001:db8::3]:443"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_skips_dead_addresses() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+        // 192.0.2.0/24 is reserved for documentation, so nothing answers: the
+        // attempt either fails at once or hangs until the stagger moves on.
+        let blackhole: SocketAddr = "192.0.2.1:9".parse().unwrap();
+
+        let stream = tokio::time::timeout(
+            Duration::from_secs(2),
+            connect(&[blackhole, refused_addr(), live], None),
+        )
+        .await
+        .expect("A dead address must not stall the connect")
+        .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), live);
+    }
+
+    #[tokio::test]
+    async fn test_connect_reports_last_error_when_all_fail() {
+        let err = connect(&[refused_addr(), refused_addr()], None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+
+        let err = connect(&[], None).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_connect_binds_source_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+        // All of 127.0.0.0/8 is loopback on Linux, so this is a second local
+        // address without any setup.
+        let source: IpAddr = "127.0.0.2".parse().unwrap();
+        let v6: SocketAddr = "[::1]:9".parse().unwrap();
+
+        let _stream = connect(&[v6, live], Some(source)).await.unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer.ip(), source);
+
+        let err = connect(&[v6], Some(source)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,362 @@
+//! `--mitm`: terminate a CONNECT tunnel's TLS with a leaf certificate issued
+//! by a configured CA, so the request inside can go through the normal HTTP
+//! path (SSRF checks, logging, blocking) and out to the upstream over a fresh
+//! TLS connection. Only clients that trust the CA will accept the leaf.
+
+use anyhow::{Context, Result};
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::extension::{
+    BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName,
+};
+use openssl::x509::{X509NameBuilder, X509};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio_native_tls::TlsAcceptor;
+use tracing::{info, warn};
+
+use crate::config::ProxyConfig;
+use crate::constants;
+use crate::protocol::http::write_error_response;
+use crate::protocol::{http, https, Outcome, RequestHead};
+
+/// Issued leaves are cached per host; past this many the cache starts over
+/// rather than growing without bound.
+const MAX_CACHED_LEAVES: usize = 1024;
+const LEAF_VALIDITY_DAYS: u32 = 30;
+
+/// The CA that signs per-host leaf certificates, with the acceptors built
+/// from leaves issued so far.
+pub struct MitmAuthority {
+    ca_cert: X509,
+    ca_key: PKey<Private>,
+    acceptors: Mutex<HashMap<String, TlsAcceptor>>,
+}
+
+impl fmt::Debug for MitmAuthority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MitmAuthority")
+            .field("ca", &self.ca_cert.subject_name())
+            .finish_non_exhaustive()
+    }
+}
+
+impl MitmAuthority {
+    pub fn new(ca_cert: X509, ca_key: PKey<Private>) -> Self {
+        Self {
+            ca_cert,
+            ca_key,
+            acceptors: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Load a PEM CA certificate and its PEM private key.
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self> {
+        let cert = std::fs::read(cert_path)
+            .with_context(|| format!("Failed to read {}", cert_path.display()))?;
+        let key = std::fs::read(key_path)
+            .with_context(|| format!("Failed to read {}", key_path.display()))?;
+        let ca_cert = X509::from_pem(&cert)
+            .with_context(|| format!("Invalid CA certificate {}", cert_path.display()))?;
+        let ca_key = PKey::private_key_from_pem(&key)
+            .with_context(|| format!("Invalid CA key {}", key_path.display()))?;
+        if !ca_cert.public_key()?.public_eq(&ca_key) {
+            anyhow::bail!(
+                "{} is not the key for {}",
+                key_path.display(),
+                cert_path.display()
+            );
+        }
+        Ok(Self::new(ca_cert, ca_key))
+    }
+
+    fn acceptor_for(&self, host: &str) -> Result<TlsAcceptor> {
+        let mut acceptors = self.acceptors.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(acceptor) = acceptors.get(host) {
+            return Ok(acceptor.clone());
+        }
+        if acceptors.len() >= MAX_CACHED_LEAVES {
+            acceptors.clear();
+        }
+
+        let (cert, key) = self.issue_leaf(host)?;
+        let mut chain = cert.to_pem()?;
+        chain.extend_from_slice(&self.ca_cert.to_pem()?);
+        let identity = native_tls::Identity::from_pkcs8(&chain, &key.private_key_to_pem_pkcs8()?)?;
+        let acceptor = TlsAcceptor::from(native_tls::TlsAcceptor::new(identity)?);
+        acceptors.insert(host.to_string(), acceptor.clone());
+        Ok(acceptor)
+    }
+
+    /// A P-256 leaf for `host` (a DNS name or IP literal), signed by the CA.
+    pub fn issue_leaf(&self, host: &str) -> Result<(X509, PKey<Private>)> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, host)?;
+        let name = name.build();
+
+        let mut serial = BigNum::new()?;
+        serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+
+        // Backdated an hour to tolerate client clock skew.
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+        let serial = serial.to_asn1_integer()?;
+        let not_before = Asn1Time::from_unix(now - 3600)?;
+        let not_after = Asn1Time::days_from_now(LEAF_VALIDITY_DAYS)?;
+
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
+        builder.set_serial_number(&serial)?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(self.ca_cert.subject_name())?;
+        builder.set_pubkey(&key)?;
+        builder.set_not_before(&not_before)?;
+        builder.set_not_after(&not_after)?;
+        builder.append_extension(BasicConstraints::new().build()?)?;
+        builder.append_extension(
+            KeyUsage::new()
+                .critical()
+                .digital_signature()
+                .key_agreement()
+                .build()?,
+        )?;
+        builder.append_extension(ExtendedKeyUsage::new().server_auth().build()?)?;
+        let mut san = SubjectAlternativeName::new();
+        if host.parse::<std::net::IpAddr>().is_ok() {
+            san.ip(host);
+        } else {
+            san.dns(host);
+        }
+        let san = san.build(&builder.x509v3_context(Some(&self.ca_cert), None))?;
+        builder.append_extension(san)?;
+        builder.sign(&self.ca_key, MessageDigest::sha256())?;
+
+        Ok((builder.build(), key))
+    }
+}
+
+/// Answer a CONNECT by decrypting the tunnel instead of relaying it. The
+/// target gets the same port and SSRF checks as a plain tunnel; the one
+/// request inside is then handled like any HTTP request to
+/// `https://{target}`.
+pub async fn handle_request<W, R>(
+    writer: &mut W,
+    reader: &mut R,
+    head: RequestHead,
+    config: &ProxyConfig,
+    authority: &MitmAuthority,
+    request_id: &str,
+    peer_addr: Option<SocketAddr>,
+) -> Result<Outcome>
+where
+    W: AsyncWriteExt + Unpin,
+    R: AsyncBufReadExt + Unpin,
+{
+    let RequestHead {
+        target, received, ..
+    } = head;
+    let (host, port) = https::parse_host_port(&target)?;
+    if let Err(refusal) = https::check_target(&target, host, port, config).await {
+        crate::tarpit(config).await;
+        return write_error_response(writer, refusal.http_status(), refusal.detail(), None, None)
+            .await;
+    }
+    let acceptor = authority.acceptor_for(host)?;
+
+    writer
+        .write_all(constants::CONNECTION_ESTABLISHED_RESPONSE)
+        .await?;
+    writer.flush().await?;
+
+    let tls = match acceptor.accept(tokio::io::join(reader, writer)).await {
+        Ok(tls) => tls,
+        Err(e) => {
+            // Typically a client that doesn't trust the CA.
+            warn!("TLS handshake for intercepted {} failed: {}", target, e);
+            return Ok(Outcome::status(200));
+        }
+    };
+    let (tls_reader, mut tls_writer) = tokio::io::split(tls);
+    let mut tls_reader = BufReader::with_capacity(config.io_buffer_size(), tls_reader);
+
+    let max_request_line = config
+        .max_request_line
+        .unwrap_or(constants::MAX_REQUEST_LINE_LEN);
+    let max_header_bytes = config
+        .max_header_bytes
+        .unwrap_or(constants::MAX_HEADER_BYTES);
+    let head = match read_inner_head(
+        &mut tls_reader,
+        &target,
+        max_request_line,
+        max_header_bytes,
+        received,
+    )
+    .await
+    {
+        Ok(head) => head,
+        Err(e) if crate::is_client_io_error(&e) => return Err(e),
+        Err(e) => {
+            warn!("Malformed request inside {}: {}", target, e);
+            return write_error_response(&mut tls_writer, 400, &e.to_string(), None, None).await;
+        }
+    };
+    info!("[MITM] {} {}", head.method, head.target);
+
+    let outcome = if !config.http_version_allowed(head.version) {
+        warn!("Refused {:?} request inside {}", head.version, target);
+        write_error_response(
+            &mut tls_writer,
+            505,
+            "HTTP/1.0 requests are not served",
+            None,
+            None,
+        )
+        .await?
+    } else if !config.method_allowed(&head.method) {
+        crate::metrics::record_block(
+            crate::metrics::BlockReason::MethodNotAllowed,
+            format_args!("{} request to {}", head.method, head.target),
+            "method not allowed",
+        );
+        crate::tarpit(config).await;
+        crate::reject_method(&mut tls_writer, config).await?;
+        Outcome::status(405)
+    } else {
+        http::handle_request(
+            &mut tls_writer,
+            &mut tls_reader,
+            head,
+            config,
+            request_id,
+            peer_addr,
+        )
+        .await?
+    };
+    let _ = tls_writer.shutdown().await;
+    Ok(outcome)
+}
+
+/// Read the decrypted request's head, turning its origin-form target into
+/// an absolute `https` URL on the tunnel's host.
+async fn read_inner_head<R>(
+    reader: &mut R,
+    target: &str,
+    max_request_line: usize,
+    max_header_bytes: usize,
+    received: Instant,
+) -> Result<RequestHead>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let (method, path, version) = crate::extract_request_parts(reader, max_request_line).await?;
+    let headers = http::parse_request_headers(reader, max_header_bytes).await?;
+    if !path.starts_with('/') {
+        return Err(crate::error::ProxyError::InvalidTarget(format!(
+            "Expected a path inside the tunnel, got {}",
+            path
+        ))
+        .into());
+    }
+    Ok(RequestHead {
+        method,
+        target: format!("https://{}{}", target, path),
+        version,
+        headers,
+        received,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::x509::X509VerifyResult;
+
+    /// A throwaway self-signed CA.
+    fn test_authority() -> MitmAuthority {
+        let key = PKey::from_ec_key(
+            EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap(),
+        )
+        .unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "rhoxy test CA")
+            .unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder
+            .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        MitmAuthority::new(builder.build(), key)
+    }
+
+    #[test]
+    fn test_issue_leaf_is_signed_by_ca_for_host() {
+        let authority = test_authority();
+        for host in ["example.com", "203.0.113.7"] {
+            let (leaf, _) = authority.issue_leaf(host).unwrap();
+            assert_eq!(
+                authority.ca_cert.issued(&leaf),
+                X509VerifyResult::OK,
+                "{} leaf not issued by the CA",
+                host
+            );
+            assert!(leaf.verify(&authority.ca_key).unwrap());
+            let names = leaf.subject_alt_names().unwrap();
+            let name = names.iter().next().unwrap();
+            match host.parse::<std::net::IpAddr>() {
+                Ok(_) => assert_eq!(name.ipaddress(), Some(&[203, 0, 113, 7][..])),
+                Err(_) => assert_eq!(name.dnsname(), Some(host)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_acceptor_cached_per_host() {
+        let authority = test_authority();
+        authority.acceptor_for("example.com").unwrap();
+        authority.acceptor_for("example.com").unwrap();
+        authority.acceptor_for("example.org").unwrap();
+        assert_eq!(authority.acceptors.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_inner_origin_form_target_becomes_https_url() {
+        let mut reader = std::io::Cursor::new(b"GET /a?b=c HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        let head = read_inner_head(&mut reader, "example.com:8443", 4096, 4096, Instant::now())
+            .await
+            .unwrap();
+        assert_eq!(head.target, "https://example.com:8443/a?b=c");
+
+        let mut reader =
+            std::io::Cursor::new(b"GET http://evil.test/ HTTP/1.1\r\nHost: evil.test\r\n\r\n");
+        assert!(
+            read_inner_head(&mut reader, "example.com:443", 4096, 4096, Instant::now())
+                .await
+                .is_err()
+        );
+    }
+}
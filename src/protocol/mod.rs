@@ -1,11 +1,53 @@
+pub mod body;
+pub mod decompress;
+mod early_hints;
+pub(crate) mod happy_eyeballs;
 pub mod http;
 pub mod https;
+pub mod mitm;
+pub mod sni;
+pub mod socks;
 
-use ::http::Method;
+use ::http::{Method, Version};
 use anyhow::Result;
 use std::fmt;
+use std::net::SocketAddr;
+use std::time::Instant;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
 
+use crate::config::ProxyConfig;
+
+/// What a handler sent back to the client, for access logging.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Outcome {
+    pub status: u16,
+    /// Response body bytes, or bytes relayed to the client for a tunnel.
+    pub bytes_sent: u64,
+}
+
+impl Outcome {
+    pub fn status(status: u16) -> Self {
+        Self {
+            status,
+            bytes_sent: 0,
+        }
+    }
+}
+
+/// The request line and headers, read before dispatching to a handler.
+/// Header names are lowercase.
+#[derive(Debug, Clone)]
+pub struct RequestHead {
+    pub method: Method,
+    pub target: String,
+    /// `HTTP/1.0` or `HTTP/1.1`, from the request line.
+    pub version: Version,
+    pub headers: Vec<(String, String)>,
+    /// When the proxy accepted the request; the access log and
+    /// `--add-latency-header` measure from here.
+    pub received: Instant,
+}
+
 pub enum Protocol {
     Http,
     Https,
@@ -25,16 +67,28 @@ impl Protocol {
         &self,
         writer: &mut W,
         reader: &mut R,
-        method: Method,
-        target: String,
-    ) -> Result<()>
+        head: RequestHead,
+        config: &ProxyConfig,
+        request_id: &str,
+        peer_addr: Option<SocketAddr>,
+    ) -> Result<Outcome>
     where
         W: AsyncWriteExt + Unpin,
         R: AsyncBufReadExt + Unpin,
     {
         match self {
-            Protocol::Http => http::handle_request(writer, reader, method, target).await,
-            Protocol::Https => https::handle_request(writer, reader, target).await,
+            Protocol::Http => {
+                http::handle_request(writer, reader, head, config, request_id, peer_addr).await
+            }
+            Protocol::Https => match &config.mitm {
+                Some(authority) => {
+                    mitm::handle_request(
+                        writer, reader, head, config, authority, request_id, peer_addr,
+                    )
+                    .await
+                }
+                None => https::handle_request(writer, reader, head.target, config, peer_addr).await,
+            },
         }
     }
 
@@ -0,0 +1,253 @@
+use std::time::Duration;
+use tokio::io::AsyncBufReadExt;
+
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_RECORD_HEADER_LEN: usize = 5;
+const TLS_MAX_RECORD_LEN: usize = 16384;
+const SNI_EXTENSION: u16 = 0x0000;
+const SNI_HOST_NAME: u8 = 0x00;
+
+/// Read the first TLS record from the client if it looks like a handshake.
+///
+/// Only the first byte is peeked before deciding, so tunnels carrying
+/// non-TLS traffic are left untouched and an empty vector is returned.
+/// The returned bytes have been consumed from `reader` and must be replayed
+/// to the upstream before tunneling the rest of the stream. Reading stops
+/// early, returning what was read so far, if the client goes quiet for
+/// `timeout`, closes, or fails; the tunnel copy takes it from there.
+pub async fn read_client_hello<R>(reader: &mut R, timeout: Duration) -> Vec<u8>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut record = Vec::new();
+    loop {
+        let wanted = match record.get(..TLS_RECORD_HEADER_LEN) {
+            None => TLS_RECORD_HEADER_LEN,
+            Some(header) => {
+                let record_len = u16::from_be_bytes([header[3], header[4]]) as usize;
+                if record_len > TLS_MAX_RECORD_LEN {
+                    // Not a valid TLS record; replay the header and let the tunnel carry on.
+                    return record;
+                }
+                TLS_RECORD_HEADER_LEN + record_len
+            }
+        };
+        if record.len() >= wanted {
+            return record;
+        }
+
+        // `fill_buf` consumes nothing, so giving up on it mid-wait loses no bytes.
+        let available = match tokio::time::timeout_at(deadline, reader.fill_buf()).await {
+            Ok(Ok(available)) if !available.is_empty() => available,
+            _ => return record,
+        };
+        if record.is_empty() && available[0] != TLS_HANDSHAKE_RECORD {
+            return record;
+        }
+        let n = available.len().min(wanted - record.len());
+        record.extend_from_slice(&available[..n]);
+        reader.consume(n);
+    }
+}
+
+/// Extract the `server_name` extension from a TLS record containing a
+/// ClientHello. Returns `None` for anything that doesn't parse.
+pub fn parse_sni(record: &[u8]) -> Option<String> {
+    let mut cursor = Cursor::new(record);
+
+    if cursor.u8()? != TLS_HANDSHAKE_RECORD {
+        return None;
+    }
+    cursor.skip(2)?; // record version
+    let record_len = cursor.u16()? as usize;
+    let mut hello = Cursor::new(cursor.take(record_len)?);
+
+    if hello.u8()? != TLS_CLIENT_HELLO {
+        return None;
+    }
+    let hello_len = hello.u24()?;
+    let mut hello = Cursor::new(hello.take(hello_len)?);
+
+    hello.skip(2 + 32)?; // client_version + random
+    let session_id_len = hello.u8()? as usize;
+    hello.skip(session_id_len)?;
+    let cipher_suites_len = hello.u16()? as usize;
+    hello.skip(cipher_suites_len)?;
+    let compression_len = hello.u8()? as usize;
+    hello.skip(compression_len)?;
+
+    let extensions_len = hello.u16()? as usize;
+    let mut extensions = Cursor::new(hello.take(extensions_len)?);
+
+    while !extensions.is_empty() {
+        let ext_type = extensions.u16()?;
+        let ext_len = extensions.u16()? as usize;
+        let ext_data = extensions.take(ext_len)?;
+        if ext_type != SNI_EXTENSION {
+            continue;
+        }
+
+        let mut ext = Cursor::new(ext_data);
+        let list_len = ext.u16()? as usize;
+        let mut names = Cursor::new(ext.take(list_len)?);
+        while !names.is_empty() {
+            let name_type = names.u8()?;
+            let name_len = names.u16()? as usize;
+            let name = names.take(name_len)?;
+            if name_type == SNI_HOST_NAME {
+                return std::str::from_utf8(name).ok().map(str::to_string);
+            }
+        }
+        return None;
+    }
+
+    None
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Some(head)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| ((b[0] as usize) << 16) | ((b[1] as usize) << 8) | b[2] as usize)
+    }
+}
+
+/// Build a minimal but well-formed ClientHello record carrying `sni`.
+/// Shared with the integration tests so they exercise the same bytes.
+#[cfg(any(test, feature = "_test-support"))]
+pub fn build_client_hello(sni: &str) -> Vec<u8> {
+    let name = sni.as_bytes();
+
+    let mut server_name_list = vec![SNI_HOST_NAME];
+    server_name_list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    server_name_list.extend_from_slice(name);
+
+    let mut sni_ext = Vec::new();
+    sni_ext.extend_from_slice(&(server_name_list.len() as u16).to_be_bytes());
+    sni_ext.extend_from_slice(&server_name_list);
+
+    let mut extensions = Vec::new();
+    extensions.extend_from_slice(&SNI_EXTENSION.to_be_bytes());
+    extensions.extend_from_slice(&(sni_ext.len() as u16).to_be_bytes());
+    extensions.extend_from_slice(&sni_ext);
+
+    let mut body = vec![0x03, 0x03]; // TLS 1.2 client_version
+    body.extend_from_slice(&[0u8; 32]); // random
+    body.push(0); // session_id
+    body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); // one cipher suite
+    body.extend_from_slice(&[0x01, 0x00]); // null compression
+    body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    body.extend_from_slice(&extensions);
+
+    let mut handshake = vec![TLS_CLIENT_HELLO];
+    handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    handshake.extend_from_slice(&body);
+
+    let mut record = vec![TLS_HANDSHAKE_RECORD, 0x03, 0x01];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend_from_slice(&handshake);
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn test_parse_sni_from_client_hello() {
+        let record = build_client_hello("example.com");
+        assert_eq!(parse_sni(&record).as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn test_parse_sni_rejects_non_tls() {
+        assert_eq!(parse_sni(b"GET / HTTP/1.1\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn test_parse_sni_truncated_record() {
+        let record = build_client_hello("example.com");
+        assert_eq!(parse_sni(&record[..record.len() - 4]), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_client_hello_consumes_only_first_record() {
+        let mut data = build_client_hello("example.com");
+        let record_len = data.len();
+        data.extend_from_slice(b"application data");
+        let mut reader = BufReader::new(std::io::Cursor::new(data));
+
+        let record = read_client_hello(&mut reader, TIMEOUT).await;
+        assert_eq!(record.len(), record_len);
+        assert_eq!(parse_sni(&record).as_deref(), Some("example.com"));
+
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"application data");
+    }
+
+    #[tokio::test]
+    async fn test_read_client_hello_leaves_non_tls_untouched() {
+        let mut reader = BufReader::new(std::io::Cursor::new(b"SSH-2.0-OpenSSH\r\n".to_vec()));
+
+        let record = read_client_hello(&mut reader, TIMEOUT).await;
+        assert!(record.is_empty());
+
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"SSH-2.0-OpenSSH\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_read_client_hello_gives_up_on_stalled_record() {
+        let record = build_client_hello("example.com");
+        let (mut client, proxy_side) = tokio::io::duplex(1024);
+        client.write_all(&record[..10]).await.unwrap();
+        let mut reader = BufReader::new(proxy_side);
+
+        let partial = read_client_hello(&mut reader, Duration::from_millis(50)).await;
+        assert_eq!(
+            partial,
+            &record[..10],
+            "Bytes read so far must be kept for replay"
+        );
+        assert_eq!(parse_sni(&partial), None);
+    }
+}
@@ -0,0 +1,324 @@
+use anyhow::Result;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tracing::debug;
+
+use crate::config::ProxyConfig;
+use crate::error::ProxyError;
+use crate::metrics::{self, BlockReason};
+use crate::protocol::https::{self, TunnelRefusal};
+use crate::protocol::Outcome;
+
+const SOCKS_VERSION: u8 = 0x05;
+const AUTH_VERSION: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_NOT_ALLOWED: u8 = 0x02;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// The `host:port` a SOCKS5 client asked to connect to, and how the
+/// request ended.
+#[derive(Debug)]
+pub struct SocksOutcome {
+    pub target: String,
+    pub outcome: Outcome,
+}
+
+/// Serve one SOCKS5 client (RFC 1928): negotiate no-auth, or
+/// username/password (RFC 1929) when `config.proxy_auth` is set, then open
+/// the CONNECT target with the same checks as HTTP CONNECT and tunnel.
+/// Returns `None` if the client was turned away before naming a target.
+pub async fn handle_connection<W, R>(
+    writer: &mut W,
+    reader: &mut R,
+    config: &ProxyConfig,
+) -> Result<Option<SocksOutcome>>
+where
+    W: AsyncWriteExt + Unpin,
+    R: AsyncBufReadExt + Unpin,
+{
+    if !negotiate_auth(writer, reader, config).await? {
+        return Ok(None);
+    }
+
+    let mut request = [0u8; 4];
+    reader.read_exact(&mut request).await?;
+    let [version, command, _, address_type] = request;
+    if version != SOCKS_VERSION {
+        return Err(
+            ProxyError::MalformedRequest(format!("Unsupported SOCKS version {}", version)).into(),
+        );
+    }
+
+    let host = match address_type {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            reader.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            reader.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).to_string()
+        }
+        ATYP_DOMAIN => {
+            let len = reader.read_u8().await? as usize;
+            let mut name = vec![0u8; len];
+            reader.read_exact(&mut name).await?;
+            String::from_utf8(name).map_err(|_| {
+                ProxyError::MalformedRequest("SOCKS domain name is not UTF-8".to_string())
+            })?
+        }
+        _ => {
+            write_reply(writer, REPLY_ADDRESS_NOT_SUPPORTED, None).await?;
+            return Ok(None);
+        }
+    };
+    let port = reader.read_u16().await?;
+    let target = match address_type {
+        ATYP_IPV6 => format!("[{}]:{}", host, port),
+        _ => format!("{}:{}", host, port),
+    };
+
+    if command != CMD_CONNECT {
+        debug!("SOCKS5 command {} to {} not supported", command, target);
+        write_reply(writer, REPLY_COMMAND_NOT_SUPPORTED, None).await?;
+        return Ok(Some(SocksOutcome {
+            target,
+            outcome: Outcome::status(501),
+        }));
+    }
+
+    if let Some(hours) = config.allowed_hours.filter(|h| !h.is_open_at(crate::now())) {
+        metrics::record_block(
+            BlockReason::OutsideAllowedHours,
+            format_args!("SOCKS5 tunnel to {}", target),
+            format_args!("outside allowed hours {}", hours),
+        );
+        crate::tarpit(config).await;
+        write_reply(writer, REPLY_NOT_ALLOWED, None).await?;
+        return Ok(Some(SocksOutcome {
+            target,
+            outcome: Outcome::status(403),
+        }));
+    }
+
+    let target_stream = match https::open_target(&target, &host, port, config).await {
+        Ok(stream) => stream,
+        Err(refusal) => {
+            let reply = match refusal {
+                TunnelRefusal::PortNotAllowed
+                | TunnelRefusal::Blocked
+                | TunnelRefusal::RouteBlocked => REPLY_NOT_ALLOWED,
+                TunnelRefusal::Unreachable | TunnelRefusal::CircuitOpen => REPLY_HOST_UNREACHABLE,
+            };
+            write_reply(writer, reply, None).await?;
+            return Ok(Some(SocksOutcome {
+                target,
+                outcome: Outcome::status(refusal.http_status()),
+            }));
+        }
+    };
+
+    write_reply(writer, REPLY_SUCCEEDED, target_stream.local_addr().ok()).await?;
+    debug!("SOCKS5 tunnel established to {}", target);
+
+    let (_, bytes_sent) = https::tunnel_data(
+        writer,
+        reader,
+        target_stream,
+        &target,
+        config.tunnel_idle_timeout,
+        config.io_buffer_size(),
+        false,
+    )
+    .await?;
+
+    Ok(Some(SocksOutcome {
+        target,
+        outcome: Outcome {
+            status: 200,
+            bytes_sent,
+        },
+    }))
+}
+
+/// Run method selection and, if chosen, the username/password exchange.
+/// Returns `false` once the client has been refused.
+async fn negotiate_auth<W, R>(writer: &mut W, reader: &mut R, config: &ProxyConfig) -> Result<bool>
+where
+    W: AsyncWriteExt + Unpin,
+    R: AsyncBufReadExt + Unpin,
+{
+    let mut greeting = [0u8; 2];
+    reader.read_exact(&mut greeting).await?;
+    if greeting[0] != SOCKS_VERSION {
+        return Err(ProxyError::MalformedRequest(format!(
+            "Unsupported SOCKS version {}",
+            greeting[0]
+        ))
+        .into());
+    }
+    let mut methods = vec![0u8; greeting[1] as usize];
+    reader.read_exact(&mut methods).await?;
+
+    let wanted = match config.proxy_auth {
+        Some(_) => METHOD_USER_PASS,
+        None => METHOD_NO_AUTH,
+    };
+    if !methods.contains(&wanted) {
+        writer
+            .write_all(&[SOCKS_VERSION, METHOD_NONE_ACCEPTABLE])
+            .await?;
+        writer.flush().await?;
+        return Ok(false);
+    }
+    writer.write_all(&[SOCKS_VERSION, wanted]).await?;
+    writer.flush().await?;
+
+    let Some(auth) = &config.proxy_auth else {
+        return Ok(true);
+    };
+
+    let version = reader.read_u8().await?;
+    if version != AUTH_VERSION {
+        return Err(ProxyError::MalformedRequest(format!(
+            "Unsupported SOCKS auth version {}",
+            version
+        ))
+        .into());
+    }
+    let user = read_short_string(reader).await?;
+    let pass = read_short_string(reader).await?;
+
+    let authorized = auth.verify(&user, &pass);
+    let status = if authorized { 0x00 } else { 0x01 };
+    writer.write_all(&[AUTH_VERSION, status]).await?;
+    writer.flush().await?;
+    if !authorized {
+        tracing::warn!("Rejected SOCKS5 client with bad credentials for {}", user);
+    }
+    Ok(authorized)
+}
+
+/// A length-prefixed string as used by the RFC 1929 auth request.
+async fn read_short_string<R>(reader: &mut R) -> Result<String>
+where
+    R: AsyncReadExt + Unpin,
+{
+    let len = reader.read_u8().await? as usize;
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes).await?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+async fn write_reply<W>(writer: &mut W, reply: u8, bound: Option<SocketAddr>) -> Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    let bound = bound.unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+    let mut message = vec![SOCKS_VERSION, reply, 0x00];
+    match bound.ip() {
+        IpAddr::V4(ip) => {
+            message.push(ATYP_IPV4);
+            message.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            message.push(ATYP_IPV6);
+            message.extend_from_slice(&ip.octets());
+        }
+    }
+    message.extend_from_slice(&bound.port().to_be_bytes());
+    writer.write_all(&message).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::ProxyAuth;
+    use std::io::Cursor;
+    use tokio::io::BufReader;
+
+    async fn run(input: &[u8], config: &ProxyConfig) -> (Option<SocksOutcome>, Vec<u8>) {
+        let mut reader = BufReader::new(Cursor::new(input.to_vec()));
+        let mut writer = Vec::new();
+        let outcome = handle_connection(&mut writer, &mut reader, config)
+            .await
+            .unwrap();
+        (outcome, writer)
+    }
+
+    #[tokio::test]
+    async fn test_refuses_client_without_acceptable_method() {
+        let mut auth = ProxyAuth::new();
+        auth.add("bob", "hunter2");
+        let config = ProxyConfig {
+            proxy_auth: Some(auth),
+            ..Default::default()
+        };
+
+        // Offers only no-auth while credentials are required.
+        let (outcome, written) = run(&[0x05, 0x01, 0x00], &config).await;
+        assert!(outcome.is_none());
+        assert_eq!(written, [0x05, METHOD_NONE_ACCEPTABLE]);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_bad_credentials() {
+        let mut auth = ProxyAuth::new();
+        auth.add("bob", "hunter2");
+        let config = ProxyConfig {
+            proxy_auth: Some(auth),
+            ..Default::default()
+        };
+
+        let mut input = vec![0x05, 0x01, 0x02, 0x01, 3];
+        input.extend_from_slice(b"bob");
+        input.push(5);
+        input.extend_from_slice(b"wrong");
+        let (outcome, written) = run(&input, &config).await;
+        assert!(outcome.is_none());
+        assert_eq!(written, [0x05, METHOD_USER_PASS, 0x01, 0x01]);
+    }
+
+    #[tokio::test]
+    async fn test_disallowed_port_gets_not_allowed_reply() {
+        // CONNECT example.com:25 — refused by the port allowlist before any DNS.
+        let mut input = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, ATYP_DOMAIN, 11];
+        input.extend_from_slice(b"example.com");
+        input.extend_from_slice(&25u16.to_be_bytes());
+        let (outcome, written) = run(&input, &ProxyConfig::default()).await;
+
+        let outcome = outcome.unwrap();
+        assert_eq!(outcome.target, "example.com:25");
+        assert_eq!(outcome.outcome.status, 403);
+        assert_eq!(written[..2], [0x05, METHOD_NO_AUTH]);
+        assert_eq!(written[2..4], [0x05, REPLY_NOT_ALLOWED]);
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_command() {
+        // BIND 1.2.3.4:443
+        let input = [
+            0x05, 0x01, 0x00, 0x05, 0x02, 0x00, ATYP_IPV4, 1, 2, 3, 4, 0x01, 0xbb,
+        ];
+        let (outcome, written) = run(&input, &ProxyConfig::default()).await;
+
+        assert_eq!(outcome.unwrap().target, "1.2.3.4:443");
+        assert_eq!(written[2..4], [0x05, REPLY_COMMAND_NOT_SUPPORTED]);
+    }
+}
@@ -0,0 +1,101 @@
+//! `--ready-probe`: `/ready` answers `200` only while the proxy can open a
+//! TCP connection to the probe target, so orchestrators stop sending
+//! traffic to an instance that has lost its way out. Results are cached
+//! for `READY_PROBE_CACHE_TTL` so frequent checks don't hammer the target.
+
+use anyhow::Result;
+use std::net::IpAddr;
+use std::time::Instant;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::constants;
+use crate::protocol::happy_eyeballs;
+
+#[derive(Debug)]
+pub struct ReadinessProbe {
+    target: String,
+    /// When the last probe ran and whether it connected.
+    last: Mutex<Option<(Instant, bool)>>,
+}
+
+impl ReadinessProbe {
+    /// `target` must be `host:port`.
+    pub fn new(target: &str) -> Result<Self> {
+        let port = target.rsplit_once(':').map(|(_, port)| port.parse::<u16>());
+        if !matches!(port, Some(Ok(port)) if port != 0) {
+            anyhow::bail!("--ready-probe must be host:port, got {}", target);
+        }
+        Ok(Self {
+            target: target.to_string(),
+            last: Mutex::new(None),
+        })
+    }
+
+    /// Whether the target accepted a connection, from `source` if given.
+    /// Probes again once the cached result is stale; concurrent callers
+    /// share one probe.
+    pub async fn is_ready(&self, source: Option<IpAddr>) -> bool {
+        let mut last = self.last.lock().await;
+        if let Some((at, ready)) = *last {
+            if at.elapsed() < constants::READY_PROBE_CACHE_TTL {
+                return ready;
+            }
+        }
+        let ready = self.probe(source).await;
+        *last = Some((Instant::now(), ready));
+        ready
+    }
+
+    async fn probe(&self, source: Option<IpAddr>) -> bool {
+        let connect = async {
+            let addrs: Vec<_> = tokio::net::lookup_host(&self.target).await?.collect();
+            happy_eyeballs::connect(&addrs, source).await
+        };
+        match tokio::time::timeout(constants::READY_PROBE_TIMEOUT, connect).await {
+            Ok(Ok(_)) => true,
+            Ok(Err(e)) => {
+                warn!("Readiness probe to {} failed: {}", self.target, e);
+                false
+            }
+            Err(_) => {
+                warn!(
+                    "Readiness probe to {} timed out after {:?}",
+                    self.target,
+                    constants::READY_PROBE_TIMEOUT
+                );
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_target_needs_port() {
+        assert!(ReadinessProbe::new("example.com:443").is_ok());
+        assert!(ReadinessProbe::new("[2001:db8::1]:53").is_ok());
+        for bad in [
+            "example.com",
+            "example.com:",
+            "example.com:0",
+            "example.com:https",
+        ] {
+            assert!(ReadinessProbe::new(bad).is_err(), "{:?} should fail", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_result_is_cached() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let probe = ReadinessProbe::new(&listener.local_addr().unwrap().to_string()).unwrap();
+        assert!(probe.is_ready(None).await);
+
+        // The target going away isn't noticed until the cache expires.
+        drop(listener);
+        assert!(probe.is_ready(None).await);
+    }
+}
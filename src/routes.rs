@@ -0,0 +1,325 @@
+//! `--routes` files: a PAC-style table choosing, per target host, whether
+//! to connect directly, go through an upstream proxy, or refuse. One rule
+//! per line, first match wins:
+//!
+//! ```text
+//! # pattern            action
+//! intranet.example     DIRECT
+//! *.corp.example       PROXY http://corp-proxy:3128
+//! 10.0.0.0/8           DIRECT
+//! ads.example          BLOCK
+//! ```
+//!
+//! A pattern is a host name (case-insensitive), `*.domain` for any
+//! subdomain of `domain`, an IP address or CIDR block (matched against IP
+//! literal targets only; host names are not resolved for this), or `*` for
+//! everything.
+//!
+//! `--no-proxy-list` uses the same patterns, spelled the `NO_PROXY` way.
+
+use anyhow::{Context, Result};
+use reqwest::Url;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Where traffic for a host goes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Route {
+    Direct,
+    /// Through this upstream HTTP proxy, as `--upstream-proxy` would.
+    Proxy(Url),
+    /// Refused with `403` (or a SOCKS5 "not allowed" reply).
+    Block,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostPattern {
+    Any,
+    Exact(String),
+    /// `*.domain`, stored as `.domain`.
+    Subdomain(String),
+    /// A no-proxy `domain`: it and any subdomain.
+    Domain(String),
+    Cidr(IpAddr, u8),
+}
+
+impl HostPattern {
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Any => true,
+            HostPattern::Exact(name) => host.eq_ignore_ascii_case(name),
+            HostPattern::Subdomain(suffix) => is_subdomain(host, suffix),
+            HostPattern::Domain(name) => {
+                host.eq_ignore_ascii_case(name) || is_subdomain(host, &format!(".{}", name))
+            }
+            HostPattern::Cidr(network, prefix) => {
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                host.parse::<IpAddr>()
+                    .is_ok_and(|addr| in_network(addr.to_canonical(), *network, *prefix))
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for HostPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" {
+            return Ok(HostPattern::Any);
+        }
+        if let Some(domain) = s.strip_prefix("*.") {
+            if domain.is_empty() || domain.contains('*') {
+                return Err(format!("invalid wildcard pattern: {}", s));
+            }
+            return Ok(HostPattern::Subdomain(format!(".{}", domain)));
+        }
+        if s.contains('*') {
+            return Err(format!(
+                "wildcards are only allowed as a leading '*.': {}",
+                s
+            ));
+        }
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        match (addr.parse::<IpAddr>(), prefix) {
+            (Ok(addr), prefix) => {
+                let max = if addr.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    Some(prefix) => prefix
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|&p| p <= max)
+                        .ok_or_else(|| format!("invalid prefix length: {}", s))?,
+                    None => max,
+                };
+                Ok(HostPattern::Cidr(addr, prefix))
+            }
+            (Err(_), Some(_)) => Err(format!("invalid CIDR block: {}", s)),
+            (Err(_), None) => Ok(HostPattern::Exact(s.to_ascii_lowercase())),
+        }
+    }
+}
+
+/// Whether `host` ends with `suffix` (`.domain`) and has a label before it.
+fn is_subdomain(host: &str, suffix: &str) -> bool {
+    host.len() > suffix.len()
+        && host.is_char_boundary(host.len() - suffix.len())
+        && host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+}
+
+/// One `--no-proxy-list` entry, with `NO_PROXY` semantics: `example.com`
+/// covers it and its subdomains, `.example.com` only the subdomains, and
+/// IP addresses, CIDR blocks, `*.example.com`, and `*` mean what they do in
+/// a routes file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoProxyPattern(HostPattern);
+
+impl NoProxyPattern {
+    pub fn matches(&self, host: &str) -> bool {
+        self.0.matches(host)
+    }
+}
+
+impl std::str::FromStr for NoProxyPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("empty no-proxy pattern".to_string());
+        }
+        let pattern = match s.strip_prefix('.') {
+            Some(domain) => format!("*.{}", domain).parse()?,
+            None => match s.parse()? {
+                HostPattern::Exact(name) => HostPattern::Domain(name),
+                pattern => pattern,
+            },
+        };
+        Ok(Self(pattern))
+    }
+}
+
+fn in_network(addr: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (addr, network) {
+        (IpAddr::V4(addr), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(addr) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(addr), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(addr) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RouteRule {
+    pattern: HostPattern,
+    route: Route,
+}
+
+/// The rules from a `--routes` file, in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RouteTable {
+    rules: Vec<RouteRule>,
+}
+
+impl RouteTable {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read routes file {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid routes file {}", path.display()))
+    }
+
+    /// Parse rules, one per line. Blank lines and lines starting with `#`
+    /// are ignored.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut rules = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule =
+                parse_rule(line).map_err(|e| anyhow::anyhow!("line {}: {}", index + 1, e))?;
+            rules.push(rule);
+        }
+        Ok(Self { rules })
+    }
+
+    /// The route of the first rule matching `host`, if any.
+    pub fn route_for(&self, host: &str) -> Option<&Route> {
+        self.rules
+            .iter()
+            .find(|rule| rule.pattern.matches(host))
+            .map(|rule| &rule.route)
+    }
+}
+
+fn parse_rule(line: &str) -> Result<RouteRule, String> {
+    let mut fields = line.split_whitespace();
+    let pattern = fields.next().unwrap_or_default().parse()?;
+    let action = fields
+        .next()
+        .ok_or_else(|| format!("missing action after {}", line))?;
+    let route = match action.to_ascii_uppercase().as_str() {
+        "DIRECT" => Route::Direct,
+        "BLOCK" => Route::Block,
+        "PROXY" => {
+            let url = fields
+                .next()
+                .ok_or_else(|| "PROXY needs an http://host:port URL".to_string())?;
+            let url = Url::parse(url)
+                .ok()
+                .filter(|url| url.scheme() == "http" && url.host_str().is_some())
+                .ok_or_else(|| format!("PROXY needs an http://host:port URL, got {}", url))?;
+            Route::Proxy(url)
+        }
+        other => return Err(format!("unknown action {}", other)),
+    };
+    if let Some(extra) = fields.next() {
+        return Err(format!("unexpected {} after {}", extra, action));
+    }
+    Ok(RouteRule { pattern, route })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: &str = "
+# Internal hosts
+intranet.example     DIRECT
+*.corp.example       PROXY http://corp-proxy:3128
+10.0.0.0/8           direct
+fd00::/8             BLOCK
+ads.example          BLOCK
+";
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let table = RouteTable::parse(&format!("{}*  BLOCK\n", TABLE)).unwrap();
+        assert_eq!(table.route_for("INTRANET.example"), Some(&Route::Direct));
+        assert_eq!(
+            table.route_for("git.corp.example"),
+            Some(&Route::Proxy("http://corp-proxy:3128".parse().unwrap()))
+        );
+        assert_eq!(table.route_for("10.1.2.3"), Some(&Route::Direct));
+        assert_eq!(table.route_for("[fd00::1]"), Some(&Route::Block));
+        assert_eq!(table.route_for("example.com"), Some(&Route::Block));
+    }
+
+    #[test]
+    fn test_unmatched_hosts_have_no_route() {
+        let table = RouteTable::parse(TABLE).unwrap();
+        // `*.corp.example` covers subdomains only.
+        assert_eq!(table.route_for("corp.example"), None);
+        assert_eq!(table.route_for("evilcorp.example"), None);
+        assert_eq!(table.route_for("11.0.0.1"), None);
+        assert_eq!(table.route_for("example.com"), None);
+    }
+
+    #[test]
+    fn test_cidr_matches_ip_literals_only() {
+        let table = RouteTable::parse("192.168.0.0/16 BLOCK\n127.0.0.1 BLOCK").unwrap();
+        assert_eq!(table.route_for("192.168.44.1"), Some(&Route::Block));
+        assert_eq!(table.route_for("127.0.0.1"), Some(&Route::Block));
+        assert_eq!(table.route_for("::ffff:127.0.0.1"), Some(&Route::Block));
+        assert_eq!(table.route_for("127.0.0.2"), None);
+        assert_eq!(table.route_for("localhost"), None);
+    }
+
+    #[test]
+    fn test_no_proxy_patterns() {
+        let list: Vec<NoProxyPattern> = ["internal.example", ".corp.example", "10.0.0.0/8"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let bypassed = |host: &str| list.iter().any(|p| p.matches(host));
+
+        assert!(bypassed("INTERNAL.example"));
+        assert!(bypassed("db.internal.example"));
+        assert!(!bypassed("notinternal.example"));
+        assert!(bypassed("git.corp.example"));
+        assert!(
+            !bypassed("corp.example"),
+            "A leading dot means subdomains only"
+        );
+        assert!(bypassed("10.9.8.7"));
+        assert!(!bypassed("11.0.0.1"));
+
+        for bad in ["", ".", "10.0.0.0/33", "a*.example"] {
+            assert!(
+                bad.parse::<NoProxyPattern>().is_err(),
+                "{:?} should fail",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_rejects_bad_rules() {
+        for bad in [
+            "example.com",
+            "example.com ALLOW",
+            "example.com PROXY",
+            "example.com PROXY socks5://proxy:1080",
+            "example.com DIRECT now",
+            "10.0.0.0/33 BLOCK",
+            "example.com/8 BLOCK",
+            "a*.example.com BLOCK",
+        ] {
+            let err = RouteTable::parse(bad).unwrap_err();
+            assert!(
+                err.to_string().starts_with("line 1:"),
+                "Expected {:?} to be rejected with its line number, got {}",
+                bad,
+                err
+            );
+        }
+    }
+}
@@ -0,0 +1,753 @@
+//! The accept loop behind every listener: connection limits, backoff when
+//! `accept()` runs out of resources, the per-connection timeout, and a
+//! graceful drain on shutdown. The binary runs it over its TCP, TLS, Unix,
+//! and SOCKS5 listeners through `Listen`; `Server` runs it over one plain
+//! TCP listener for embedding the proxy in-process.
+
+use anyhow::Result;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, BufReader, BufWriter};
+use tokio::net::TcpListener;
+use tokio::sync::{watch, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, warn};
+
+use crate::config::ProxyConfig;
+use crate::constants;
+
+/// A source of client connections for `run`.
+pub trait Listen: Send + Sync + 'static {
+    type Stream: Send + 'static;
+
+    /// Accept the next client. Peers without a socket address (Unix
+    /// sockets) pass `None` and a label to log in place of `ip:port`.
+    fn accept(
+        &self,
+    ) -> impl Future<Output = std::io::Result<(Self::Stream, Option<SocketAddr>, String)>> + Send;
+
+    /// Speak the proxy protocol to one accepted client.
+    fn serve(
+        stream: Self::Stream,
+        peer_addr: Option<SocketAddr>,
+        config: &ProxyConfig,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    fn describe(&self) -> Result<String>;
+
+    /// Release anything the listener holds once it stops accepting.
+    fn cleanup(&self) {}
+}
+
+impl Listen for TcpListener {
+    type Stream = tokio::net::TcpStream;
+
+    async fn accept(&self) -> std::io::Result<(Self::Stream, Option<SocketAddr>, String)> {
+        let (stream, peer_addr) = TcpListener::accept(self).await?;
+        Ok((stream, Some(peer_addr), peer_addr.to_string()))
+    }
+
+    async fn serve(
+        stream: Self::Stream,
+        peer_addr: Option<SocketAddr>,
+        config: &ProxyConfig,
+    ) -> Result<()> {
+        config.configure_socket(&stream);
+        let (reader, writer) = stream.into_split();
+        serve_split(reader, writer, peer_addr, config).await
+    }
+
+    fn describe(&self) -> Result<String> {
+        Ok(self.local_addr()?.to_string())
+    }
+}
+
+pub struct Server {
+    listener: TcpListener,
+    config: ProxyConfig,
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+/// Stops a running `Server` from accepting; see `Server::run`.
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<watch::Sender<bool>>);
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.0.send_replace(true);
+    }
+}
+
+impl Server {
+    /// Bind `addr`; port `0` picks a free port, which `local_addr` reports.
+    pub async fn bind(addr: SocketAddr, config: ProxyConfig) -> Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to bind {}: {}", addr, e))?;
+        Ok(Self {
+            listener,
+            config,
+            shutdown: Arc::new(watch::channel(false).0),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.shutdown.clone())
+    }
+
+    /// Serve connections until `ShutdownHandle::shutdown` is called, then
+    /// stop accepting and wait up to `SHUTDOWN_GRACE` for the connections
+    /// in flight to finish.
+    pub async fn run(self) -> Result<()> {
+        let mut stop = self.shutdown.subscribe();
+        let shutdown = async move {
+            let _ = stop.wait_for(|stopped| *stopped).await;
+            Ok(())
+        };
+        let state = Arc::new(ServerState::new(
+            self.config,
+            None,
+            constants::MAX_CONCURRENT_CONNECTIONS,
+            ConnectionLimit::Reject,
+        ));
+        run(
+            vec![self.listener],
+            state,
+            shutdown,
+            constants::SHUTDOWN_GRACE,
+        )
+        .await
+    }
+}
+
+/// Bind `addr` and run a `Server` on it in the background, returning the
+/// bound address and a handle to stop it.
+pub async fn serve(addr: SocketAddr, config: ProxyConfig) -> Result<(SocketAddr, ShutdownHandle)> {
+    let server = Server::bind(addr, config).await?;
+    let local_addr = server.local_addr()?;
+    let handle = server.shutdown_handle();
+    tokio::spawn(async move {
+        if let Err(e) = server.run().await {
+            error!("Server on {} failed: {}", local_addr, e);
+        }
+    });
+    Ok((local_addr, handle))
+}
+
+/// How the accept loop treats a connection that arrives with every slot
+/// taken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionLimit {
+    /// Close it immediately.
+    Reject,
+    /// Hold it open until a slot frees up, closing it after the timeout.
+    /// The accept loop waits with it, leaving later clients in the backlog.
+    Queue(Duration),
+}
+
+/// State shared by every accept loop: the configuration, the connection
+/// slots, and the lifetime request count.
+pub struct ServerState {
+    config: Arc<ProxyConfig>,
+    semaphore: Arc<Semaphore>,
+    max_connections: usize,
+    connection_limit: ConnectionLimit,
+    requests_served: AtomicU64,
+    max_lifetime_requests: Option<u64>,
+    lifetime_exhausted: Notify,
+}
+
+impl ServerState {
+    pub fn new(
+        config: ProxyConfig,
+        max_lifetime_requests: Option<u64>,
+        max_connections: usize,
+        connection_limit: ConnectionLimit,
+    ) -> Self {
+        Self {
+            config: Arc::new(config),
+            semaphore: Arc::new(Semaphore::new(max_connections)),
+            max_connections,
+            connection_limit,
+            requests_served: AtomicU64::new(0),
+            max_lifetime_requests,
+            lifetime_exhausted: Notify::new(),
+        }
+    }
+
+    /// Count a new request against the lifetime limit. Returns `false` once
+    /// the limit is used up; the request that reaches it wakes `run`.
+    fn admit_request(&self) -> bool {
+        let max = self.max_lifetime_requests;
+        let admitted = self.requests_served.fetch_update(
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+            |served| match max {
+                Some(max) if served >= max => None,
+                _ => Some(served + 1),
+            },
+        );
+
+        match admitted {
+            Ok(previous) => {
+                if Some(previous + 1) == max {
+                    self.lifetime_exhausted.notify_one();
+                }
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Take a connection slot according to `connection_limit`. Returns
+    /// `None` if none is free (`Reject`) or none freed up in time (`Queue`).
+    async fn acquire_permit(&self) -> Option<OwnedSemaphorePermit> {
+        match self.connection_limit {
+            ConnectionLimit::Reject => self.semaphore.clone().try_acquire_owned().ok(),
+            ConnectionLimit::Queue(timeout) => {
+                tokio::time::timeout(timeout, self.semaphore.clone().acquire_owned())
+                    .await
+                    .ok()?
+                    .ok()
+            }
+        }
+    }
+
+    fn lifetime_reached(&self) -> bool {
+        self.max_lifetime_requests
+            .is_some_and(|max| self.requests_served.load(Ordering::SeqCst) >= max)
+    }
+
+    fn in_flight(&self) -> usize {
+        self.max_connections - self.semaphore.available_permits()
+    }
+}
+
+/// Accept on every listener until `shutdown` resolves or the lifetime
+/// request limit is reached, then stop accepting and give the connections
+/// in flight `shutdown_grace` to finish before aborting them.
+pub async fn run<L: Listen>(
+    listeners: Vec<L>,
+    state: Arc<ServerState>,
+    shutdown: impl Future<Output = Result<()>>,
+    shutdown_grace: Duration,
+) -> Result<()> {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut workers = JoinSet::new();
+
+    for listener in listeners {
+        info!("Server listening on {}", listener.describe()?);
+        workers.spawn(accept_loop(listener, state.clone(), stop_rx.clone()));
+    }
+
+    tokio::select! {
+        result = shutdown => {
+            result?;
+            info!("Shutdown signal received");
+        }
+        _ = state.lifetime_exhausted.notified() => {
+            info!("Lifetime request limit reached, shutting down");
+        }
+    }
+    let in_flight = state.in_flight();
+    info!(
+        "Draining {} in-flight connections (grace {:?})",
+        in_flight, shutdown_grace
+    );
+    let _ = stop_tx.send(true);
+
+    let drain = async { while workers.join_next().await.is_some() {} };
+    if tokio::time::timeout(shutdown_grace, drain).await.is_err() {
+        let remaining = state.in_flight();
+        warn!(
+            "Grace period expired, aborting {} of {} connections",
+            remaining, in_flight
+        );
+        // Dropping each accept loop drops its JoinSet, aborting its connections.
+        workers.shutdown().await;
+    } else {
+        info!("Drained {} connections", in_flight);
+    }
+    info!(
+        "Server stopped after serving {} requests",
+        state.requests_served.load(Ordering::SeqCst)
+    );
+
+    Ok(())
+}
+
+async fn accept_loop<L: Listen>(
+    listener: L,
+    state: Arc<ServerState>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut tasks = JoinSet::new();
+    let mut backoff = AcceptBackoff::default();
+
+    loop {
+        let (stream, peer_addr, peer) = tokio::select! {
+            result = accept_with_backoff(|| listener.accept(), &mut backoff) => match result {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!(
+                        "Listener {} failed, no longer accepting on it: {}",
+                        listener.describe().unwrap_or_default(),
+                        e
+                    );
+                    break;
+                }
+            },
+            _ = shutdown.changed() => {
+                debug!("Draining {} in-flight connections", tasks.len());
+                break;
+            }
+        };
+
+        // A queued connection holds up the loop while it waits, so a burst
+        // waits in the listen backlog instead of in tasks and descriptors.
+        let permit = tokio::select! {
+            permit = state.acquire_permit() => permit,
+            _ = shutdown.changed() => {
+                debug!("Draining {} in-flight connections", tasks.len());
+                break;
+            }
+        };
+        let Some(permit) = permit else {
+            match state.connection_limit {
+                ConnectionLimit::Reject => {
+                    warn!("[{peer}] Connection rejected: max connections reached")
+                }
+                ConnectionLimit::Queue(_) => {
+                    warn!("[{peer}] Connection rejected: no slot freed within accept queue timeout")
+                }
+            }
+            drop(stream);
+            continue;
+        };
+
+        if !state.admit_request() {
+            debug!("[{peer}] Connection rejected: lifetime request limit reached");
+            break;
+        }
+
+        debug!("[{peer}] Connection established");
+
+        let task_state = state.clone();
+        tasks.spawn(async move {
+            let _permit = permit;
+            let config = &task_state.config;
+            let connection = L::serve(stream, peer_addr, config);
+            // An idle timeout swaps the fixed cap for a far longer one, so
+            // clients making progress aren't cut off but a quiet tunnel
+            // without its own idle timeout isn't held forever either.
+            let timeout = match config.idle_timeout {
+                Some(_) => constants::IDLE_CONNECTION_MAX_LIFETIME,
+                None => Duration::from_secs(constants::CONNECTION_TIMEOUT_SECS),
+            };
+            let result = tokio::time::timeout(timeout, connection).await;
+            match result {
+                Ok(Err(e)) => log_connection_error(&peer, &e),
+                Err(_) => warn!("[{peer}] Connection timed out"),
+                Ok(Ok(())) => {}
+            }
+            debug!("[{peer}] Connection closed");
+        });
+
+        if state.lifetime_reached() {
+            break;
+        }
+    }
+
+    // Stop accepting before draining so new clients are refused, not queued.
+    listener.cleanup();
+    drop(listener);
+    while tasks.join_next().await.is_some() {}
+}
+
+/// How the accept loop treats a failed `accept()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AcceptFailure {
+    /// That one connection failed before it was accepted; take the next.
+    Connection,
+    /// The listener itself is unusable; stop accepting on it.
+    Fatal,
+    /// Anything else, typically running out of file descriptors, buffers,
+    /// or memory: retrying at once would only fail again, so back off.
+    Resources,
+}
+
+impl AcceptFailure {
+    fn of(e: &std::io::Error) -> Self {
+        use std::io::ErrorKind;
+        match e.kind() {
+            ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+            | ErrorKind::PermissionDenied
+            | ErrorKind::HostUnreachable
+            | ErrorKind::NetworkUnreachable
+            | ErrorKind::NetworkDown => AcceptFailure::Connection,
+            ErrorKind::InvalidInput => AcceptFailure::Fatal,
+            _ => AcceptFailure::Resources,
+        }
+    }
+}
+
+/// The pause before retrying after `AcceptFailure::Resources`, doubling up
+/// to `ACCEPT_BACKOFF_MAX` until an accept succeeds.
+#[derive(Debug, Default)]
+struct AcceptBackoff {
+    next: Option<Duration>,
+}
+
+impl AcceptBackoff {
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.next.unwrap_or(crate::constants::ACCEPT_BACKOFF_MIN);
+        self.next = Some((delay * 2).min(crate::constants::ACCEPT_BACKOFF_MAX));
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.next = None;
+    }
+}
+
+/// Call `accept` until it succeeds or fails fatally, backing off while the
+/// process is out of resources instead of spinning on the error.
+async fn accept_with_backoff<T, F, Fut>(
+    mut accept: F,
+    backoff: &mut AcceptBackoff,
+) -> std::io::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = std::io::Result<T>>,
+{
+    loop {
+        let e = match accept().await {
+            Ok(accepted) => {
+                backoff.reset();
+                return Ok(accepted);
+            }
+            Err(e) => e,
+        };
+        match AcceptFailure::of(&e) {
+            AcceptFailure::Connection => debug!("Connection failed before it was accepted: {}", e),
+            AcceptFailure::Fatal => return Err(e),
+            AcceptFailure::Resources => {
+                let delay = backoff.next_delay();
+                error!(
+                    "Failed to accept connection: {}; retrying in {:?}",
+                    e, delay
+                );
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+fn log_connection_error(peer: &str, err: &anyhow::Error) {
+    error!(
+        code = crate::error::error_code(err),
+        "[{peer}] Error handling request: {}", err
+    );
+}
+
+/// Serve one client connection, already split into its two halves.
+pub async fn serve_split<R, W>(
+    reader: R,
+    writer: W,
+    peer_addr: Option<SocketAddr>,
+    config: &ProxyConfig,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = BufReader::with_capacity(config.io_buffer_size(), reader);
+    let mut writer = BufWriter::with_capacity(config.io_buffer_size(), writer);
+
+    crate::handle_connection(&mut writer, &mut reader, peer_addr, config).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_admit_request_stops_at_lifetime_limit() {
+        let state = ServerState::new(
+            ProxyConfig::default(),
+            Some(2),
+            constants::MAX_CONCURRENT_CONNECTIONS,
+            ConnectionLimit::Reject,
+        );
+
+        assert!(state.admit_request());
+        assert!(!state.lifetime_reached());
+        assert!(state.admit_request());
+        assert!(state.lifetime_reached());
+        assert!(
+            !state.admit_request(),
+            "Requests past the limit are refused"
+        );
+        assert_eq!(state.requests_served.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_admit_request_without_limit() {
+        let state = ServerState::new(
+            ProxyConfig::default(),
+            None,
+            constants::MAX_CONCURRENT_CONNECTIONS,
+            ConnectionLimit::Reject,
+        );
+        for _ in 0..1000 {
+            assert!(state.admit_request());
+        }
+        assert!(!state.lifetime_reached());
+    }
+
+    /// A state whose single slot is already taken by the returned permit.
+    fn saturated_state(limit: ConnectionLimit) -> (ServerState, OwnedSemaphorePermit) {
+        let state = ServerState::new(ProxyConfig::default(), None, 1, limit);
+        let held = state.semaphore.clone().try_acquire_owned().unwrap();
+        (state, held)
+    }
+
+    #[tokio::test]
+    async fn test_reject_limit_refuses_when_saturated() {
+        let (state, _held) = saturated_state(ConnectionLimit::Reject);
+        assert!(state.acquire_permit().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_queue_limit_waits_for_free_slot() {
+        let (state, held) = saturated_state(ConnectionLimit::Queue(Duration::from_secs(5)));
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(held);
+        });
+        assert!(
+            state.acquire_permit().await.is_some(),
+            "Queued connection should get the slot once it is released"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_queue_limit_gives_up_after_timeout() {
+        let (state, _held) = saturated_state(ConnectionLimit::Queue(Duration::from_millis(50)));
+        let started = std::time::Instant::now();
+        assert!(state.acquire_permit().await.is_none());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+
+    /// Accepts TCP clients, counting them, and serves each until shutdown
+    /// without reading from it, so every served client keeps its slot.
+    struct HoldingListener(TcpListener, Arc<AtomicUsize>);
+
+    impl Listen for HoldingListener {
+        type Stream = tokio::net::TcpStream;
+
+        async fn accept(&self) -> std::io::Result<(Self::Stream, Option<SocketAddr>, String)> {
+            let (stream, peer_addr) = self.0.accept().await?;
+            self.1.fetch_add(1, Ordering::SeqCst);
+            Ok((stream, Some(peer_addr), peer_addr.to_string()))
+        }
+
+        async fn serve(
+            _stream: Self::Stream,
+            _peer_addr: Option<SocketAddr>,
+            _config: &ProxyConfig,
+        ) -> Result<()> {
+            std::future::pending().await
+        }
+
+        fn describe(&self) -> Result<String> {
+            Ok(self.0.local_addr()?.to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue_limit_stops_accepting_while_saturated() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let state = Arc::new(ServerState::new(
+            ProxyConfig::default(),
+            None,
+            1,
+            ConnectionLimit::Queue(Duration::from_secs(5)),
+        ));
+        let (_stop, stop_rx) = watch::channel(false);
+        tokio::spawn(accept_loop(
+            HoldingListener(listener, accepted.clone()),
+            state.clone(),
+            stop_rx,
+        ));
+
+        let mut clients = Vec::new();
+        for _ in 0..10 {
+            clients.push(tokio::net::TcpStream::connect(addr).await.unwrap());
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // One client is served and one waits for its slot; the rest stay in
+        // the backlog instead of each holding a task.
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+        assert_eq!(state.in_flight(), 1);
+        assert_eq!(
+            state.requests_served.load(Ordering::SeqCst),
+            1,
+            "Only connections that got a slot count against the lifetime limit"
+        );
+    }
+
+    #[test]
+    fn test_accept_failures_classified() {
+        use std::io::{Error, ErrorKind};
+        assert_eq!(
+            AcceptFailure::of(&Error::from(ErrorKind::ConnectionAborted)),
+            AcceptFailure::Connection
+        );
+        assert_eq!(
+            AcceptFailure::of(&Error::from(ErrorKind::InvalidInput)),
+            AcceptFailure::Fatal
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            // EMFILE: out of file descriptors.
+            AcceptFailure::of(&Error::from_raw_os_error(24)),
+            AcceptFailure::Resources
+        );
+    }
+
+    #[tokio::test]
+    async fn test_accept_backs_off_on_repeated_resource_errors() {
+        let attempts = AtomicUsize::new(0);
+        let flaky_accept = || {
+            let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if attempt < 4 {
+                    Err(std::io::Error::other("too many open files"))
+                } else {
+                    Ok(attempt)
+                }
+            }
+        };
+
+        let mut backoff = AcceptBackoff::default();
+        let started = std::time::Instant::now();
+        let accepted = accept_with_backoff(flaky_accept, &mut backoff)
+            .await
+            .unwrap();
+
+        assert_eq!(accepted, 4);
+        // 10ms + 20ms + 40ms + 80ms between the five attempts.
+        assert!(
+            started.elapsed() >= Duration::from_millis(150),
+            "Expected backoff between failed accepts, took {:?}",
+            started.elapsed()
+        );
+        // A success starts the next run of failures from the minimum again.
+        assert_eq!(backoff.next_delay(), constants::ACCEPT_BACKOFF_MIN);
+    }
+
+    #[tokio::test]
+    async fn test_accept_gives_up_on_fatal_error() {
+        let attempts = AtomicUsize::new(0);
+        let broken_accept = || {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            async { Err::<(), _>(std::io::Error::from(std::io::ErrorKind::InvalidInput)) }
+        };
+
+        let result = accept_with_backoff(broken_accept, &mut AcceptBackoff::default()).await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_accept_backoff_doubles_up_to_max() {
+        let mut backoff = AcceptBackoff::default();
+        let delays: Vec<Duration> = (0..10).map(|_| backoff.next_delay()).collect();
+        assert_eq!(delays[0], constants::ACCEPT_BACKOFF_MIN);
+        assert_eq!(delays[1], constants::ACCEPT_BACKOFF_MIN * 2);
+        assert_eq!(delays[9], constants::ACCEPT_BACKOFF_MAX);
+    }
+
+    /// Collects formatted log output so tests can assert on fields.
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl CapturedLogs {
+        fn contents(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    fn capture_connection_error(err: &anyhow::Error) -> String {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || log_connection_error("peer", err));
+        logs.contents()
+    }
+
+    async fn run_connection(request: &str) -> anyhow::Error {
+        let mut reader = BufReader::new(std::io::Cursor::new(request.to_string()));
+        let mut writer = Vec::new();
+        crate::handle_connection(&mut writer, &mut reader, None, &ProxyConfig::default())
+            .await
+            .expect_err("request should fail")
+    }
+
+    #[tokio::test]
+    async fn test_connection_error_logs_request_too_large_code() {
+        let err = run_connection(
+            "POST http://example.com/ HTTP/1.1\r\nContent-Length: 999999999999\r\n\r\n",
+        )
+        .await;
+        let logs = capture_connection_error(&err);
+        assert!(
+            logs.contains("code=\"REQUEST_TOO_LARGE\""),
+            "Expected REQUEST_TOO_LARGE code in: {}",
+            logs
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connection_error_logs_invalid_target_code() {
+        let err = run_connection("CONNECT example.com:notaport HTTP/1.1\r\n\r\n").await;
+        let logs = capture_connection_error(&err);
+        assert!(
+            logs.contains("code=\"INVALID_TARGET\""),
+            "Expected INVALID_TARGET code in: {}",
+            logs
+        );
+    }
+}
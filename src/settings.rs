@@ -0,0 +1,453 @@
+//! `--config` files: TOML whose keys are the long flag names, e.g.
+//!
+//! ```toml
+//! host = "0.0.0.0"
+//! port = 3128
+//! allowed-methods = ["GET", "CONNECT"]
+//! connect-allow-ports = "443,8443"
+//! ```
+//!
+//! Values from the file fill in anything not given on the command line, so
+//! flags always win.
+
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use rhoxy::config::{
+    parse_duration, AllowedHours, InterceptStatus, PathDeprecation, PortRanges, RemoveHeader,
+    SetHeader, UpstreamSni, UtcOffset,
+};
+use rhoxy::dns::{DnsServer, HostOverride};
+use rhoxy::routes::NoProxyPattern;
+use serde::{Deserialize, Deserializer};
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::{parse_method, CommandLineArguments, HostMismatch, LimitBehavior, LogFormat};
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct FileSettings {
+    host: Option<String>,
+    port: Option<u16>,
+    bind: Option<Vec<SocketAddr>>,
+    verbose: Option<bool>,
+    log_format: Option<LogFormat>,
+    log_sni: Option<bool>,
+    trace_bodies: Option<usize>,
+    admin: Option<bool>,
+    metrics: Option<bool>,
+    tenant_header: Option<String>,
+    tenant_trusted_peer: Option<Vec<std::net::IpAddr>>,
+    spill_to_disk_threshold: Option<usize>,
+    #[cfg(unix)]
+    unix_socket: Option<PathBuf>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    accept_workers: Option<u16>,
+    accept_backlog: Option<u32>,
+    #[serde(default, deserialize_with = "duration")]
+    shutdown_grace: Option<Duration>,
+    max_lifetime_requests: Option<u64>,
+    access_log: Option<PathBuf>,
+    max_header_bytes: Option<usize>,
+    max_request_line: Option<usize>,
+    #[serde(default, deserialize_with = "duration")]
+    header_read_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "duration")]
+    idle_timeout: Option<Duration>,
+    max_response_size: Option<u64>,
+    cache_size: Option<u64>,
+    #[serde(default, deserialize_with = "methods")]
+    allowed_methods: Option<Vec<http::Method>>,
+    #[serde(default, deserialize_with = "methods")]
+    deny_methods: Option<Vec<http::Method>>,
+    allow_trace: Option<bool>,
+    reject_http10: Option<bool>,
+    host_mismatch: Option<HostMismatch>,
+    allow_url_userinfo: Option<bool>,
+    auth_user: Option<String>,
+    auth_pass: Option<String>,
+    auth_file: Option<PathBuf>,
+    #[serde(rename = "block-response-content-type")]
+    block_response_content_types: Option<Vec<String>>,
+    block_response_status: Option<u16>,
+    decompress: Option<bool>,
+    compress_responses: Option<bool>,
+    compress_min_size: Option<usize>,
+    #[serde(rename = "deprecate-path", default, deserialize_with = "parsed_list")]
+    deprecations: Option<Vec<PathDeprecation>>,
+    security_headers: Option<bool>,
+    add_latency_header: Option<bool>,
+    #[serde(default, deserialize_with = "duration")]
+    upstream_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "duration")]
+    connect_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "parsed")]
+    allowed_hours: Option<AllowedHours>,
+    #[serde(default, deserialize_with = "parsed")]
+    allowed_hours_tz: Option<UtcOffset>,
+    follow_redirects: Option<usize>,
+    retries: Option<u32>,
+    upstream_http2: Option<bool>,
+    allow_private_addresses: Option<bool>,
+    force_upstream_https: Option<bool>,
+    force_https_except: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "parsed_list")]
+    upstream_sni: Option<Vec<UpstreamSni>>,
+    #[serde(default, deserialize_with = "parsed_list")]
+    set_header: Option<Vec<SetHeader>>,
+    #[serde(default, deserialize_with = "parsed_list")]
+    remove_header: Option<Vec<RemoveHeader>>,
+    #[serde(default, deserialize_with = "parsed_list")]
+    set_response_header: Option<Vec<SetHeader>>,
+    #[serde(default, deserialize_with = "parsed_list")]
+    remove_response_header: Option<Vec<RemoveHeader>>,
+    #[serde(default, deserialize_with = "parsed_list")]
+    intercept_status: Option<Vec<InterceptStatus>>,
+    early_hints: Option<bool>,
+    #[serde(default, deserialize_with = "duration")]
+    tunnel_idle_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "parsed")]
+    connect_allow_ports: Option<PortRanges>,
+    mitm: Option<bool>,
+    mitm_ca_cert: Option<PathBuf>,
+    mitm_ca_key: Option<PathBuf>,
+    #[serde(default, deserialize_with = "parsed")]
+    upstream_proxy: Option<reqwest::Url>,
+    #[serde(default, deserialize_with = "parsed_list")]
+    no_proxy_list: Option<Vec<NoProxyPattern>>,
+    #[serde(default, deserialize_with = "parsed")]
+    reverse_upstream: Option<reqwest::Url>,
+    strip_prefix: Option<String>,
+    egress_bind: Option<std::net::IpAddr>,
+    #[serde(default, deserialize_with = "parsed_list")]
+    hosts_override: Option<Vec<HostOverride>>,
+    #[serde(default, deserialize_with = "parsed")]
+    resolver: Option<DnsServer>,
+    circuit_breaker_threshold: Option<u32>,
+    #[serde(default, deserialize_with = "duration")]
+    circuit_breaker_window: Option<Duration>,
+    #[serde(default, deserialize_with = "duration")]
+    circuit_breaker_cooldown: Option<Duration>,
+    ready_probe: Option<String>,
+    routes: Option<PathBuf>,
+    socks_port: Option<u16>,
+    tarpit_ms: Option<u64>,
+    io_buffer_size: Option<usize>,
+    tcp_nodelay: Option<bool>,
+    #[serde(default, deserialize_with = "duration")]
+    tcp_keepalive: Option<Duration>,
+    max_connections: Option<usize>,
+    connection_limit_behavior: Option<LimitBehavior>,
+    #[serde(default, deserialize_with = "duration")]
+    accept_queue_timeout: Option<Duration>,
+}
+
+impl FileSettings {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// Copy every value set in the file into `args`, except for options
+    /// that `matches` shows were given on the command line.
+    pub fn apply(self, args: &mut CommandLineArguments, matches: &ArgMatches) -> Result<()> {
+        let from_cli = |id: &str| {
+            matches
+                .value_source(id)
+                .is_some_and(|source| source != ValueSource::DefaultValue)
+        };
+        macro_rules! merge {
+            ($field:ident) => {
+                if let Some(value) = self.$field {
+                    if !from_cli(stringify!($field)) {
+                        args.$field = value;
+                    }
+                }
+            };
+            ($field:ident?) => {
+                if let Some(value) = self.$field {
+                    if !from_cli(stringify!($field)) {
+                        args.$field = Some(value);
+                    }
+                }
+            };
+        }
+
+        merge!(host);
+        merge!(port);
+        merge!(bind);
+        merge!(verbose);
+        merge!(log_format);
+        merge!(log_sni);
+        merge!(trace_bodies?);
+        merge!(admin);
+        merge!(metrics);
+        merge!(tenant_header?);
+        merge!(tenant_trusted_peer);
+        merge!(spill_to_disk_threshold?);
+        #[cfg(unix)]
+        merge!(unix_socket?);
+        merge!(tls_cert?);
+        merge!(tls_key?);
+        merge!(accept_workers);
+        merge!(accept_backlog);
+        merge!(shutdown_grace);
+        merge!(max_lifetime_requests?);
+        merge!(access_log?);
+        merge!(max_header_bytes);
+        merge!(max_request_line);
+        merge!(header_read_timeout?);
+        merge!(idle_timeout?);
+        merge!(max_response_size?);
+        merge!(cache_size?);
+        merge!(allowed_methods);
+        merge!(deny_methods);
+        merge!(allow_trace);
+        merge!(reject_http10);
+        merge!(host_mismatch);
+        merge!(allow_url_userinfo);
+        merge!(auth_user?);
+        merge!(auth_pass?);
+        merge!(auth_file?);
+        merge!(block_response_content_types);
+        merge!(block_response_status);
+        merge!(decompress);
+        merge!(compress_responses);
+        merge!(compress_min_size);
+        merge!(deprecations);
+        merge!(security_headers);
+        merge!(add_latency_header);
+        merge!(upstream_timeout);
+        merge!(connect_timeout);
+        merge!(allowed_hours?);
+        merge!(allowed_hours_tz);
+        merge!(follow_redirects);
+        merge!(retries);
+        merge!(upstream_http2);
+        merge!(allow_private_addresses);
+        merge!(force_upstream_https);
+        merge!(force_https_except);
+        merge!(upstream_sni);
+        merge!(set_header);
+        merge!(remove_header);
+        merge!(set_response_header);
+        merge!(remove_response_header);
+        merge!(intercept_status);
+        merge!(early_hints);
+        merge!(tunnel_idle_timeout?);
+        merge!(connect_allow_ports);
+        merge!(mitm);
+        merge!(mitm_ca_cert?);
+        merge!(mitm_ca_key?);
+        merge!(upstream_proxy?);
+        merge!(no_proxy_list);
+        merge!(reverse_upstream?);
+        merge!(strip_prefix?);
+        merge!(egress_bind?);
+        merge!(hosts_override);
+        merge!(resolver?);
+        merge!(circuit_breaker_threshold?);
+        merge!(circuit_breaker_window);
+        merge!(circuit_breaker_cooldown);
+        merge!(ready_probe?);
+        merge!(routes?);
+        merge!(socks_port?);
+        merge!(tarpit_ms?);
+        merge!(io_buffer_size?);
+        merge!(tcp_nodelay);
+        merge!(tcp_keepalive?);
+        merge!(max_connections);
+        merge!(connection_limit_behavior);
+        merge!(accept_queue_timeout);
+
+        // The command line enforces these through clap; the file can't.
+        if args.accept_workers == 0 {
+            anyhow::bail!("accept-workers must be at least 1");
+        }
+        if args.accept_backlog == 0 {
+            anyhow::bail!("accept-backlog must be at least 1");
+        }
+        if !(400..600).contains(&args.block_response_status) {
+            anyhow::bail!("block-response-status must be between 400 and 599");
+        }
+        if args.strip_prefix.is_some() && args.reverse_upstream.is_none() {
+            anyhow::bail!("strip-prefix requires reverse-upstream");
+        }
+        if args.auth_user.is_some() != args.auth_pass.is_some() {
+            anyhow::bail!("auth-user and auth-pass must be set together");
+        }
+        Ok(())
+    }
+}
+
+/// A string value parsed with the same `FromStr` as the matching flag.
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| s.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+fn parsed_list<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Option::<Vec<String>>::deserialize(deserializer)?
+        .map(|list| {
+            list.iter()
+                .map(|s| s.parse().map_err(serde::de::Error::custom))
+                .collect()
+        })
+        .transpose()
+}
+
+/// A duration string as the flags take it (`"1.5s"`), or a bare integer of
+/// seconds.
+fn duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Secs(u64),
+        Text(String),
+    }
+
+    Ok(match Option::<Raw>::deserialize(deserializer)? {
+        Some(Raw::Secs(secs)) => Some(Duration::from_secs(secs)),
+        Some(Raw::Text(text)) => Some(parse_duration(&text).map_err(serde::de::Error::custom)?),
+        None => None,
+    })
+}
+
+fn methods<'de, D>(deserializer: D) -> Result<Option<Vec<http::Method>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<Vec<String>>::deserialize(deserializer)?
+        .map(|list| {
+            list.iter()
+                .map(|s| parse_method(s).map_err(serde::de::Error::custom))
+                .collect()
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+    use std::io::Write;
+
+    const SAMPLE: &str = r#"
+host = "0.0.0.0"
+port = 3128
+shutdown-grace = 10
+tunnel-idle-timeout = "2m"
+connect-timeout = "500ms"
+max-header-bytes = 16384
+allowed-methods = ["get", "CONNECT"]
+connect-allow-ports = "443,8000-8100"
+block-response-content-type = ["application/x-msdownload"]
+auth-user = "bob"
+auth-pass = "hunter2"
+connection-limit-behavior = "queue"
+"#;
+
+    fn sample_file() -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(SAMPLE.as_bytes()).unwrap();
+        file
+    }
+
+    fn parse(argv: &[&str]) -> Result<CommandLineArguments> {
+        let matches = CommandLineArguments::command().try_get_matches_from(argv)?;
+        CommandLineArguments::from_matches(&matches)
+    }
+
+    #[test]
+    fn test_file_values_fill_unset_options() {
+        let file = sample_file();
+        let args = parse(&["rhoxy", "--config", file.path().to_str().unwrap()]).unwrap();
+
+        assert_eq!(args.host, "0.0.0.0");
+        assert_eq!(args.port, 3128);
+        assert_eq!(args.shutdown_grace, Duration::from_secs(10));
+        assert_eq!(args.connection_limit_behavior, LimitBehavior::Queue);
+        // Untouched by the file, so still the flag default.
+        assert_eq!(args.accept_queue_timeout, Duration::from_secs(5));
+
+        let config = args.proxy_config().unwrap();
+        assert_eq!(config.tunnel_idle_timeout, Some(Duration::from_secs(120)));
+        assert_eq!(config.connect_timeout, Some(Duration::from_millis(500)));
+        assert_eq!(config.upstream_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(config.max_header_bytes, Some(16384));
+        assert_eq!(
+            config.allowed_methods,
+            Some(vec![http::Method::GET, http::Method::CONNECT])
+        );
+        assert!(config.connect_allowed_ports.contains(8050));
+        assert!(!config.connect_allowed_ports.contains(25));
+        assert_eq!(
+            config.blocked_content_types,
+            vec!["application/x-msdownload".to_string()]
+        );
+        assert!(config.proxy_auth.unwrap().verify("bob", "hunter2"));
+    }
+
+    #[test]
+    fn test_command_line_overrides_file() {
+        let file = sample_file();
+        let args = parse(&[
+            "rhoxy",
+            "--config",
+            file.path().to_str().unwrap(),
+            "--port",
+            "9000",
+            "--allowed-methods",
+            "POST",
+            "--connection-limit-behavior",
+            "reject",
+        ])
+        .unwrap();
+
+        assert_eq!(args.port, 9000);
+        assert_eq!(args.allowed_methods, vec![http::Method::POST]);
+        assert_eq!(args.connection_limit_behavior, LimitBehavior::Reject);
+        assert_eq!(
+            args.host, "0.0.0.0",
+            "Options not on the command line still come from the file"
+        );
+    }
+
+    #[test]
+    fn test_rejects_unknown_and_invalid_keys() {
+        for bad in [
+            "prot = 8080",
+            "connect-allow-ports = \"70000\"",
+            "accept-workers = 0",
+            "connect-timeout = \"soon\"",
+        ] {
+            let mut file = tempfile::NamedTempFile::new().unwrap();
+            file.write_all(bad.as_bytes()).unwrap();
+            assert!(
+                parse(&["rhoxy", "--config", file.path().to_str().unwrap()]).is_err(),
+                "Expected {:?} to be rejected",
+                bad
+            );
+        }
+    }
+}
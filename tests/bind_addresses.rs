@@ -0,0 +1,66 @@
+//! Integration tests for repeated `--bind`. These run the real binary because
+//! the listener setup lives in `main.rs`.
+//!
+//!     cargo test --test bind_addresses
+
+mod common;
+
+use std::net::SocketAddr;
+
+async fn assert_healthy(addr: SocketAddr) {
+    common::wait_for_listener(addr).await;
+    let response = common::send_raw(addr, b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    assert!(
+        response.contains("200 OK"),
+        "Expected 200 OK from {}, got: {}",
+        addr,
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_every_bind_address_serves_health() {
+    let first: SocketAddr = format!("127.0.0.1:{}", common::free_port())
+        .parse()
+        .unwrap();
+    let second: SocketAddr = format!("127.0.0.1:{}", common::free_port())
+        .parse()
+        .unwrap();
+    let _child =
+        common::spawn_rhoxy(&["--bind", &first.to_string(), "--bind", &second.to_string()]);
+
+    assert_healthy(first).await;
+    assert_healthy(second).await;
+}
+
+#[tokio::test]
+async fn test_ipv4_and_ipv6_share_a_port() {
+    if std::net::TcpListener::bind("[::1]:0").is_err() {
+        eprintln!("Skipping: IPv6 loopback is not available");
+        return;
+    }
+    // The wildcards overlap, so this only works if `[::]` stays v6-only.
+    let port = common::free_port();
+    let _child = common::spawn_rhoxy(&[
+        "--bind",
+        &format!("0.0.0.0:{}", port),
+        "--bind",
+        &format!("[::]:{}", port),
+    ]);
+
+    let v4: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+    let v6: SocketAddr = format!("[::1]:{}", port).parse().unwrap();
+    assert_healthy(v4).await;
+    assert_healthy(v6).await;
+}
+
+#[test]
+fn test_bind_conflicts_with_port() {
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_rhoxy"))
+        .args(["--bind", "127.0.0.1:0", "--port", "9000"])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+}
@@ -1,27 +1,47 @@
+use rhoxy::config::ProxyConfig;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Semaphore;
 
-/// Spawn a proxy using the same `handle_connection` as production.
-/// Accepts connections in a loop until the listener is dropped.
+/// Spawn a proxy with `rhoxy::serve`, the in-process server.
 #[allow(dead_code)]
 pub async fn start_proxy() -> std::net::SocketAddr {
+    start_proxy_with_config(ProxyConfig::default()).await
+}
+
+/// Like `start_proxy` but runs every connection with the given config.
+#[allow(dead_code)]
+pub async fn start_proxy_with_config(config: ProxyConfig) -> std::net::SocketAddr {
+    let (addr, _) = rhoxy::serve("127.0.0.1:0".parse().unwrap(), config)
+        .await
+        .unwrap();
+    addr
+}
+
+/// Like `start_proxy_with_config` but serves SOCKS5 via
+/// `handle_socks_connection`, as the `--socks-port` listener does.
+#[allow(dead_code)]
+pub async fn start_socks_proxy_with_config(config: ProxyConfig) -> std::net::SocketAddr {
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
     let addr = listener.local_addr().unwrap();
+    let config = Arc::new(config);
 
     tokio::spawn(async move {
         loop {
-            let Ok((stream, _)) = listener.accept().await else {
+            let Ok((stream, peer)) = listener.accept().await else {
                 break;
             };
+            let config = config.clone();
             tokio::spawn(async move {
                 let (reader, writer) = stream.into_split();
                 let mut reader = BufReader::new(reader);
                 let mut writer = BufWriter::new(writer);
 
-                let _ = rhoxy::handle_connection(&mut writer, &mut reader, None).await;
+                let _ =
+                    rhoxy::handle_socks_connection(&mut writer, &mut reader, Some(peer), &config)
+                        .await;
             });
         }
     });
@@ -48,7 +68,12 @@ pub async fn start_proxy_with_timeout(timeout: Duration) -> std::net::SocketAddr
 
                 let _ = tokio::time::timeout(
                     timeout,
-                    rhoxy::handle_connection(&mut writer, &mut reader, None),
+                    rhoxy::handle_connection(
+                        &mut writer,
+                        &mut reader,
+                        None,
+                        &ProxyConfig::default(),
+                    ),
                 )
                 .await;
             });
@@ -86,7 +111,13 @@ pub async fn start_proxy_with_limit(max_connections: usize) -> std::net::SocketA
                 let mut reader = BufReader::new(reader);
                 let mut writer = BufWriter::new(writer);
 
-                let _ = rhoxy::handle_connection(&mut writer, &mut reader, None).await;
+                let _ = rhoxy::handle_connection(
+                    &mut writer,
+                    &mut reader,
+                    None,
+                    &ProxyConfig::default(),
+                )
+                .await;
             });
         }
     });
@@ -138,6 +169,7 @@ pub async fn start_upstream(response: &'static [u8]) -> std::net::SocketAddr {
 
 /// Send raw bytes to a TCP address, shut down the write half, and read
 /// the full response.
+#[allow(dead_code)]
 pub async fn send_raw(addr: std::net::SocketAddr, request: &[u8]) -> String {
     let mut stream = TcpStream::connect(addr).await.unwrap();
     stream.write_all(request).await.unwrap();
@@ -180,3 +212,132 @@ where
     }
     body
 }
+
+/// Launch the real `rhoxy` binary with the given arguments. The child is
+/// killed when the handle is dropped so a failing test can't leak it.
+#[allow(dead_code)]
+pub fn spawn_rhoxy(args: &[&str]) -> tokio::process::Child {
+    tokio::process::Command::new(env!("CARGO_BIN_EXE_rhoxy"))
+        .args(args)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .expect("Failed to spawn rhoxy binary")
+}
+
+/// Send a signal to a child spawned by `spawn_rhoxy`.
+#[cfg(unix)]
+#[allow(dead_code)]
+pub fn signal(child: &tokio::process::Child, signal: &str) {
+    let pid = child.id().expect("Child already exited").to_string();
+    let status = std::process::Command::new("kill")
+        .args(["-s", signal, &pid])
+        .status()
+        .expect("Failed to run kill");
+    assert!(status.success(), "kill -s {} {} failed", signal, pid);
+}
+
+/// Reserve an ephemeral port for a spawned binary. The port is released
+/// before returning, so there is a small window where another process could
+/// take it; fine for tests.
+#[allow(dead_code)]
+pub fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Poll until something accepts TCP connections on `addr`.
+#[allow(dead_code)]
+pub async fn wait_for_listener(addr: std::net::SocketAddr) {
+    for _ in 0..100 {
+        if TcpStream::connect(addr).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("Nothing started listening on {}", addr);
+}
+
+/// A self-signed CA written out as PEM, as `--mitm-ca-cert` and
+/// `--mitm-ca-key` expect.
+#[allow(dead_code)]
+pub fn write_test_ca(dir: &std::path::Path) -> (std::path::PathBuf, std::path::PathBuf) {
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::extension::BasicConstraints;
+    use openssl::x509::{X509NameBuilder, X509};
+
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, "rhoxy test CA")
+        .unwrap();
+    let name = name.build();
+    let not_before = Asn1Time::days_from_now(0).unwrap();
+    let not_after = Asn1Time::days_from_now(1).unwrap();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(&not_before).unwrap();
+    builder.set_not_after(&not_after).unwrap();
+    builder
+        .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+        .unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+    let cert_path = dir.join("ca.pem");
+    let key_path = dir.join("ca.key");
+    std::fs::write(&cert_path, builder.build().to_pem().unwrap()).unwrap();
+    std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    (cert_path, key_path)
+}
+
+/// An https upstream whose certificate, issued by `authority`, is for
+/// `name`. It answers one request with `response`, reporting the decrypted
+/// request it received.
+#[allow(dead_code)]
+pub async fn start_tls_upstream(
+    authority: &rhoxy::protocol::mitm::MitmAuthority,
+    name: &str,
+    response: &'static [u8],
+) -> (std::net::SocketAddr, tokio::sync::oneshot::Receiver<String>) {
+    let (cert, key) = authority.issue_leaf(name).unwrap();
+    let identity = native_tls::Identity::from_pkcs8(
+        &cert.to_pem().unwrap(),
+        &key.private_key_to_pem_pkcs8().unwrap(),
+    )
+    .unwrap();
+    let acceptor =
+        tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(acceptor.accept(stream).await.unwrap());
+        let mut request = String::new();
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.unwrap() == 0 || line == "\r\n" {
+                break;
+            }
+            request.push_str(&line);
+        }
+        stream.get_mut().write_all(response).await.unwrap();
+        stream.get_mut().shutdown().await.unwrap();
+        let _ = tx.send(request);
+    });
+
+    (addr, rx)
+}
@@ -0,0 +1,108 @@
+//! Integration tests for `--check`. These run the real binary because the
+//! check happens in `main.rs`.
+//!
+//!     cargo test --test config_check
+
+mod common;
+
+use std::io::Write;
+use std::process::Output;
+
+fn run_check(args: &[&str]) -> Output {
+    std::process::Command::new(env!("CARGO_BIN_EXE_rhoxy"))
+        .arg("--check")
+        .args(args)
+        .output()
+        .expect("Failed to run rhoxy binary")
+}
+
+#[test]
+fn test_check_valid_config_exits_zero_and_releases_port() {
+    let mut routes = tempfile::NamedTempFile::new().unwrap();
+    writeln!(routes, "*.internal.example DIRECT").unwrap();
+    let mut config = tempfile::NamedTempFile::new().unwrap();
+    writeln!(config, "routes = {:?}", routes.path()).unwrap();
+    writeln!(config, "allowed-methods = [\"GET\", \"CONNECT\"]").unwrap();
+
+    let port = common::free_port().to_string();
+    let output = run_check(&["--port", &port, "--config", config.path().to_str().unwrap()]);
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(
+        stdout.contains(&format!("listen: 127.0.0.1:{}", port)),
+        "Got: {}",
+        stdout
+    );
+    assert!(stdout.contains("Configuration OK"), "Got: {}", stdout);
+    std::net::TcpListener::bind(format!("127.0.0.1:{}", port))
+        .expect("--check should release the port it bound");
+}
+
+#[test]
+fn test_check_fails_on_unreadable_tls_cert() {
+    let port = common::free_port().to_string();
+    let output = run_check(&[
+        "--port",
+        &port,
+        "--tls-cert",
+        "/nonexistent/cert.pem",
+        "--tls-key",
+        "/nonexistent/key.pem",
+    ]);
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("/nonexistent/cert.pem"), "Got: {}", stderr);
+}
+
+#[test]
+fn test_check_fails_on_bad_route_pattern() {
+    let mut routes = tempfile::NamedTempFile::new().unwrap();
+    writeln!(routes, "example.com SOMETIMES").unwrap();
+
+    let port = common::free_port().to_string();
+    let output = run_check(&["--port", &port, "--routes", routes.path().to_str().unwrap()]);
+
+    assert!(!output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("Configuration OK"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_check_leaves_unix_socket_paths_alone() {
+    let dir = tempfile::tempdir().unwrap();
+    let free = dir.path().join("free.sock");
+    let output = run_check(&["--unix-socket", free.to_str().unwrap()]);
+    assert!(
+        output.status.success(),
+        "stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(String::from_utf8_lossy(&output.stdout).contains("listen: unix:"));
+    assert!(!free.exists(), "--check should not create the socket");
+
+    // A running server's socket is reported, not taken over or removed.
+    let live = dir.path().join("live.sock");
+    let _server = std::os::unix::net::UnixListener::bind(&live).unwrap();
+    let output = run_check(&["--unix-socket", live.to_str().unwrap()]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("address in use"), "Got: {}", stderr);
+    assert!(std::os::unix::net::UnixStream::connect(&live).is_ok());
+}
+
+#[test]
+fn test_check_fails_when_port_is_taken() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = taken.local_addr().unwrap().port().to_string();
+    let output = run_check(&["--port", &port]);
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Failed to bind"), "Got: {}", stderr);
+}
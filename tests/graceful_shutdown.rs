@@ -0,0 +1,135 @@
+//! Integration tests for graceful shutdown, triggered by SIGTERM/SIGINT or by
+//! `--max-lifetime-requests`. These run the real binary since the shutdown
+//! path lives in `main.rs`.
+//!
+//!     cargo test --test graceful_shutdown
+#![cfg(unix)]
+
+mod common;
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn spawn_on_free_port(grace_secs: &str) -> (tokio::process::Child, SocketAddr) {
+    let port = common::free_port();
+    let child = common::spawn_rhoxy(&["--port", &port.to_string(), "--shutdown-grace", grace_secs]);
+    let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+    common::wait_for_listener(addr).await;
+    (child, addr)
+}
+
+#[tokio::test]
+async fn test_sigterm_lets_in_flight_connection_finish() {
+    let (mut child, addr) = spawn_on_free_port("10").await;
+
+    // Start a request but hold back the rest of the request line so the
+    // connection is still in flight when the signal arrives.
+    let mut slow = TcpStream::connect(addr).await.unwrap();
+    slow.write_all(b"GET /hea").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    common::signal(&child, "TERM");
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    assert!(
+        TcpStream::connect(addr).await.is_err(),
+        "New connections should be refused once shutdown starts"
+    );
+
+    slow.write_all(b"lth HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    slow.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), slow.read_to_end(&mut response))
+        .await
+        .expect("Timed out reading response")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response);
+    assert!(
+        response.contains("200 OK"),
+        "In-flight request should complete during the grace period, got: {}",
+        response
+    );
+
+    let status = tokio::time::timeout(Duration::from_secs(5), child.wait())
+        .await
+        .expect("rhoxy did not exit after draining")
+        .unwrap();
+    assert!(status.success(), "rhoxy exited with {}", status);
+}
+
+#[tokio::test]
+async fn test_shutdown_grace_expiry_aborts_stuck_connection() {
+    let (mut child, addr) = spawn_on_free_port("1").await;
+
+    let mut stuck = TcpStream::connect(addr).await.unwrap();
+    stuck.write_all(b"GET ").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let started = Instant::now();
+    common::signal(&child, "INT");
+    let status = tokio::time::timeout(Duration::from_secs(5), child.wait())
+        .await
+        .expect("rhoxy should exit once the grace period expires")
+        .unwrap();
+
+    assert!(status.success(), "rhoxy exited with {}", status);
+    assert!(
+        started.elapsed() >= Duration::from_millis(900),
+        "rhoxy should wait out the grace period, exited after {:?}",
+        started.elapsed()
+    );
+
+    let mut buf = [0u8; 64];
+    let n = stuck.read(&mut buf).await.unwrap_or(0);
+    assert_eq!(
+        n, 0,
+        "Aborted connection should be closed without a response"
+    );
+}
+
+#[tokio::test]
+async fn test_max_lifetime_requests_stops_server() {
+    let port = common::free_port();
+    let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+    let mut child =
+        common::spawn_rhoxy(&["--port", &port.to_string(), "--max-lifetime-requests", "2"]);
+
+    // Wait for the listener without spending a request on a probe.
+    let mut first = None;
+    for _ in 0..100 {
+        if let Ok(stream) = TcpStream::connect(addr).await {
+            first = Some(stream);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let mut first = first.expect("rhoxy never started listening");
+    first
+        .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    first.read_to_end(&mut response).await.unwrap();
+    assert!(String::from_utf8_lossy(&response).contains("200 OK"));
+
+    let second = common::send_raw(addr, b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    assert!(
+        second.contains("200 OK"),
+        "The request that hits the limit is still served, got: {}",
+        second
+    );
+
+    let status = tokio::time::timeout(Duration::from_secs(5), child.wait())
+        .await
+        .expect("rhoxy should shut itself down after the lifetime limit")
+        .unwrap();
+    assert!(status.success(), "rhoxy exited with {}", status);
+    assert!(
+        TcpStream::connect(addr).await.is_err(),
+        "No connections should be accepted after the limit"
+    );
+}
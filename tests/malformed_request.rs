@@ -23,3 +23,228 @@ async fn test_empty_request_returns_400() {
         response
     );
 }
+
+#[tokio::test]
+async fn test_header_line_without_colon_returns_400() {
+    let proxy = common::start_proxy().await;
+    let response = common::send_raw(
+        proxy,
+        b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\nX-Broken header\r\n\r\n",
+    )
+    .await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request"),
+        "Expected 400 rather than a dropped connection, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_control_characters_in_target_return_400() {
+    let proxy = common::start_proxy().await;
+
+    for request in [
+        &b"GET http://example.com/a\0b HTTP/1.1\r\nHost: example.com\r\n\r\n"[..],
+        &b"GET http://example.com/a\rX-Injected: 1 HTTP/1.1\r\nHost: example.com\r\n\r\n"[..],
+    ] {
+        let response = common::send_raw(proxy, request).await;
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request"),
+            "Expected 400 for {:?}, got: {}",
+            String::from_utf8_lossy(request),
+            response
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_oversized_request_line_returns_414() {
+    let proxy = common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        max_request_line: Some(1024),
+        ..Default::default()
+    })
+    .await;
+
+    let request = format!(
+        "GET http://example.com/{} HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        "a".repeat(2000)
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 414 URI Too Long"),
+        "Expected 414, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_request_line_within_limit_is_accepted() {
+    let proxy = common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        max_request_line: Some(1024),
+        ..Default::default()
+    })
+    .await;
+
+    // Under the limit, so the request gets as far as SSRF protection.
+    let request = format!(
+        "GET http://127.0.0.1/{} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n",
+        "a".repeat(900)
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 403 Forbidden"),
+        "Expected the request line to be accepted, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_non_http_schemes_return_400() {
+    let proxy = common::start_proxy().await;
+
+    for target in ["file:///etc/passwd", "ftp://example.com/pub/file"] {
+        let request = format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", target);
+        let response = common::send_raw(proxy, request.as_bytes()).await;
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request"),
+            "Expected 400 for {}, got: {}",
+            target,
+            response
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_unsupported_http_version_returns_505() {
+    let proxy = common::start_proxy().await;
+
+    for (version, expected) in [
+        ("HTTP/2.0", "HTTP/1.1 505 HTTP Version Not Supported"),
+        ("GARBAGE", "HTTP/1.1 400 Bad Request"),
+    ] {
+        let request = format!(
+            "GET http://example.com/ {}\r\nHost: example.com\r\n\r\n",
+            version
+        );
+        let response = common::send_raw(proxy, request.as_bytes()).await;
+        assert!(
+            response.starts_with(expected),
+            "Expected {:?} for {}, got: {}",
+            expected,
+            version,
+            response
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_http10_accepted_unless_rejected() {
+    let request = b"GET http://127.0.0.1/ HTTP/1.0\r\nHost: 127.0.0.1\r\n\r\n";
+
+    // Accepted, so the request gets as far as SSRF protection.
+    let proxy = common::start_proxy().await;
+    let response = common::send_raw(proxy, request).await;
+    assert!(
+        response.starts_with("HTTP/1.1 403 Forbidden"),
+        "Expected HTTP/1.0 to be accepted, got: {}",
+        response
+    );
+
+    let proxy = common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        reject_http10: true,
+        ..Default::default()
+    })
+    .await;
+    let response = common::send_raw(proxy, request).await;
+    assert!(
+        response.starts_with("HTTP/1.1 505 HTTP Version Not Supported"),
+        "Expected 505 with reject_http10, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_oversized_header_section_returns_431() {
+    let proxy = common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        max_header_bytes: Some(4096),
+        ..Default::default()
+    })
+    .await;
+
+    let mut request = b"GET http://example.com/ HTTP/1.1\r\n".to_vec();
+    for i in 0..50 {
+        request.extend_from_slice(format!("X-Filler-{}: {}\r\n", i, "a".repeat(200)).as_bytes());
+    }
+    request.extend_from_slice(b"\r\n");
+    let response = common::send_raw(proxy, &request).await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 431 Request Header Fields Too Large"),
+        "Expected 431, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_header_section_within_limit_is_accepted() {
+    let proxy = common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        max_header_bytes: Some(4096),
+        ..Default::default()
+    })
+    .await;
+
+    // Well under the limit, so the request gets as far as SSRF protection.
+    let mut request = b"GET http://127.0.0.1/ HTTP/1.1\r\nHost: 127.0.0.1\r\n".to_vec();
+    for i in 0..5 {
+        request.extend_from_slice(format!("X-Filler-{}: {}\r\n", i, "a".repeat(200)).as_bytes());
+    }
+    request.extend_from_slice(b"\r\n");
+    let response = common::send_raw(proxy, &request).await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 403 Forbidden"),
+        "Expected headers to be accepted, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_oversized_connect_header_section_returns_431() {
+    let proxy = common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        max_header_bytes: Some(4096),
+        ..Default::default()
+    })
+    .await;
+
+    let mut request = b"CONNECT example.com:443 HTTP/1.1\r\n".to_vec();
+    for i in 0..50 {
+        request.extend_from_slice(format!("X-Filler-{}: {}\r\n", i, "a".repeat(200)).as_bytes());
+    }
+    request.extend_from_slice(b"\r\n");
+    let response = common::send_raw(proxy, &request).await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 431 Request Header Fields Too Large"),
+        "Expected 431 for CONNECT, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_truncated_body_returns_400() {
+    let proxy = common::start_proxy().await;
+    let response = common::send_raw(
+        proxy,
+        b"POST http://example.com/ HTTP/1.1\r\nHost: example.com\r\nContent-Length: 100\r\n\r\nonly-a-few-bytes",
+    )
+    .await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request"),
+        "Expected 400 for a truncated body, got: {}",
+        response
+    );
+}
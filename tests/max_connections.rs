@@ -0,0 +1,47 @@
+//! Integration tests for `--max-connections`. These run the real binary
+//! because the connection limit lives in `main.rs`'s accept loop.
+//!
+//!     cargo test --test max_connections
+
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn test_max_connections_rejects_excess_then_recovers() {
+    let addr: SocketAddr = format!("127.0.0.1:{}", common::free_port())
+        .parse()
+        .unwrap();
+    let _child =
+        common::spawn_rhoxy(&["--port", &addr.port().to_string(), "--max-connections", "1"]);
+    common::wait_for_listener(addr).await;
+    // `wait_for_listener`'s probe holds the slot until the proxy sees it close.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    // An idle client holds the only slot while the proxy waits for its request.
+    let held = TcpStream::connect(addr).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut excess = TcpStream::connect(addr).await.unwrap();
+    let mut buf = Vec::new();
+    let read = tokio::time::timeout(Duration::from_secs(2), excess.read_to_end(&mut buf))
+        .await
+        .expect("An excess connection should be closed, not left waiting");
+    assert!(
+        read.is_err() || buf.is_empty(),
+        "An excess connection should be closed without a response, got: {}",
+        String::from_utf8_lossy(&buf)
+    );
+
+    drop(held);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let response = common::send_raw(addr, b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    assert!(
+        response.contains("200 OK"),
+        "Expected the freed slot to serve the next client, got: {}",
+        response
+    );
+}
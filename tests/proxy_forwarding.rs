@@ -8,9 +8,11 @@
 
 mod common;
 
-use std::sync::Once;
+use rhoxy::config::{PortRanges, ProxyConfig};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Once};
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
 use tokio::net::{TcpListener, TcpStream};
 
 static INIT: Once = Once::new();
@@ -23,6 +25,12 @@ fn setup() {
     });
 }
 
+/// Local upstreams listen on ephemeral ports, which the default CONNECT
+/// allowlist (443 only) refuses.
+fn any_connect_port() -> PortRanges {
+    "1-65535".parse().unwrap()
+}
+
 // ---------------------------------------------------------------------------
 // SSRF bypass verification
 // ---------------------------------------------------------------------------
@@ -67,444 +75,3742 @@ async fn test_http_get_forwarding() {
 }
 
 #[tokio::test]
-async fn test_http_post_with_body() {
+async fn test_http_forwarded_body_counts_as_bytes_proxied() {
     setup();
 
-    let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-    let upstream_addr = upstream_listener.local_addr().unwrap();
+    let upstream =
+        common::start_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").await;
+    let proxy = common::start_proxy().await;
+    let before = rhoxy::metrics::stats().bytes_proxied;
+
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(response.ends_with("hello"), "Got: {}", response);
+    assert!(rhoxy::metrics::stats().bytes_proxied >= before + 5);
+}
+
+#[tokio::test]
+async fn test_http_get_writes_access_log_line() {
+    setup();
+
+    let upstream =
+        common::start_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").await;
+    let log_file = tempfile::NamedTempFile::new().unwrap();
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        access_log: Some(rhoxy::access_log::AccessLog::open(log_file.path()).unwrap()),
+        ..Default::default()
+    })
+    .await;
+
+    let request = format!(
+        "GET http://{}/path HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+    assert!(
+        response.contains("200 OK"),
+        "Expected 200 OK, got: {}",
+        response
+    );
+
+    let log = std::fs::read_to_string(log_file.path()).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(
+        lines.len(),
+        1,
+        "Expected one access log line, got: {:?}",
+        lines
+    );
+
+    let line = lines[0];
+    assert!(line.starts_with("127.0.0.1 - - ["), "Bad prefix: {}", line);
+    assert!(
+        line.contains(&format!("\"GET http://{}/path HTTP/1.1\" 200 5 ", upstream)),
+        "Bad request/status/bytes: {}",
+        line
+    );
+    // Duration, then the bytes read from and written to the client: the
+    // whole request and the whole response, headers included.
+    let fields: Vec<&str> = line.rsplitn(4, ' ').collect();
+    assert!(fields[2].parse::<u64>().is_ok(), "Bad duration: {}", line);
+    assert_eq!(
+        fields[1],
+        request.len().to_string(),
+        "Bad bytes read: {}",
+        line
+    );
+    assert_eq!(
+        fields[0],
+        response.len().to_string(),
+        "Bad bytes written: {}",
+        line
+    );
+}
+
+/// Upstream that answers 200 and reports the X-Request-Id it received on the
+/// returned channel.
+async fn start_request_id_upstream() -> (
+    std::net::SocketAddr,
+    tokio::sync::oneshot::Receiver<Option<String>>,
+) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = tokio::sync::oneshot::channel();
 
     tokio::spawn(async move {
-        let (stream, _) = upstream_listener.accept().await.unwrap();
-        let (reader, writer) = stream.into_split();
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
         let mut reader = BufReader::new(reader);
-        let mut writer = BufWriter::new(writer);
 
-        let body = common::read_upstream_body(&mut reader).await;
+        let mut request_id = None;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("x-request-id") {
+                    request_id = Some(value.trim().to_string());
+                }
+            }
+        }
+        let _ = tx.send(request_id);
 
-        let resp_body = format!("received {} bytes", body.len());
-        let resp = format!(
-            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
-            resp_body.len(),
-            resp_body
-        );
-        writer.write_all(resp.as_bytes()).await.unwrap();
-        writer.flush().await.unwrap();
+        writer
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK")
+            .await
+            .unwrap();
     });
 
+    (addr, rx)
+}
+
+fn response_request_id(response: &str) -> Option<String> {
+    response
+        .lines()
+        .find_map(|line| line.strip_prefix("x-request-id: "))
+        .map(str::to_string)
+}
+
+#[tokio::test]
+async fn test_http_request_id_generated_and_propagated() {
+    setup();
+
+    let (upstream, upstream_id) = start_request_id_upstream().await;
     let proxy = common::start_proxy().await;
-    let body = "test body payload";
+
     let request = format!(
-        "POST http://{}/submit HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{}",
-        upstream_addr,
-        upstream_addr,
-        body.len(),
-        body
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
     );
     let response = common::send_raw(proxy, request.as_bytes()).await;
 
-    assert!(
-        response.contains("200 OK"),
-        "Expected 200 OK, got: {}",
+    let upstream_id = upstream_id
+        .await
+        .unwrap()
+        .expect("upstream should receive X-Request-Id");
+    assert!(!upstream_id.is_empty());
+    assert_eq!(
+        response_request_id(&response).as_deref(),
+        Some(upstream_id.as_str()),
+        "Response should echo the upstream request ID, got: {}",
         response
     );
-    let expected = format!("received {} bytes", body.len());
+}
+
+#[tokio::test]
+async fn test_http_request_id_honors_client_header() {
+    setup();
+
+    let (upstream, upstream_id) = start_request_id_upstream().await;
+    let proxy = common::start_proxy().await;
+
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\nX-Request-Id: client-abc-123\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert_eq!(
+        upstream_id.await.unwrap().as_deref(),
+        Some("client-abc-123")
+    );
+    assert_eq!(
+        response_request_id(&response).as_deref(),
+        Some("client-abc-123"),
+        "Response should echo the client's request ID, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_http_request_id_replaces_unusable_client_header() {
+    setup();
+
+    let (upstream, upstream_id) = start_request_id_upstream().await;
+    let proxy = common::start_proxy().await;
+
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\nX-Request-Id: {}\r\n\r\n",
+        upstream,
+        upstream,
+        "x".repeat(rhoxy::constants::MAX_REQUEST_ID_LEN + 1)
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    let upstream_id = upstream_id
+        .await
+        .unwrap()
+        .expect("upstream should receive X-Request-Id");
     assert!(
-        response.contains(&expected),
-        "Expected upstream to receive body, got: {}",
+        uuid::Uuid::parse_str(&upstream_id).is_ok(),
+        "Got: {}",
+        upstream_id
+    );
+    assert_eq!(
+        response_request_id(&response).as_deref(),
+        Some(upstream_id.as_str())
+    );
+}
+
+#[tokio::test]
+async fn test_http_get_with_valid_proxy_auth() {
+    setup();
+
+    let upstream =
+        common::start_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").await;
+    let mut auth = rhoxy::auth::ProxyAuth::new();
+    auth.add("bob", "hunter2");
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        proxy_auth: Some(auth),
+        ..Default::default()
+    })
+    .await;
+
+    // bob:hunter2
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\nProxy-Authorization: Basic Ym9iOmh1bnRlcjI=\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.contains("200 OK") && response.contains("hello"),
+        "Expected forwarded response, got: {}",
         response
     );
 }
 
 #[tokio::test]
-async fn test_http_response_headers_forwarded() {
+async fn test_http_blocked_response_content_type() {
     setup();
 
     let upstream = common::start_upstream(
-        b"HTTP/1.1 200 OK\r\nX-Custom: test-value\r\nContent-Length: 2\r\n\r\nOK",
+        b"HTTP/1.1 200 OK\r\nContent-Type: application/x-msdownload\r\nContent-Length: 10\r\n\r\nMZ-payload",
     )
     .await;
-    let proxy = common::start_proxy().await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        blocked_content_types: vec!["application/x-msdownload".to_string()],
+        block_status: Some(451),
+        ..Default::default()
+    })
+    .await;
 
     let request = format!(
-        "GET http://{}/path HTTP/1.1\r\nHost: {}\r\n\r\n",
+        "GET http://{}/setup.exe HTTP/1.1\r\nHost: {}\r\n\r\n",
         upstream, upstream
     );
     let response = common::send_raw(proxy, request.as_bytes()).await;
 
     assert!(
-        response.contains("x-custom: test-value") || response.contains("X-Custom: test-value"),
-        "Expected X-Custom header in response, got: {}",
+        response.starts_with("HTTP/1.1 451 Unavailable For Legal Reasons\r\n"),
+        "Expected configured block status, got: {}",
+        response
+    );
+    assert!(
+        !response.contains("MZ-payload"),
+        "Blocked body must not reach the client, got: {}",
         response
     );
 }
 
 #[tokio::test]
-async fn test_http_hop_by_hop_headers_stripped_and_others_forwarded() {
+async fn test_http_fragment_stripped_before_forwarding() {
     setup();
 
     let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
     let upstream_addr = upstream_listener.local_addr().unwrap();
+    let (tx, rx) = tokio::sync::oneshot::channel();
 
     tokio::spawn(async move {
-        let (mut stream, _) = upstream_listener.accept().await.unwrap();
-        let mut buf = vec![0u8; 8192];
-        let n = stream.read(&mut buf).await.unwrap();
-        let received = String::from_utf8_lossy(&buf[..n]);
-
-        let has_proxy_auth = received
-            .lines()
-            .any(|l| l.to_lowercase().starts_with("proxy-authorization:"));
-        let has_x_keep = received
-            .lines()
-            .any(|l| l.to_lowercase().starts_with("x-keep:"));
+        let (stream, _) = upstream_listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
 
-        let body = match (has_proxy_auth, has_x_keep) {
-            (false, true) => "STRIPPED_AND_KEPT",
-            (true, _) => "LEAKED",
-            (false, false) => "OVER_STRIPPED",
-        };
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).await.unwrap();
+        let _ = tx.send(request_line);
 
-        let resp = format!(
-            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
-            body.len(),
-            body
-        );
-        stream.write_all(resp.as_bytes()).await.unwrap();
-        stream.shutdown().await.unwrap();
+        writer
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK")
+            .await
+            .unwrap();
     });
 
     let proxy = common::start_proxy().await;
     let request = format!(
-        "GET http://{}/path HTTP/1.1\r\nHost: {}\r\nProxy-Authorization: Basic secret\r\nX-Keep: yes\r\n\r\n",
+        "GET http://{}/page?q=1#section-2 HTTP/1.1\r\nHost: {}\r\n\r\n",
         upstream_addr, upstream_addr
     );
     let response = common::send_raw(proxy, request.as_bytes()).await;
-
     assert!(
         response.contains("200 OK"),
         "Expected 200 OK, got: {}",
         response
     );
-    assert!(
-        response.contains("STRIPPED_AND_KEPT"),
-        "Expected hop-by-hop stripped and non-hop-by-hop kept, got: {}",
-        response
-    );
+
+    let request_line = rx.await.unwrap();
+    assert_eq!(request_line, "GET /page?q=1 HTTP/1.1\r\n");
 }
 
-// ---------------------------------------------------------------------------
-// Health check non-interception for absolute URLs
-// ---------------------------------------------------------------------------
+const DECOMPRESS_PLAINTEXT: &str = "compressible compressible compressible compressible";
+
+/// Upstream that always answers with a gzip-encoded body.
+async fn start_gzip_upstream() -> std::net::SocketAddr {
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(DECOMPRESS_PLAINTEXT.as_bytes()).unwrap();
+    let body = encoder.finish().unwrap();
+
+    let mut response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(&body);
+    common::start_upstream(response.leak()).await
+}
 
 #[tokio::test]
-async fn test_health_check_not_intercepted_for_absolute_url() {
+async fn test_http_decompress_for_client_without_accept_encoding() {
     setup();
 
-    let upstream =
-        common::start_upstream(b"HTTP/1.1 418 I'm a Teapot\r\nContent-Length: 6\r\n\r\nteapot")
-            .await;
-    let proxy = common::start_proxy().await;
+    let upstream = start_gzip_upstream().await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        decompress: true,
+        ..Default::default()
+    })
+    .await;
 
     let request = format!(
-        "GET http://{}/health HTTP/1.1\r\nHost: {}\r\n\r\n",
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
         upstream, upstream
     );
     let response = common::send_raw(proxy, request.as_bytes()).await;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
 
+    assert!(head.starts_with("HTTP/1.1 200 OK"), "Got: {}", head);
     assert!(
-        response.contains("418"),
-        "Absolute /health should be forwarded to upstream, got: {}",
-        response
+        !head.to_lowercase().contains("content-encoding"),
+        "Content-Encoding should be dropped, got: {}",
+        head
+    );
+    assert!(
+        !head.to_lowercase().contains("content-length"),
+        "Stale Content-Length should be dropped, got: {}",
+        head
     );
+    assert_eq!(body, DECOMPRESS_PLAINTEXT);
 }
 
-// ---------------------------------------------------------------------------
-// 502 Bad Gateway — deterministic (closed port)
-// ---------------------------------------------------------------------------
-
 #[tokio::test]
-async fn test_http_502_on_closed_port() {
+async fn test_http_decompress_preserves_body_client_accepts() {
     setup();
 
-    // Bind and immediately drop to get a port guaranteed not listening.
-    let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
-    let dead_addr = dead.local_addr().unwrap();
-    drop(dead);
+    let upstream = start_gzip_upstream().await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        decompress: true,
+        ..Default::default()
+    })
+    .await;
 
-    let proxy = common::start_proxy().await;
     let request = format!(
-        "GET http://{}/path HTTP/1.1\r\nHost: {}\r\n\r\n",
-        dead_addr, dead_addr
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\nAccept-Encoding: gzip, br\r\n\r\n",
+        upstream, upstream
     );
     let response = common::send_raw(proxy, request.as_bytes()).await;
 
     assert!(
-        response.contains("502 Bad Gateway"),
-        "Expected 502 for closed port, got: {}",
+        response.contains("content-encoding: gzip"),
+        "Encoded body should pass through, got: {}",
         response
     );
+    assert!(!response.contains(DECOMPRESS_PLAINTEXT));
+}
+
+/// An upstream that drops its first connection after reading the request,
+/// then answers `200` on every later one. Returns the connection count.
+async fn start_flaky_upstream() -> (std::net::SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let first = counter.fetch_add(1, Ordering::SeqCst) == 0;
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; 8192];
+                let _ = stream.read(&mut buf).await;
+                if !first {
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                        .await;
+                }
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+    (addr, connections)
 }
 
 #[tokio::test]
-async fn test_connect_502_on_closed_port() {
+async fn test_http_retries_idempotent_request_after_dropped_connection() {
     setup();
 
-    let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
-    let dead_addr = dead.local_addr().unwrap();
-    drop(dead);
+    let (upstream, connections) = start_flaky_upstream().await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        retries: 1,
+        ..Default::default()
+    })
+    .await;
 
-    let proxy = common::start_proxy().await;
     let request = format!(
-        "CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n",
-        dead_addr, dead_addr
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
     );
     let response = common::send_raw(proxy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "Got: {}", response);
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+}
 
+#[tokio::test]
+async fn test_http_never_retries_post() {
+    setup();
+
+    let (upstream, connections) = start_flaky_upstream().await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        retries: 3,
+        ..Default::default()
+    })
+    .await;
+
+    let request = format!(
+        "POST http://{}/ HTTP/1.1\r\nHost: {}\r\nContent-Length: 4\r\n\r\ndata",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
     assert!(
-        response.contains("502 Bad Gateway"),
-        "Expected 502 for CONNECT to closed port, got: {}",
+        response.starts_with("HTTP/1.1 502 Bad Gateway"),
+        "Got: {}",
         response
     );
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
 }
 
-// ---------------------------------------------------------------------------
-// CONNECT tunnel — bidirectional data
-// ---------------------------------------------------------------------------
-
 #[tokio::test]
-async fn test_connect_tunnel_bidirectional() {
+async fn test_http_url_userinfo_needs_flag() {
     setup();
 
-    // Start a TCP echo server: reads data, sends it back prefixed with "echo:".
-    let echo_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-    let echo_addr = echo_listener.local_addr().unwrap();
-
-    tokio::spawn(async move {
-        let (mut stream, _) = echo_listener.accept().await.unwrap();
-        let mut buf = vec![0u8; 1024];
-        let n = stream.read(&mut buf).await.unwrap();
-        let mut response = b"echo:".to_vec();
-        response.extend_from_slice(&buf[..n]);
-        stream.write_all(&response).await.unwrap();
-        stream.shutdown().await.unwrap();
-    });
+    let upstream =
+        common::start_looping_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+    let request = |target: String| format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, upstream);
+    let plain = request(format!("http://{}/", upstream));
+    let with_userinfo = request(format!("http://user:secret@{}/", upstream));
 
     let proxy = common::start_proxy().await;
-    let mut stream = TcpStream::connect(proxy).await.unwrap();
-
-    // Phase 1: Send CONNECT request
-    let connect_req = format!(
-        "CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n",
-        echo_addr, echo_addr
+    let response = common::send_raw(proxy, plain.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "Got: {}", response);
+    let response = common::send_raw(proxy, with_userinfo.as_bytes()).await;
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request"),
+        "Got: {}",
+        response
     );
-    stream.write_all(connect_req.as_bytes()).await.unwrap();
 
-    // Phase 2: Read "200 Connection Established\r\n\r\n"
-    let mut buf = vec![0u8; 256];
-    let mut total = 0;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        allow_url_userinfo: true,
+        ..Default::default()
+    })
+    .await;
+    let response = common::send_raw(proxy, with_userinfo.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "Got: {}", response);
+}
+
+/// Send `request` and return the raw response head and body bytes.
+async fn send_raw_bytes(proxy: std::net::SocketAddr, request: &str) -> (String, Vec<u8>) {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("Timed out reading response")
+        .unwrap();
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .expect("no end of headers");
+    let body = response.split_off(end + 4);
+    (String::from_utf8(response).unwrap(), body)
+}
+
+#[tokio::test]
+async fn test_http_compress_responses_gzips_text_for_accepting_client() {
+    use std::io::Read;
+
+    setup();
+
+    let text = "compress me ".repeat(200);
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}",
+        text.len(),
+        text
+    );
+    let upstream = common::start_looping_upstream(response.leak().as_bytes()).await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        compress_min_size: Some(1024),
+        ..Default::default()
+    })
+    .await;
+
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\nAccept-Encoding: gzip\r\n\r\n",
+        upstream, upstream
+    );
+    let (head, mut body) = send_raw_bytes(proxy, &request).await;
+    let head = head.to_ascii_lowercase();
+    assert!(head.contains("content-encoding: gzip\r\n"), "Got: {}", head);
+    assert!(
+        head.contains("transfer-encoding: chunked\r\n"),
+        "Got: {}",
+        head
+    );
+    assert!(
+        !head.contains("content-length"),
+        "The upstream's length no longer applies, got: {}",
+        head
+    );
+
+    // Undo the chunked framing, checking it ends with the last-chunk.
+    let mut compressed = Vec::new();
     loop {
-        let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf[total..]))
-            .await
-            .expect("Timed out waiting for CONNECT response")
-            .expect("Failed to read CONNECT response");
-        assert!(n > 0, "Connection closed before tunnel established");
-        total += n;
-        if buf[..total].windows(4).any(|w| w == b"\r\n\r\n") {
+        let line_end = body.windows(2).position(|w| w == b"\r\n").unwrap();
+        let size = usize::from_str_radix(std::str::from_utf8(&body[..line_end]).unwrap(), 16)
+            .expect("bad chunk size");
+        let rest = body.split_off(line_end + 2);
+        if size == 0 {
+            assert_eq!(rest, b"\r\n");
             break;
         }
+        compressed.extend_from_slice(&rest[..size]);
+        assert_eq!(&rest[size..size + 2], b"\r\n");
+        body = rest[size + 2..].to_vec();
     }
-    let establishment = String::from_utf8_lossy(&buf[..total]);
+    assert!(compressed.len() < text.len());
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(compressed.as_slice())
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, text);
+
+    // A client that doesn't accept gzip gets the body as the upstream sent it.
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let (head, body) = send_raw_bytes(proxy, &request).await;
+    assert!(!head.to_ascii_lowercase().contains("content-encoding"));
+    assert_eq!(body, text.as_bytes());
+}
+
+#[tokio::test]
+async fn test_http_deprecated_path_gets_sunset_header() {
+    setup();
+
+    let upstream =
+        common::start_looping_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK").await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        deprecations: vec!["/api/v1/*=Wed, 31 Dec 2025 23:59:59 GMT".parse().unwrap()],
+        ..Default::default()
+    })
+    .await;
+
+    let deprecated = format!(
+        "GET http://{}/api/v1/users HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, deprecated.as_bytes()).await;
+    assert!(
+        response.contains("deprecation: true\r\n")
+            && response.contains("sunset: Wed, 31 Dec 2025 23:59:59 GMT\r\n"),
+        "Expected deprecation headers, got: {}",
+        response
+    );
+
+    let current = format!(
+        "GET http://{}/api/v2/users HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, current.as_bytes()).await;
+    assert!(
+        response.contains("200 OK"),
+        "Expected 200 OK, got: {}",
+        response
+    );
+    assert!(
+        !response.contains("deprecation:") && !response.contains("sunset:"),
+        "Unmatched path must not be marked, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_http_security_headers_added_without_overwriting() {
+    setup();
+
+    let upstream = common::start_upstream(
+        b"HTTP/1.1 200 OK\r\nX-Frame-Options: DENY\r\nContent-Length: 2\r\n\r\nOK",
+    )
+    .await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        security_headers: true,
+        ..Default::default()
+    })
+    .await;
+
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.contains("x-content-type-options: nosniff\r\n")
+            && response.contains("referrer-policy: strict-origin-when-cross-origin\r\n"),
+        "Expected the security header bundle, got: {}",
+        response
+    );
+    assert!(
+        response.contains("x-frame-options: DENY\r\n") && !response.contains("SAMEORIGIN"),
+        "Upstream X-Frame-Options must be kept, got: {}",
+        response
+    );
+    assert!(
+        !response.contains("strict-transport-security"),
+        "HSTS is only added for https upstreams, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_http_latency_header_only_when_enabled() {
+    setup();
+
+    for enabled in [true, false] {
+        let upstream =
+            common::start_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK").await;
+        let proxy = common::start_proxy_with_config(ProxyConfig {
+            add_latency_header: enabled,
+            ..Default::default()
+        })
+        .await;
+
+        let request = format!(
+            "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+            upstream, upstream
+        );
+        let response = common::send_raw(proxy, request.as_bytes()).await;
+        let latency = response
+            .lines()
+            .find_map(|line| line.strip_prefix("x-proxy-latency-ms: "));
+        if enabled {
+            assert!(
+                latency.is_some_and(|ms| ms.parse::<u64>().is_ok()),
+                "Expected a numeric latency header, got: {}",
+                response
+            );
+        } else {
+            assert_eq!(latency, None, "Header must be opt-in, got: {}", response);
+        }
+    }
+}
+
+/// Upstream that answers with `response` and reports the header lines it
+/// received, lowercased, on the returned channel.
+async fn start_header_capturing_upstream(
+    response: &'static [u8],
+) -> (
+    std::net::SocketAddr,
+    tokio::sync::oneshot::Receiver<Vec<String>>,
+) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+
+        let mut headers = Vec::new();
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        loop {
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+            headers.push(line.trim().to_ascii_lowercase());
+        }
+        let _ = tx.send(headers);
+
+        writer.write_all(response).await.unwrap();
+    });
+
+    (addr, rx)
+}
+
+#[tokio::test]
+async fn test_http_header_rewrites_in_both_directions() {
+    setup();
+
+    let (upstream, rx) = start_header_capturing_upstream(
+        b"HTTP/1.1 200 OK\r\nServer: upstream\r\nX-Powered-By: php\r\nCache-Control: no-store\r\nContent-Length: 2\r\n\r\nOK",
+    )
+    .await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        set_request_headers: vec![
+            "X-Env: prod".parse().unwrap(),
+            "User-Agent: rhoxy".parse().unwrap(),
+        ],
+        remove_request_headers: vec!["cookie".parse().unwrap()],
+        set_response_headers: vec![
+            "Cache-Control: public".parse().unwrap(),
+            "X-Served-By: rhoxy".parse().unwrap(),
+        ],
+        remove_response_headers: vec!["x-powered-by".parse().unwrap()],
+        ..Default::default()
+    })
+    .await;
+
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\nUser-Agent: curl\r\nCookie: session=1\r\nX-Keep: yes\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    let sent = rx.await.unwrap();
+    for expected in ["x-env: prod", "user-agent: rhoxy", "x-keep: yes"] {
+        assert!(
+            sent.iter().any(|h| h == expected),
+            "Expected {:?} upstream, got: {:?}",
+            expected,
+            sent
+        );
+    }
+    assert!(
+        !sent
+            .iter()
+            .any(|h| h.starts_with("cookie:") || h == "user-agent: curl"),
+        "Removed and overridden headers must not reach the upstream, got: {:?}",
+        sent
+    );
+
+    assert!(
+        response.contains("cache-control: public\r\n")
+            && response.contains("x-served-by: rhoxy\r\n")
+            && response.contains("server: upstream\r\n"),
+        "Expected rewritten response headers, got: {}",
+        response
+    );
+    assert!(
+        !response.contains("no-store") && !response.contains("x-powered-by"),
+        "Removed and overridden headers must not reach the client, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_proxy_connection_close_honored_and_not_forwarded() {
+    setup();
+
+    let (upstream, rx) =
+        start_header_capturing_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK").await;
+    let proxy = common::start_proxy_with_config(ProxyConfig::default()).await;
+
+    // The client keeps its end open, so only the proxy can end the exchange.
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\nProxy-Connection: close\r\n\r\n",
+        upstream, upstream
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("Proxy-Connection: close should close the client connection")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "Got: {}", response);
+
+    let sent = rx.await.unwrap();
+    assert!(
+        !sent.iter().any(|h| h.starts_with("proxy-connection:")),
+        "Proxy-Connection is hop-by-hop and must not reach the upstream, got: {:?}",
+        sent
+    );
+}
+
+const CHUNKED_RESPONSE: &[u8] =
+    b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+
+/// Split a response into its lower-cased header lines and its body.
+fn split_response(response: &str) -> (Vec<String>, &str) {
+    let (head, body) = response.split_once("\r\n\r\n").expect("no end of headers");
+    let headers = head
+        .lines()
+        .skip(1)
+        .map(|h| h.to_ascii_lowercase())
+        .collect();
+    (headers, body)
+}
+
+/// Decode a complete chunked body, panicking on bad or missing framing.
+fn dechunk(mut body: &str) -> String {
+    let mut decoded = String::new();
+    loop {
+        let (size, rest) = body.split_once("\r\n").expect("missing chunk size line");
+        let size = usize::from_str_radix(size, 16).expect("bad chunk size");
+        if size == 0 {
+            assert_eq!(
+                rest, "\r\n",
+                "Expected the body to end after the last chunk"
+            );
+            return decoded;
+        }
+        decoded.push_str(&rest[..size]);
+        body = rest[size..]
+            .strip_prefix("\r\n")
+            .expect("chunk data not followed by CRLF");
+    }
+}
+
+#[tokio::test]
+async fn test_http_chunked_response_is_rechunked() {
+    setup();
+
+    let upstream = common::start_upstream(CHUNKED_RESPONSE).await;
+    let proxy = common::start_proxy().await;
+
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+    let (headers, body) = split_response(&response);
+
+    let framing: Vec<_> = headers
+        .iter()
+        .filter(|h| h.starts_with("transfer-encoding:") || h.starts_with("content-length:"))
+        .collect();
+    assert_eq!(
+        framing,
+        ["transfer-encoding: chunked"],
+        "Expected exactly one framing header, got: {}",
+        response
+    );
+    assert_eq!(dechunk(body), "hello world");
+}
+
+#[tokio::test]
+async fn test_http_chunked_response_trailers_reach_client() {
+    setup();
+
+    let upstream = common::start_upstream(
+        b"HTTP/1.1 200 OK\r\nContent-Type: application/grpc\r\nTransfer-Encoding: chunked\r\nTrailer: grpc-status, grpc-message\r\n\r\n\
+          5\r\nhello\r\n\
+          0\r\ngrpc-status: 0\r\ngrpc-message: OK\r\nx-unannounced: 1\r\n\r\n",
+    )
+    .await;
+    let proxy = common::start_proxy().await;
+
+    let request = format!(
+        "POST http://{}/svc/Method HTTP/1.1\r\nHost: {}\r\nTE: trailers\r\nContent-Length: 0\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+    let (headers, body) = split_response(&response);
+
+    assert!(
+        headers
+            .iter()
+            .any(|h| h == "trailer: grpc-status, grpc-message"),
+        "Expected the Trailer header, got: {}",
+        response
+    );
+    assert_eq!(
+        body, "5\r\nhello\r\n0\r\ngrpc-status: 0\r\ngrpc-message: OK\r\n\r\n",
+        "Expected the announced trailers after the last chunk, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_http_head_response_to_chunked_has_no_body() {
+    setup();
+
+    let upstream =
+        common::start_upstream(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n").await;
+    let proxy = common::start_proxy().await;
+
+    let request = format!(
+        "HEAD http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+    let (headers, body) = split_response(&response);
+
+    assert!(
+        !headers.iter().any(|h| h.starts_with("transfer-encoding:")),
+        "A HEAD response has no body to frame, got: {}",
+        response
+    );
+    assert_eq!(body, "", "Expected no body for HEAD, got: {}", response);
+}
+
+#[tokio::test]
+async fn test_http_head_response_keeps_content_length() {
+    setup();
+
+    // The upstream keeps the connection open after the head, so a proxy
+    // waiting for 1234 body bytes would hang.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 8192];
+        let _ = stream.read(&mut buf).await;
+        stream
+            .write_all(
+                b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 1234\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(30)).await;
+    });
+    // No body is coming, so neither the size cap nor compression applies.
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        compress_min_size: Some(0),
+        max_response_size: Some(100),
+        ..Default::default()
+    })
+    .await;
+
+    let request = format!(
+        "HEAD http://{}/ HTTP/1.1\r\nHost: {}\r\nAccept-Encoding: gzip\r\n\r\n",
+        upstream, upstream
+    );
+    let response = tokio::time::timeout(
+        Duration::from_secs(5),
+        common::send_raw(proxy, request.as_bytes()),
+    )
+    .await
+    .expect("HEAD response should not wait for a body");
+    let (headers, body) = split_response(&response);
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "Got: {}", response);
+    assert!(
+        headers.iter().any(|h| h == "content-length: 1234"),
+        "Expected the upstream's Content-Length, got: {}",
+        response
+    );
+    assert!(
+        !headers.iter().any(|h| h.starts_with("content-encoding:")),
+        "A HEAD response has no body to compress, got: {}",
+        response
+    );
+    assert_eq!(body, "", "Expected no body for HEAD, got: {}", response);
+}
+
+/// A WebSocket upstream that answers the handshake with `101`, then echoes
+/// one client text frame back unmasked. Sends the handshake it received.
+async fn start_websocket_echo_upstream(
+) -> (std::net::SocketAddr, tokio::sync::oneshot::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        let mut handshake = String::new();
+        while !handshake.ends_with("\r\n\r\n") {
+            if stream.read_line(&mut handshake).await.unwrap() == 0 {
+                return;
+            }
+        }
+        let _ = tx.send(handshake);
+        stream
+            .get_mut()
+            .write_all(
+                b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                  Connection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        // FIN + text opcode, then a masked payload under 126 bytes.
+        let mut header = [0u8; 6];
+        stream.read_exact(&mut header).await.unwrap();
+        let len = (header[1] & 0x7f) as usize;
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).await.unwrap();
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= header[2 + i % 4];
+        }
+        let mut frame = vec![0x81, len as u8];
+        frame.extend_from_slice(&payload);
+        stream.get_mut().write_all(&frame).await.unwrap();
+    });
+    (addr, rx)
+}
+
+#[tokio::test]
+async fn test_websocket_upgrade_tunnels_frames() {
+    setup();
+
+    let (upstream, rx) = start_websocket_echo_upstream().await;
+    let proxy = common::start_proxy().await;
+
+    let mut stream = BufReader::new(TcpStream::connect(proxy).await.unwrap());
+    let request = format!(
+        "GET http://{}/chat?room=1 HTTP/1.1\r\nHost: {}\r\nConnection: keep-alive, Upgrade\r\n\
+         Upgrade: websocket\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+         Sec-WebSocket-Version: 13\r\n\r\n",
+        upstream, upstream
+    );
+    stream
+        .get_mut()
+        .write_all(request.as_bytes())
+        .await
+        .unwrap();
+
+    let mut head = String::new();
+    while !head.ends_with("\r\n\r\n") {
+        assert_ne!(
+            stream.read_line(&mut head).await.unwrap(),
+            0,
+            "Got: {}",
+            head
+        );
+    }
+    assert!(
+        head.starts_with("HTTP/1.1 101 "),
+        "Expected the upstream's 101, got: {}",
+        head
+    );
+
+    let handshake = rx.await.unwrap();
+    assert!(
+        handshake.starts_with("GET /chat?room=1 HTTP/1.1\r\n"),
+        "Expected an origin-form request, got: {}",
+        handshake
+    );
+    let lower = handshake.to_ascii_lowercase();
+    for expected in [
+        "connection: upgrade\r\n",
+        "upgrade: websocket\r\n",
+        "sec-websocket-key: dghlihnhbxbszsbub25jzq==\r\n",
+    ] {
+        assert!(
+            lower.contains(expected),
+            "Missing {:?} in: {}",
+            expected,
+            handshake
+        );
+    }
+
+    let mask = [1u8, 2, 3, 4];
+    let mut frame = vec![0x81, 0x80 | 5];
+    frame.extend_from_slice(&mask);
+    frame.extend(b"hello".iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    stream.get_mut().write_all(&frame).await.unwrap();
+
+    let mut echoed = [0u8; 7];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut echoed))
+        .await
+        .expect("The echoed frame should come back through the tunnel")
+        .unwrap();
+    assert_eq!(&echoed, b"\x81\x05hello");
+}
+
+const HOST_TEST_RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK";
+
+/// Send a GET for `upstream` carrying `host` as its Host header, returning
+/// the client's response and the Host lines the upstream saw.
+async fn send_with_host(
+    upstream: std::net::SocketAddr,
+    rx: tokio::sync::oneshot::Receiver<Vec<String>>,
+    host: &str,
+    config: ProxyConfig,
+) -> (String, Vec<String>) {
+    let proxy = common::start_proxy_with_config(config).await;
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, host
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+    let hosts = match tokio::time::timeout(Duration::from_millis(500), rx).await {
+        Ok(Ok(headers)) => headers
+            .into_iter()
+            .filter(|h| h.starts_with("host:"))
+            .collect(),
+        _ => Vec::new(),
+    };
+    (response, hosts)
+}
+
+#[tokio::test]
+async fn test_matching_host_passes_through() {
+    setup();
+
+    let (upstream, rx) = start_header_capturing_upstream(HOST_TEST_RESPONSE).await;
+    let (response, hosts) = send_with_host(
+        upstream,
+        rx,
+        &upstream.to_string(),
+        ProxyConfig {
+            reject_host_mismatch: true,
+            ..Default::default()
+        },
+    )
+    .await;
+
+    assert!(response.contains("200 OK"), "Got: {}", response);
+    assert_eq!(hosts, [format!("host: {}", upstream)]);
+}
+
+#[tokio::test]
+async fn test_mismatched_host_overridden_with_target() {
+    setup();
+
+    let (upstream, rx) = start_header_capturing_upstream(HOST_TEST_RESPONSE).await;
+    let (response, hosts) =
+        send_with_host(upstream, rx, "evil.example", ProxyConfig::default()).await;
+
+    assert!(response.contains("200 OK"), "Got: {}", response);
+    assert_eq!(
+        hosts,
+        [format!("host: {}", upstream)],
+        "The upstream should see the target's authority, not the client's Host"
+    );
+}
+
+#[tokio::test]
+async fn test_mismatched_host_rejected_with_400() {
+    setup();
+
+    let (upstream, rx) = start_header_capturing_upstream(HOST_TEST_RESPONSE).await;
+    let (response, hosts) = send_with_host(
+        upstream,
+        rx,
+        "evil.example",
+        ProxyConfig {
+            reject_host_mismatch: true,
+            ..Default::default()
+        },
+    )
+    .await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request"),
+        "Expected 400 for a conflicting Host, got: {}",
+        response
+    );
+    assert!(hosts.is_empty(), "The upstream must not be contacted");
+}
+
+const LARGE_BODY_LEN: usize = 4 * 1024 * 1024;
+
+fn large_body() -> Vec<u8> {
+    (0..LARGE_BODY_LEN).map(|i| (i % 253) as u8).collect()
+}
+
+/// Upstream that serves `large_body()` to one client.
+async fn start_large_upstream() -> std::net::SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let _ = common::read_upstream_body(&mut reader).await;
+
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+            LARGE_BODY_LEN
+        );
+        writer.write_all(head.as_bytes()).await.unwrap();
+        // The proxy may stop reading if its client goes away.
+        let _ = writer.write_all(&large_body()).await;
+    });
+
+    addr
+}
+
+#[tokio::test]
+async fn test_http_large_response_streamed_intact() {
+    setup();
+
+    let upstream = start_large_upstream().await;
+    let proxy = common::start_proxy().await;
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let request = format!(
+        "GET http://{}/big HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    stream.shutdown().await.unwrap();
+
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), stream.read_to_end(&mut response))
+        .await
+        .expect("download timed out")
+        .unwrap();
+
+    let header_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .expect("response should have a header terminator")
+        + 4;
+    assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    assert_eq!(response.len() - header_end, LARGE_BODY_LEN);
+    assert!(
+        response[header_end..] == large_body()[..],
+        "Body bytes differ"
+    );
+}
+
+/// Accepts writes until `limit` bytes, then fails like a closed socket.
+struct DisconnectingWriter {
+    written: usize,
+    limit: usize,
+}
+
+impl tokio::io::AsyncWrite for DisconnectingWriter {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        if self.written >= self.limit {
+            return std::task::Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+        }
+        let n = buf.len().min(self.limit - self.written);
+        self.written += n;
+        std::task::Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn test_http_client_disconnect_mid_stream_is_not_an_error() {
+    setup();
+
+    let upstream = start_large_upstream().await;
+    let request = format!(
+        "GET http://{}/big HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let mut reader = BufReader::new(std::io::Cursor::new(request.into_bytes()));
+    let mut writer = DisconnectingWriter {
+        written: 0,
+        limit: 256 * 1024,
+    };
+
+    let result = tokio::time::timeout(
+        Duration::from_secs(10),
+        rhoxy::handle_connection(&mut writer, &mut reader, None, &ProxyConfig::default()),
+    )
+    .await
+    .expect("handler should stop once the client is gone");
+
+    assert!(
+        result.is_ok(),
+        "Client disconnect should not be an error: {:?}",
+        result
+    );
+    assert_eq!(writer.written, 256 * 1024);
+}
+
+#[tokio::test]
+async fn test_http_identical_duplicate_content_length_accepted() {
+    setup();
+
+    let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream_listener.local_addr().unwrap();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+
+    tokio::spawn(async move {
+        let (stream, _) = upstream_listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let body = common::read_upstream_body(&mut reader).await;
+        let _ = tx.send(body);
+        writer
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK")
+            .await
+            .unwrap();
+    });
+
+    let proxy = common::start_proxy().await;
+    let request = format!(
+        "POST http://{}/ HTTP/1.1\r\nHost: {}\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nhello",
+        upstream_addr, upstream_addr
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.contains("200 OK"),
+        "Expected 200 OK, got: {}",
+        response
+    );
+    assert_eq!(rx.await.unwrap(), b"hello");
+}
+
+#[tokio::test]
+async fn test_http_nonstandard_status_gets_reason_phrase() {
+    setup();
+
+    let upstream = common::start_upstream(b"HTTP/1.1 599 \r\nContent-Length: 0\r\n\r\n").await;
+    let proxy = common::start_proxy().await;
+
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 599 Unknown\r\n"),
+        "Expected a non-empty reason for 599, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_http_unusual_status_codes_pass_through() {
+    setup();
+
+    for (upstream_response, expected) in [
+        (
+            &b"HTTP/1.1 299 \r\nContent-Length: 0\r\n\r\n"[..],
+            "HTTP/1.1 299 Unknown\r\n",
+        ),
+        (
+            b"HTTP/1.1 420 Enhance Your Calm\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 420 Enhance Your Calm\r\n",
+        ),
+    ] {
+        let upstream = common::start_upstream(upstream_response).await;
+        let proxy = common::start_proxy().await;
+
+        let request = format!(
+            "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+            upstream, upstream
+        );
+        let response = common::send_raw(proxy, request.as_bytes()).await;
+        assert!(
+            response.starts_with(expected),
+            "Expected {:?}, got: {}",
+            expected,
+            response
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_http_status_line_is_http_1_1_whatever_the_upstream_speaks() {
+    setup();
+
+    for config in [
+        ProxyConfig::default(),
+        ProxyConfig {
+            upstream_http2: true,
+            ..Default::default()
+        },
+    ] {
+        let upstream =
+            common::start_upstream(b"HTTP/1.0 200 OK\r\nContent-Length: 5\r\n\r\nhello").await;
+        let proxy = common::start_proxy_with_config(config).await;
+
+        let request = format!(
+            "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+            upstream, upstream
+        );
+        let response = common::send_raw(proxy, request.as_bytes()).await;
+
+        assert!(
+            response.starts_with("HTTP/1.1 200 OK\r\n") && response.ends_with("hello"),
+            "Expected an HTTP/1.1 status line, got: {}",
+            response
+        );
+    }
+}
+
+/// A canned 302 to `target`, leaked so it can be served by `start_upstream`.
+fn redirect_to(target: std::net::SocketAddr) -> &'static [u8] {
+    let response = format!(
+        "HTTP/1.1 302 Found\r\nLocation: http://{}/moved\r\nContent-Length: 0\r\n\r\n",
+        target
+    );
+    Box::leak(response.into_bytes().into_boxed_slice())
+}
+
+#[tokio::test]
+async fn test_http_redirect_passed_through_by_default() {
+    setup();
+
+    let target = common::start_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nmoved").await;
+    let upstream = common::start_upstream(redirect_to(target)).await;
+    let proxy = common::start_proxy().await;
+
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 302 Found\r\n"),
+        "Expected the 302 to reach the client, got: {}",
+        response
+    );
+    assert!(response.contains(&format!("http://{}/moved", target)));
+}
+
+#[tokio::test]
+async fn test_http_follow_redirects_fetches_target() {
+    setup();
+
+    let target = common::start_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nmoved").await;
+    let upstream = common::start_upstream(redirect_to(target)).await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        follow_redirects: 1,
+        ..Default::default()
+    })
+    .await;
+
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK\r\n") && response.ends_with("moved"),
+        "Expected the redirect to be followed, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_http_follow_redirects_stops_at_limit() {
+    setup();
+
+    let target = common::start_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nmoved").await;
+    let hop = common::start_upstream(redirect_to(target)).await;
+    let upstream = common::start_upstream(redirect_to(hop)).await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        follow_redirects: 1,
+        ..Default::default()
+    })
+    .await;
+
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 302 Found\r\n"),
+        "Expected the second redirect to be relayed, got: {}",
+        response
+    );
+    assert!(response.contains(&format!("http://{}/moved", target)));
+}
+
+#[tokio::test]
+async fn test_http_upstream_reason_phrase_preserved() {
+    setup();
+
+    let upstream = common::start_upstream(
+        b"HTTP/1.1 599 Network Connect Timeout\r\nContent-Length: 0\r\n\r\n",
+    )
+    .await;
+    let proxy = common::start_proxy().await;
+
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 599 Network Connect Timeout\r\n"),
+        "Expected upstream reason phrase, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_http_expect_continue_gets_interim_response() {
+    setup();
+
+    let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream_listener.local_addr().unwrap();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+
+    tokio::spawn(async move {
+        let (stream, _) = upstream_listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+
+        let mut head = String::new();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            head.push_str(&line);
+            if line.trim().is_empty() {
+                break;
+            }
+        }
+        let mut body = vec![0u8; 5];
+        reader.read_exact(&mut body).await.unwrap();
+        let _ = tx.send((head, body));
+        writer
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK")
+            .await
+            .unwrap();
+    });
+
+    let proxy = common::start_proxy().await;
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let request = format!(
+        "POST http://{}/ HTTP/1.1\r\nHost: {}\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n",
+        upstream_addr, upstream_addr
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    // Like a real client, hold the body until the proxy says to continue.
+    let mut interim = vec![0u8; b"HTTP/1.1 100 Continue\r\n\r\n".len()];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut interim))
+        .await
+        .expect("Timed out waiting for 100 Continue")
+        .unwrap();
+    assert_eq!(interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+    stream.write_all(b"hello").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("Timed out reading final response")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response);
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK"),
+        "Expected final 200 after the interim response, got: {}",
+        response
+    );
+
+    let (head, body) = rx.await.unwrap();
+    assert_eq!(body, b"hello");
+    assert!(
+        !head.to_lowercase().contains("expect:"),
+        "Expect must not be forwarded upstream, got: {}",
+        head
+    );
+}
+
+#[tokio::test]
+async fn test_http_post_with_body() {
+    setup();
+
+    let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream_listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (stream, _) = upstream_listener.accept().await.unwrap();
+        let (reader, writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+
+        let body = common::read_upstream_body(&mut reader).await;
+
+        let resp_body = format!("received {} bytes", body.len());
+        let resp = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            resp_body.len(),
+            resp_body
+        );
+        writer.write_all(resp.as_bytes()).await.unwrap();
+        writer.flush().await.unwrap();
+    });
+
+    let proxy = common::start_proxy().await;
+    let body = "test body payload";
+    let request = format!(
+        "POST http://{}/submit HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{}",
+        upstream_addr,
+        upstream_addr,
+        body.len(),
+        body
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.contains("200 OK"),
+        "Expected 200 OK, got: {}",
+        response
+    );
+    let expected = format!("received {} bytes", body.len());
+    assert!(
+        response.contains(&expected),
+        "Expected upstream to receive body, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_http_response_headers_forwarded() {
+    setup();
+
+    let upstream = common::start_upstream(
+        b"HTTP/1.1 200 OK\r\nX-Custom: test-value\r\nContent-Length: 2\r\n\r\nOK",
+    )
+    .await;
+    let proxy = common::start_proxy().await;
+
+    let request = format!(
+        "GET http://{}/path HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.contains("x-custom: test-value") || response.contains("X-Custom: test-value"),
+        "Expected X-Custom header in response, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_http_hop_by_hop_headers_stripped_and_others_forwarded() {
+    setup();
+
+    let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream_listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = upstream_listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 8192];
+        let n = stream.read(&mut buf).await.unwrap();
+        let received = String::from_utf8_lossy(&buf[..n]);
+
+        let has_proxy_auth = received
+            .lines()
+            .any(|l| l.to_lowercase().starts_with("proxy-authorization:"));
+        let has_x_keep = received
+            .lines()
+            .any(|l| l.to_lowercase().starts_with("x-keep:"));
+
+        let body = match (has_proxy_auth, has_x_keep) {
+            (false, true) => "STRIPPED_AND_KEPT",
+            (true, _) => "LEAKED",
+            (false, false) => "OVER_STRIPPED",
+        };
+
+        let resp = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(resp.as_bytes()).await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    let proxy = common::start_proxy().await;
+    let request = format!(
+        "GET http://{}/path HTTP/1.1\r\nHost: {}\r\nProxy-Authorization: Basic secret\r\nX-Keep: yes\r\n\r\n",
+        upstream_addr, upstream_addr
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.contains("200 OK"),
+        "Expected 200 OK, got: {}",
+        response
+    );
+    assert!(
+        response.contains("STRIPPED_AND_KEPT"),
+        "Expected hop-by-hop stripped and non-hop-by-hop kept, got: {}",
+        response
+    );
+}
+
+// ---------------------------------------------------------------------------
+// Health check non-interception for absolute URLs
+// ---------------------------------------------------------------------------
+
+#[tokio::test]
+async fn test_health_check_not_intercepted_for_absolute_url() {
+    setup();
+
+    let upstream =
+        common::start_upstream(b"HTTP/1.1 418 I'm a Teapot\r\nContent-Length: 6\r\n\r\nteapot")
+            .await;
+    let proxy = common::start_proxy().await;
+
+    let request = format!(
+        "GET http://{}/health HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.contains("418"),
+        "Absolute /health should be forwarded to upstream, got: {}",
+        response
+    );
+}
+
+// ---------------------------------------------------------------------------
+// 502 Bad Gateway — deterministic (closed port)
+// ---------------------------------------------------------------------------
+
+#[tokio::test]
+async fn test_http_502_on_closed_port() {
+    setup();
+
+    // Bind and immediately drop to get a port guaranteed not listening.
+    let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dead_addr = dead.local_addr().unwrap();
+    drop(dead);
+
+    let proxy = common::start_proxy().await;
+    let request = format!(
+        "GET http://{}/path HTTP/1.1\r\nHost: {}\r\n\r\n",
+        dead_addr, dead_addr
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.contains("502 Bad Gateway"),
+        "Expected 502 for closed port, got: {}",
+        response
+    );
+}
+
+/// Upstream that sends `103 Early Hints`, then the final response once
+/// `release` fires, so a test can see the hint arrive on its own.
+async fn start_early_hints_upstream(
+    release: tokio::sync::oneshot::Receiver<()>,
+) -> std::net::SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 8192];
+        let _ = stream.read(&mut buf).await;
+        stream
+            .write_all(b"HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload\r\n\r\n")
+            .await
+            .unwrap();
+        let _ = release.await;
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello")
+            .await
+            .unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    addr
+}
+
+#[tokio::test]
+async fn test_early_hints_relayed_before_final_response() {
+    setup();
+
+    let (release, released) = tokio::sync::oneshot::channel();
+    let upstream = start_early_hints_upstream(released).await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        early_hints: true,
+        ..Default::default()
+    })
+    .await;
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let request = format!(
+        "GET http://{}/page HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    // The upstream holds its final response back, so the hint can only have
+    // come through ahead of it.
+    let mut hint = vec![0u8; 512];
+    let mut read = 0;
+    while !hint[..read].ends_with(b"\r\n\r\n") {
+        let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut hint[read..]))
+            .await
+            .expect("Timed out waiting for the early hint")
+            .unwrap();
+        assert!(n > 0, "Connection closed before the early hint");
+        read += n;
+    }
+    let hint = String::from_utf8_lossy(&hint[..read]).into_owned();
+    assert_eq!(
+        hint,
+        "HTTP/1.1 103 Early Hints\r\nlink: </style.css>; rel=preload\r\n\r\n"
+    );
+
+    release.send(()).unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut response))
+        .await
+        .expect("Timed out reading the final response")
+        .unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK\r\n") && response.ends_with("hello"),
+        "Expected the final response after the hint, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_early_hints_dropped_by_default() {
+    setup();
+
+    let upstream = common::start_upstream(
+        b"HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload\r\n\r\n\
+          HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello",
+    )
+    .await;
+    let proxy = common::start_proxy().await;
+
+    let request = format!(
+        "GET http://{}/page HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK\r\n") && !response.contains("103"),
+        "Expected only the final response, got: {}",
+        response
+    );
+}
+
+/// Split a response into its head and body, checking that Content-Length
+/// matches the body.
+fn split_sized_response(response: &str) -> (&str, &str) {
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let length: usize = head
+        .lines()
+        .find_map(|line| line.strip_prefix("Content-Length: "))
+        .unwrap_or_else(|| panic!("No Content-Length in: {}", head))
+        .parse()
+        .unwrap();
+    assert_eq!(
+        length,
+        body.len(),
+        "Content-Length mismatch in: {}",
+        response
+    );
+    (head, body)
+}
+
+#[tokio::test]
+async fn test_http_502_explains_itself() {
+    setup();
+
+    let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dead_addr = dead.local_addr().unwrap();
+    drop(dead);
+    let proxy = common::start_proxy().await;
+
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        dead_addr, dead_addr
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+    let (head, body) = split_sized_response(&response);
+    assert!(
+        head.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+        "Got: {}",
+        head
+    );
+    assert!(head.contains("Content-Type: text/plain; charset=utf-8"));
+    assert_eq!(body, "502 Bad Gateway: The upstream could not be reached\n");
+
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\nAccept: text/html;q=0.9, application/json\r\n\r\n",
+        dead_addr, dead_addr
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+    let (head, body) = split_sized_response(&response);
+    assert!(
+        head.contains("Content-Type: application/problem+json\r\n"),
+        "Got: {}",
+        head
+    );
+    let problem: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(problem["type"], "about:blank");
+    assert_eq!(problem["title"], "Bad Gateway");
+    assert_eq!(problem["status"], 502);
+    assert_eq!(problem["detail"], "The upstream could not be reached");
+}
+
+#[tokio::test]
+async fn test_connect_502_on_closed_port() {
+    setup();
+
+    let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dead_addr = dead.local_addr().unwrap();
+    drop(dead);
+
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        connect_allowed_ports: any_connect_port(),
+        ..Default::default()
+    })
+    .await;
+    let request = format!(
+        "CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n",
+        dead_addr, dead_addr
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.contains("502 Bad Gateway"),
+        "Expected 502 for CONNECT to closed port, got: {}",
+        response
+    );
+}
+
+// ---------------------------------------------------------------------------
+// CONNECT tunnel — bidirectional data
+// ---------------------------------------------------------------------------
+
+#[tokio::test]
+async fn test_connect_tunnel_bidirectional() {
+    setup();
+
+    // Start a TCP echo server: reads data, sends it back prefixed with "echo:".
+    let echo_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo_listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = echo_listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        let mut response = b"echo:".to_vec();
+        response.extend_from_slice(&buf[..n]);
+        stream.write_all(&response).await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        connect_allowed_ports: any_connect_port(),
+        ..Default::default()
+    })
+    .await;
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+
+    // Phase 1: Send CONNECT request
+    let connect_req = format!(
+        "CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n",
+        echo_addr, echo_addr
+    );
+    stream.write_all(connect_req.as_bytes()).await.unwrap();
+
+    // Phase 2: Read "200 Connection Established\r\n\r\n"
+    let mut buf = vec![0u8; 256];
+    let mut total = 0;
+    loop {
+        let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf[total..]))
+            .await
+            .expect("Timed out waiting for CONNECT response")
+            .expect("Failed to read CONNECT response");
+        assert!(n > 0, "Connection closed before tunnel established");
+        total += n;
+        if buf[..total].windows(4).any(|w| w == b"\r\n\r\n") {
+            break;
+        }
+    }
+    let establishment = String::from_utf8_lossy(&buf[..total]);
+    assert!(
+        establishment.contains("200 Connection Established"),
+        "Expected tunnel established, got: {}",
+        establishment
+    );
+
+    // Phase 3: Send data through the tunnel
+    stream.write_all(b"hello tunnel").await.unwrap();
+    stream.shutdown().await.unwrap();
+
+    // Phase 4: Read echo response through the tunnel
+    let mut tunnel_response = Vec::new();
+    tokio::time::timeout(
+        Duration::from_secs(5),
+        stream.read_to_end(&mut tunnel_response),
+    )
+    .await
+    .expect("Timed out reading tunnel data")
+    .expect("Failed to read tunnel data");
+
+    let tunnel_str = String::from_utf8_lossy(&tunnel_response);
+    assert!(
+        tunnel_str.contains("echo:hello tunnel"),
+        "Expected echo response through tunnel, got: {}",
+        tunnel_str
+    );
+}
+
+#[tokio::test]
+async fn test_connect_tunnel_with_sni_logging_replays_client_hello() {
+    setup();
+
+    let client_hello = rhoxy::protocol::sni::build_client_hello("tunnel.example.com");
+    let mut sent = client_hello.clone();
+    sent.extend_from_slice(b"encrypted payload");
+
+    // Upstream reads exactly what the client sends and echoes it back.
+    let echo_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo_listener.local_addr().unwrap();
+    let expected_len = sent.len();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = echo_listener.accept().await.unwrap();
+        let mut received = vec![0u8; expected_len];
+        stream.read_exact(&mut received).await.unwrap();
+        stream.write_all(&received).await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        log_tls_sni: true,
+        connect_allowed_ports: any_connect_port(),
+        ..Default::default()
+    })
+    .await;
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+
+    let connect_req = format!(
+        "CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n",
+        echo_addr, echo_addr
+    );
+    stream.write_all(connect_req.as_bytes()).await.unwrap();
+
+    let mut buf = vec![0u8; 256];
+    let mut total = 0;
+    loop {
+        let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf[total..]))
+            .await
+            .expect("Timed out waiting for CONNECT response")
+            .expect("Failed to read CONNECT response");
+        assert!(n > 0, "Connection closed before tunnel established");
+        total += n;
+        if buf[..total].windows(4).any(|w| w == b"\r\n\r\n") {
+            break;
+        }
+    }
+    assert!(String::from_utf8_lossy(&buf[..total]).contains("200 Connection Established"));
+
+    stream.write_all(&sent).await.unwrap();
+    stream.shutdown().await.unwrap();
+
+    let mut echoed = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut echoed))
+        .await
+        .expect("Timed out reading tunnel data")
+        .expect("Failed to read tunnel data");
+
+    assert_eq!(
+        echoed, sent,
+        "Peeked ClientHello must be replayed to the upstream byte-for-byte"
+    );
+    assert_eq!(
+        rhoxy::protocol::sni::parse_sni(&client_hello).as_deref(),
+        Some("tunnel.example.com")
+    );
+}
+
+#[tokio::test]
+async fn test_connect_tunnel_with_sni_logging_relays_server_first_protocol() {
+    setup();
+
+    // Upstream greets first, like SSH or SMTP, then echoes the client's reply.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.write_all(b"SSH-2.0-upstream\r\n").await.unwrap();
+        let mut reply = vec![0u8; 14];
+        stream.read_exact(&mut reply).await.unwrap();
+        stream.write_all(&reply).await.unwrap();
+    });
+
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        log_tls_sni: true,
+        connect_allowed_ports: any_connect_port(),
+        ..Default::default()
+    })
+    .await;
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let connect_req = format!(
+        "CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream_addr, upstream_addr
+    );
+    stream.write_all(connect_req.as_bytes()).await.unwrap();
+
+    // The banner must arrive well before the ClientHello wait would give up.
+    let mut received = Vec::new();
+    let mut buf = [0u8; 256];
+    while !received.ends_with(b"SSH-2.0-upstream\r\n") {
+        let n = tokio::time::timeout(Duration::from_secs(1), stream.read(&mut buf))
+            .await
+            .expect("Server greeting was held back while waiting for a ClientHello")
+            .unwrap();
+        assert!(n > 0, "Tunnel closed early: {:?}", received);
+        received.extend_from_slice(&buf[..n]);
+    }
+
+    stream.write_all(b"SSH-2.0-client").await.unwrap();
+    let mut echoed = [0u8; 14];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut echoed))
+        .await
+        .expect("Timed out reading echo")
+        .unwrap();
+    assert_eq!(&echoed, b"SSH-2.0-client");
+}
+
+// ---------------------------------------------------------------------------
+// Multiple requests through same proxy
+// ---------------------------------------------------------------------------
+
+#[tokio::test]
+async fn test_proxy_handles_multiple_sequential_requests() {
+    setup();
+
+    let proxy = common::start_proxy().await;
+
+    for i in 0..3 {
+        let upstream =
+            common::start_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK").await;
+
+        let request = format!(
+            "GET http://{}/path/{} HTTP/1.1\r\nHost: {}\r\n\r\n",
+            upstream, i, upstream
+        );
+        let response = common::send_raw(proxy, request.as_bytes()).await;
+        assert!(
+            response.contains("200 OK"),
+            "Request {} failed, got: {}",
+            i,
+            response
+        );
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Concurrent requests
+// ---------------------------------------------------------------------------
+
+#[tokio::test]
+async fn test_proxy_handles_concurrent_requests() {
+    setup();
+
+    let upstream =
+        common::start_looping_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK").await;
+    let proxy = common::start_proxy().await;
+
+    let mut handles = Vec::new();
+    for i in 0..10 {
+        let request = format!(
+            "GET http://{}/path/{} HTTP/1.1\r\nHost: {}\r\n\r\n",
+            upstream, i, upstream
+        );
+        let request_bytes = request.into_bytes();
+        handles.push(tokio::spawn(async move {
+            common::send_raw(proxy, &request_bytes).await
+        }));
+    }
+
+    for (i, handle) in handles.into_iter().enumerate() {
+        let response = handle.await.expect("Task panicked");
+        assert!(
+            response.contains("200 OK"),
+            "Concurrent request {} failed, got: {}",
+            i,
+            response
+        );
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Large body forwarding
+// ---------------------------------------------------------------------------
+
+#[tokio::test]
+async fn test_http_post_large_body() {
+    setup();
+
+    let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream_listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (stream, _) = upstream_listener.accept().await.unwrap();
+        let (reader, writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+
+        let body = common::read_upstream_body(&mut reader).await;
+
+        let resp_body = format!("received {} bytes", body.len());
+        let resp = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            resp_body.len(),
+            resp_body
+        );
+        writer.write_all(resp.as_bytes()).await.unwrap();
+        writer.flush().await.unwrap();
+    });
+
+    let proxy = common::start_proxy().await;
+    let body_size = 128 * 1024; // 128 KiB
+    let body = "X".repeat(body_size);
+    let request = format!(
+        "POST http://{}/submit HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{}",
+        upstream_addr,
+        upstream_addr,
+        body.len(),
+        body
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.contains("200 OK"),
+        "Expected 200 OK, got: {}",
+        response
+    );
+    let expected = format!("received {} bytes", body_size);
+    assert!(
+        response.contains(&expected),
+        "Expected upstream to receive full body, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_http_post_body_spilled_to_disk() {
+    setup();
+
+    let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream_listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (stream, _) = upstream_listener.accept().await.unwrap();
+        let (reader, writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+
+        let body = common::read_upstream_body(&mut reader).await;
+        let intact = body.iter().enumerate().all(|(i, &b)| b == (i % 251) as u8);
+
+        let resp_body = format!("received {} bytes intact={}", body.len(), intact);
+        let resp = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            resp_body.len(),
+            resp_body
+        );
+        writer.write_all(resp.as_bytes()).await.unwrap();
+        writer.flush().await.unwrap();
+    });
+
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        spill_to_disk_threshold: Some(4096),
+        ..Default::default()
+    })
+    .await;
+
+    let body: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+    let mut request = format!(
+        "POST http://{}/upload HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n",
+        upstream_addr,
+        upstream_addr,
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(&body);
+    let response = common::send_raw(proxy, &request).await;
+
+    assert!(
+        response.contains("200 OK"),
+        "Expected 200 OK, got: {}",
+        response
+    );
+    let expected = format!("received {} bytes intact=true", body.len());
+    assert!(
+        response.contains(&expected),
+        "Expected spilled body forwarded intact, got: {}",
+        response
+    );
+}
+
+// ---------------------------------------------------------------------------
+// Chunked transfer encoding
+// ---------------------------------------------------------------------------
+
+#[tokio::test]
+async fn test_http_post_chunked_transfer_encoding() {
+    setup();
+
+    let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream_listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (stream, _) = upstream_listener.accept().await.unwrap();
+        let (reader, writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+
+        let body = common::read_upstream_body(&mut reader).await;
+
+        let body_str = String::from_utf8_lossy(&body);
+        let resp_body = format!("body={}", body_str);
+        let resp = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            resp_body.len(),
+            resp_body
+        );
+        writer.write_all(resp.as_bytes()).await.unwrap();
+        writer.flush().await.unwrap();
+    });
+
+    let proxy = common::start_proxy().await;
+    // Chunked: "Hello" (5 bytes) + " World!" (7 bytes) = "Hello World!" (12 bytes)
+    let request = format!(
+        "POST http://{}/submit HTTP/1.1\r\nHost: {}\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nHello\r\n7\r\n World!\r\n0\r\n\r\n",
+        upstream_addr, upstream_addr
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.contains("200 OK"),
+        "Expected 200 OK, got: {}",
+        response
+    );
+    assert!(
+        response.contains("body=Hello World!"),
+        "Expected chunked body reassembled as 'Hello World!', got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_chunked_body_forwarded_with_computed_content_length() {
+    setup();
+
+    let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream_listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (stream, _) = upstream_listener.accept().await.unwrap();
+        let (reader, writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+            head.push_str(&line.to_lowercase());
+        }
+        let resp_body = head.replace("\r\n", "|");
+        let resp = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            resp_body.len(),
+            resp_body
+        );
+        writer.write_all(resp.as_bytes()).await.unwrap();
+        writer.flush().await.unwrap();
+    });
+
+    let proxy = common::start_proxy().await;
+    let request = format!(
+        "POST http://{}/submit HTTP/1.1\r\nHost: {}\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nHello\r\n7\r\n World!\r\n0\r\n\r\n",
+        upstream_addr, upstream_addr
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.contains("content-length: 12|"),
+        "Expected upstream to see the decoded length, got: {}",
+        response
+    );
+    assert!(
+        !response.contains("transfer-encoding"),
+        "Expected chunking headers to be dropped, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_content_length_with_chunking_is_rejected() {
+    setup();
+
+    // Nothing should reach the upstream; it is only here to be a valid target.
+    let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream_listener.local_addr().unwrap();
+
+    let proxy = common::start_proxy().await;
+    let request = format!(
+        "POST http://{}/submit HTTP/1.1\r\nHost: {}\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\nGET /smuggled HTTP/1.1\r\n\r\n",
+        upstream_addr, upstream_addr
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+        "Expected ambiguous framing to be refused, got: {}",
+        response
+    );
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), upstream_listener.accept())
+            .await
+            .is_err(),
+        "The upstream should never have been contacted"
+    );
+}
+
+/// Send CONNECT for `target` and consume the `200 Connection Established`.
+async fn open_tunnel(proxy: std::net::SocketAddr, target: std::net::SocketAddr) -> TcpStream {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let connect_req = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target);
+    stream.write_all(connect_req.as_bytes()).await.unwrap();
+
+    let mut buf = Vec::new();
+    let mut byte = [0u8; 1];
+    while !buf.ends_with(b"\r\n\r\n") {
+        let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut byte))
+            .await
+            .expect("Timed out waiting for CONNECT response")
+            .unwrap();
+        assert!(n > 0, "Connection closed before tunnel established");
+        buf.push(byte[0]);
+    }
+    assert!(
+        buf.starts_with(b"HTTP/1.1 200 Connection Established"),
+        "Expected tunnel established, got: {}",
+        String::from_utf8_lossy(&buf)
+    );
+    stream
+}
+
+/// Upstream that reports the address each connection came from and
+/// answers any request with `200 OK`.
+async fn start_peer_reporting_upstream() -> (
+    std::net::SocketAddr,
+    tokio::sync::mpsc::UnboundedReceiver<std::net::IpAddr>,
+) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, peer)) = listener.accept().await {
+            let _ = tx.send(peer.ip());
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK")
+                    .await;
+            });
+        }
+    });
+
+    (addr, rx)
+}
+
+#[tokio::test]
+async fn test_egress_bind_sets_upstream_source_address() {
+    setup();
+
+    // 127.0.0.2 is a second loopback address on Linux with no setup.
+    let source: std::net::IpAddr = "127.0.0.2".parse().unwrap();
+    let (upstream, mut peers) = start_peer_reporting_upstream().await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        egress_bind: Some(source),
+        connect_allowed_ports: any_connect_port(),
+        ..Default::default()
+    })
+    .await;
+
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+    assert!(response.contains("200 OK"), "Got: {}", response);
+    assert_eq!(peers.recv().await.unwrap(), source, "HTTP egress address");
+
+    let _tunnel = open_tunnel(proxy, upstream).await;
+    assert_eq!(
+        peers.recv().await.unwrap(),
+        source,
+        "CONNECT egress address"
+    );
+}
+
+#[tokio::test]
+async fn test_circuit_breaker_trips_on_dead_upstream_then_recovers() {
+    setup();
+
+    // Nothing listens here until the upstream "recovers" below.
+    let upstream = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        circuit_breaker: Some(std::sync::Arc::new(rhoxy::breaker::CircuitBreaker::new(
+            2,
+            Duration::from_secs(30),
+            Duration::from_millis(500),
+        ))),
+        ..Default::default()
+    })
+    .await;
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+
+    for _ in 0..2 {
+        let response = common::send_raw(proxy, request.as_bytes()).await;
+        assert!(
+            response.starts_with("HTTP/1.1 502"),
+            "Expected 502 while the upstream is down, got: {}",
+            response
+        );
+    }
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+    assert!(
+        response.starts_with("HTTP/1.1 503 Service Unavailable"),
+        "Expected a fast 503 once the breaker trips, got: {}",
+        response
+    );
+
+    let listener = TcpListener::bind(upstream).await.unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut buf = vec![0u8; 4096];
+            let _ = stream.read(&mut buf).await;
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK")
+                .await;
+        }
+    });
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+    assert!(
+        response.starts_with("HTTP/1.1 503"),
+        "The breaker should stay open for the cooldown, got: {}",
+        response
+    );
+
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    for _ in 0..2 {
+        let response = common::send_raw(proxy, request.as_bytes()).await;
+        assert!(
+            response.starts_with("HTTP/1.1 200 OK"),
+            "Expected the probe to close the breaker, got: {}",
+            response
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_circuit_breaker_refuses_tunnels_to_failing_host() {
+    setup();
+
+    let upstream = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        circuit_breaker: Some(std::sync::Arc::new(rhoxy::breaker::CircuitBreaker::new(
+            1,
+            Duration::from_secs(30),
+            Duration::from_secs(30),
+        ))),
+        connect_allowed_ports: any_connect_port(),
+        ..Default::default()
+    })
+    .await;
+    let request = format!(
+        "CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 502"), "Got: {}", response);
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+    assert!(
+        response.starts_with("HTTP/1.1 503 Service Unavailable"),
+        "Expected the tripped breaker to refuse the tunnel, got: {}",
+        response
+    );
+}
+
+/// Upstream that answers one request with the body it received.
+async fn start_body_echo_upstream() -> std::net::SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+
+        let mut content_length = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0u8; content_length];
+        reader.read_exact(&mut body).await.unwrap();
+
+        let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+        writer.write_all(head.as_bytes()).await.unwrap();
+        writer.write_all(&body).await.unwrap();
+    });
+
+    addr
+}
+
+#[tokio::test]
+async fn test_slow_body_within_idle_timeout_is_forwarded() {
+    setup();
+
+    let upstream = start_body_echo_upstream().await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        idle_timeout: Some(Duration::from_millis(300)),
+        ..Default::default()
+    })
+    .await;
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let head = format!(
+        "POST http://{}/upload HTTP/1.1\r\nHost: {}\r\nContent-Length: 8\r\n\r\n",
+        upstream, upstream
+    );
+    stream.write_all(head.as_bytes()).await.unwrap();
+    // Eight bytes 150ms apart take well over the 300ms idle timeout in
+    // total, but the client is never idle that long.
+    for byte in b"dripdrop" {
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        stream.write_all(&[*byte]).await.unwrap();
+    }
+
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("Timed out reading response")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response);
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK") && response.ends_with("dripdrop"),
+        "Expected the slow body to reach the upstream, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_idle_client_dropped_after_idle_timeout() {
+    setup();
+
+    let upstream = start_body_echo_upstream().await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        idle_timeout: Some(Duration::from_millis(300)),
+        ..Default::default()
+    })
+    .await;
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let request = format!(
+        "POST http://{}/upload HTTP/1.1\r\nHost: {}\r\nContent-Length: 8\r\n\r\ndrip",
+        upstream, upstream
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    // Stop halfway through the body; the proxy should give up on its own.
+    let started = std::time::Instant::now();
+    let mut response = Vec::new();
+    let _ = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("An idle client should be dropped by the proxy");
+    assert!(
+        response.is_empty(),
+        "An idle client should be dropped without a response, got: {}",
+        String::from_utf8_lossy(&response)
+    );
+    assert!(started.elapsed() >= Duration::from_millis(250));
+}
+
+#[tokio::test]
+async fn test_client_not_reading_response_dropped_after_idle_timeout() {
+    setup();
+
+    // Far more than the socket buffers between proxy and client hold.
+    const BODY_LEN: usize = 64 * 1024 * 1024;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut head = vec![0u8; 1024];
+        let _ = stream.read(&mut head).await;
+        let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", BODY_LEN);
+        stream.write_all(response.as_bytes()).await.unwrap();
+        let chunk = vec![b'x'; 64 * 1024];
+        for _ in 0..BODY_LEN / chunk.len() {
+            if stream.write_all(&chunk).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        idle_timeout: Some(Duration::from_millis(300)),
+        ..Default::default()
+    })
+    .await;
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let request = format!(
+        "GET http://{}/big HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    // Never read while the proxy is writing; it should give up on its own.
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let mut received = Vec::new();
+    let _ = tokio::time::timeout(Duration::from_secs(10), stream.read_to_end(&mut received))
+        .await
+        .expect("A client that stopped reading should have been dropped");
+    assert!(
+        received.len() < BODY_LEN,
+        "Expected the proxy to give up mid-body, got {} bytes",
+        received.len()
+    );
+}
+
+/// Echo server that keeps its connection open until the peer closes it.
+async fn start_echo_upstream() -> std::net::SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 1024];
+        loop {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if stream.write_all(&buf[..n]).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    addr
+}
+
+#[tokio::test]
+async fn test_connect_tunnel_closed_after_idle_timeout() {
+    setup();
+
+    let upstream = start_echo_upstream().await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        tunnel_idle_timeout: Some(Duration::from_millis(300)),
+        connect_allowed_ports: any_connect_port(),
+        ..Default::default()
+    })
+    .await;
+
+    let mut stream = open_tunnel(proxy, upstream).await;
+
+    // Stay silent; the proxy should close the tunnel on its own.
+    let mut buf = [0u8; 16];
+    let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+        .await
+        .expect("Idle tunnel should be closed by the proxy")
+        .unwrap_or(0);
+    assert_eq!(n, 0, "Expected EOF from idle tunnel");
+}
+
+#[tokio::test]
+async fn test_connect_tunnel_with_traffic_outlives_idle_timeout() {
+    setup();
+
+    let upstream = start_echo_upstream().await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        tunnel_idle_timeout: Some(Duration::from_millis(300)),
+        connect_allowed_ports: any_connect_port(),
+        ..Default::default()
+    })
+    .await;
+
+    let mut stream = open_tunnel(proxy, upstream).await;
+
+    // Six round trips 150ms apart keep the tunnel busy for well over the
+    // 300ms idle timeout.
+    for i in 0..6u8 {
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        stream.write_all(&[i]).await.unwrap();
+        let mut echoed = [0u8; 1];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut echoed))
+            .await
+            .expect("Timed out waiting for echo")
+            .expect("Active tunnel should stay open");
+        assert_eq!(echoed[0], i);
+    }
+}
+
+#[tokio::test]
+async fn test_large_tunnel_transfer_with_custom_buffer_size() {
+    setup();
+
+    let upstream = start_echo_upstream().await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        connect_allowed_ports: any_connect_port(),
+        // Odd-sized so copies never line up with the payload's chunks.
+        io_buffer_size: Some(1500),
+        ..Default::default()
+    })
+    .await;
+
+    let payload: Vec<u8> = (0..4 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    let (mut reader, mut writer) = open_tunnel(proxy, upstream).await.into_split();
+    let started = std::time::Instant::now();
+    let upload = {
+        let payload = payload.clone();
+        tokio::spawn(async move {
+            for chunk in payload.chunks(64 * 1024) {
+                writer.write_all(chunk).await.unwrap();
+            }
+            writer
+        })
+    };
+    let mut echoed = vec![0u8; payload.len()];
+    tokio::time::timeout(Duration::from_secs(20), reader.read_exact(&mut echoed))
+        .await
+        .expect("Timed out waiting for the echoed payload")
+        .unwrap();
+    upload.await.unwrap();
+
+    eprintln!(
+        "Echoed {} bytes through a 1500 byte buffer in {:?}",
+        payload.len(),
+        started.elapsed()
+    );
+    assert!(
+        echoed == payload,
+        "Echoed payload differs from what was sent"
+    );
+}
+
+#[tokio::test]
+async fn test_connect_default_port_443_allowed() {
+    setup();
+
+    let proxy = common::start_proxy().await;
+    let response = common::send_raw(
+        proxy,
+        b"CONNECT 127.0.0.1:443 HTTP/1.1\r\nHost: 127.0.0.1:443\r\n\r\n",
+    )
+    .await;
+
+    // Nothing listens on 443 here, so getting past the allowlist means a 502.
+    assert!(
+        !response.contains("403 Forbidden"),
+        "Expected port 443 to pass the allowlist, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_connect_custom_allowed_port() {
+    setup();
+
+    let upstream = start_echo_upstream().await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        connect_allowed_ports: upstream.port().to_string().parse().unwrap(),
+        ..Default::default()
+    })
+    .await;
+
+    let mut stream = open_tunnel(proxy, upstream).await;
+    stream.write_all(b"ping").await.unwrap();
+    let mut echoed = [0u8; 4];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut echoed))
+        .await
+        .expect("Timed out waiting for echo")
+        .unwrap();
+    assert_eq!(&echoed, b"ping");
+
+    // A custom list replaces the default, so 443 is no longer allowed.
+    let response = common::send_raw(
+        proxy,
+        b"CONNECT 127.0.0.1:443 HTTP/1.1\r\nHost: 127.0.0.1:443\r\n\r\n",
+    )
+    .await;
+    assert!(
+        response.contains("403 Forbidden"),
+        "Expected 403 for port outside the custom list, got: {}",
+        response
+    );
+}
+
+// ---------------------------------------------------------------------------
+// Upstream proxy chaining
+// ---------------------------------------------------------------------------
+
+/// What a mock upstream proxy saw: the request line and any
+/// Proxy-Authorization header.
+type ProxiedRequest = (String, Option<String>);
+
+/// Mock HTTP proxy for one connection. A CONNECT is answered with 200 and
+/// then echoed; any other request gets a fixed `via-proxy` response.
+async fn start_mock_upstream_proxy() -> (
+    std::net::SocketAddr,
+    tokio::sync::oneshot::Receiver<ProxiedRequest>,
+) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).await.unwrap();
+        let mut auth = None;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("proxy-authorization") {
+                    auth = Some(value.trim().to_string());
+                }
+            }
+        }
+        let is_connect = request_line.starts_with("CONNECT ");
+        let _ = tx.send((request_line.trim().to_string(), auth));
+
+        if is_connect {
+            writer
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await
+                .unwrap();
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+        } else {
+            writer
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nvia-proxy")
+                .await
+                .unwrap();
+        }
+    });
+
+    (addr, rx)
+}
+
+#[tokio::test]
+async fn test_http_forwarded_through_upstream_proxy() {
+    setup();
+
+    let (upstream_proxy, rx) = start_mock_upstream_proxy().await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        upstream_proxy: Some(
+            format!("http://bob:hunter2@{}", upstream_proxy)
+                .parse()
+                .unwrap(),
+        ),
+        ..Default::default()
+    })
+    .await;
+
+    // Nothing listens on the target; only the upstream proxy can answer.
+    let response = common::send_raw(
+        proxy,
+        b"GET http://127.0.0.1:9/path HTTP/1.1\r\nHost: 127.0.0.1:9\r\n\r\n",
+    )
+    .await;
+    assert!(
+        response.contains("200 OK") && response.ends_with("via-proxy"),
+        "Expected the upstream proxy's response, got: {}",
+        response
+    );
+
+    let (request_line, auth) = rx.await.unwrap();
+    assert_eq!(request_line, "GET http://127.0.0.1:9/path HTTP/1.1");
+    // base64("bob:hunter2")
+    assert_eq!(auth.as_deref(), Some("Basic Ym9iOmh1bnRlcjI="));
+}
+
+#[tokio::test]
+async fn test_connect_tunneled_through_upstream_proxy() {
+    setup();
+
+    let (upstream_proxy, rx) = start_mock_upstream_proxy().await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        upstream_proxy: Some(format!("http://{}", upstream_proxy).parse().unwrap()),
+        connect_allowed_ports: any_connect_port(),
+        ..Default::default()
+    })
+    .await;
+
+    let target: std::net::SocketAddr = "127.0.0.1:9".parse().unwrap();
+    let mut stream = open_tunnel(proxy, target).await;
+    stream.write_all(b"ping").await.unwrap();
+    let mut echoed = [0u8; 4];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut echoed))
+        .await
+        .expect("Timed out waiting for echo")
+        .unwrap();
+    assert_eq!(&echoed, b"ping");
+
+    let (request_line, auth) = rx.await.unwrap();
+    assert_eq!(request_line, "CONNECT 127.0.0.1:9 HTTP/1.1");
+    assert_eq!(auth, None);
+}
+
+#[tokio::test]
+async fn test_no_proxy_list_bypasses_upstream_proxy() {
+    setup();
+
+    let (upstream_proxy, rx) = start_mock_upstream_proxy().await;
+    let upstream =
+        common::start_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\ndirect").await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        upstream_proxy: Some(format!("http://{}", upstream_proxy).parse().unwrap()),
+        no_proxy: vec![
+            "localhost".parse().unwrap(),
+            ".internal.test".parse().unwrap(),
+        ],
+        ..Default::default()
+    })
+    .await;
+
+    let request = format!(
+        "GET http://localhost:{}/ HTTP/1.1\r\nHost: localhost:{}\r\n\r\n",
+        upstream.port(),
+        upstream.port()
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK") && response.ends_with("direct"),
+        "Expected a listed host to be reached directly, got: {}",
+        response
+    );
+
+    // Nothing listens on the target; only the upstream proxy can answer.
+    let response = common::send_raw(
+        proxy,
+        b"GET http://127.0.0.1:9/path HTTP/1.1\r\nHost: 127.0.0.1:9\r\n\r\n",
+    )
+    .await;
+    assert!(
+        response.contains("200 OK") && response.ends_with("via-proxy"),
+        "Expected an unlisted host to go through the upstream proxy, got: {}",
+        response
+    );
+    let (request_line, _) = rx.await.unwrap();
+    assert_eq!(request_line, "GET http://127.0.0.1:9/path HTTP/1.1");
+}
+
+// ---------------------------------------------------------------------------
+// Routing table
+// ---------------------------------------------------------------------------
+
+fn routes(rules: &str) -> rhoxy::routes::RouteTable {
+    rhoxy::routes::RouteTable::parse(rules).unwrap()
+}
+
+#[tokio::test]
+async fn test_route_direct_bypasses_upstream_proxy() {
+    setup();
+
+    let upstream =
+        common::start_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\ndirect").await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        // Nothing listens here, so only a direct connection can succeed.
+        upstream_proxy: Some("http://127.0.0.1:9".parse().unwrap()),
+        routes: routes("127.0.0.1 DIRECT\n"),
+        ..Default::default()
+    })
+    .await;
+
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK") && response.ends_with("direct"),
+        "Expected the upstream's own response, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_route_proxy_relays_through_named_proxy() {
+    setup();
+
+    let (upstream_proxy, rx) = start_mock_upstream_proxy().await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        routes: routes(&format!(
+            "other.test DIRECT\n127.0.0.0/8 PROXY http://{}\n",
+            upstream_proxy
+        )),
+        ..Default::default()
+    })
+    .await;
+
+    // Nothing listens on the target; only the upstream proxy can answer.
+    let response = common::send_raw(
+        proxy,
+        b"GET http://127.0.0.1:9/path HTTP/1.1\r\nHost: 127.0.0.1:9\r\n\r\n",
+    )
+    .await;
+    assert!(
+        response.contains("200 OK") && response.ends_with("via-proxy"),
+        "Expected the routed proxy's response, got: {}",
+        response
+    );
+    let (request_line, _) = rx.await.unwrap();
+    assert_eq!(request_line, "GET http://127.0.0.1:9/path HTTP/1.1");
+}
+
+#[tokio::test]
+async fn test_route_block_refuses_http_and_connect() {
+    setup();
+
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        routes: routes("*.blocked.test BLOCK\n* DIRECT\n"),
+        ..Default::default()
+    })
+    .await;
+
+    let response = common::send_raw(
+        proxy,
+        b"GET http://ads.blocked.test/ HTTP/1.1\r\nHost: ads.blocked.test\r\n\r\n",
+    )
+    .await;
+    assert!(
+        response.starts_with("HTTP/1.1 403 Forbidden"),
+        "Expected 403 for a blocked route, got: {}",
+        response
+    );
+    assert!(response.contains("The routing table blocks this target"));
+
+    let response = common::send_raw(
+        proxy,
+        b"CONNECT ads.blocked.test:443 HTTP/1.1\r\nHost: ads.blocked.test:443\r\n\r\n",
+    )
+    .await;
+    assert!(
+        response.starts_with("HTTP/1.1 403 Forbidden"),
+        "Expected 403 for a blocked tunnel, got: {}",
+        response
+    );
+}
+
+// ---------------------------------------------------------------------------
+// Status interception
+// ---------------------------------------------------------------------------
+
+#[tokio::test]
+async fn test_intercepted_status_gets_custom_body() {
+    setup();
+
+    let mut page = tempfile::Builder::new().suffix(".html").tempfile().unwrap();
+    std::io::Write::write_all(&mut page, b"<h1>Back soon</h1>").unwrap();
+    let rule: rhoxy::config::InterceptStatus =
+        format!("503:{}", page.path().display()).parse().unwrap();
+    let config = || ProxyConfig {
+        intercept_statuses: vec![rule.clone()],
+        ..Default::default()
+    };
+
+    let upstream = common::start_upstream(
+        b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 13\r\n\r\nupstream down",
+    )
+    .await;
+    let proxy = common::start_proxy_with_config(config()).await;
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+    assert!(
+        response.starts_with("HTTP/1.1 503 Service Unavailable")
+            && response.contains("content-type: text/html; charset=utf-8")
+            && response.ends_with("\r\n\r\n<h1>Back soon</h1>"),
+        "Expected the upstream 503 with the custom page, got: {}",
+        response
+    );
+
+    let upstream =
+        common::start_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").await;
+    let proxy = common::start_proxy_with_config(config()).await;
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK") && response.ends_with("hello"),
+        "Expected a 200 to pass through untouched, got: {}",
+        response
+    );
+}
+
+// ---------------------------------------------------------------------------
+// Response cache
+// ---------------------------------------------------------------------------
+
+/// Upstream answering every request with `response`, or with
+/// `not_modified` when the request carries `If-None-Match`. Counts the
+/// requests that reach it.
+async fn start_counting_upstream(
+    response: &'static [u8],
+    not_modified: &'static [u8],
+) -> (
+    std::net::SocketAddr,
+    std::sync::Arc<std::sync::atomic::AtomicUsize>,
+) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = requests.clone();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+            let mut conditional = false;
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if reader.read_line(&mut line).await.unwrap_or(0) == 0 || line.trim().is_empty() {
+                    break;
+                }
+                conditional |= line.to_ascii_lowercase().starts_with("if-none-match:");
+            }
+            let reply = if conditional { not_modified } else { response };
+            let _ = writer.write_all(reply).await;
+            let _ = writer.shutdown().await;
+        }
+    });
+
+    (addr, requests)
+}
+
+async fn start_caching_proxy() -> std::net::SocketAddr {
+    common::start_proxy_with_config(ProxyConfig {
+        cache: Some(std::sync::Arc::new(rhoxy::cache::ResponseCache::new(
+            1 << 20,
+        ))),
+        ..Default::default()
+    })
+    .await
+}
+
+async fn get_twice(proxy: std::net::SocketAddr, upstream: std::net::SocketAddr) -> [String; 2] {
+    let request = format!(
+        "GET http://{}/cached HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let first = common::send_raw(proxy, request.as_bytes()).await;
+    let second = common::send_raw(proxy, request.as_bytes()).await;
+    [first, second]
+}
+
+#[tokio::test]
+async fn test_cacheable_get_served_from_cache() {
+    setup();
+
+    let (upstream, requests) = start_counting_upstream(
+        b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+        b"",
+    )
+    .await;
+    let proxy = start_caching_proxy().await;
+
+    let [first, second] = get_twice(proxy, upstream).await;
     assert!(
-        establishment.contains("200 Connection Established"),
-        "Expected tunnel established, got: {}",
-        establishment
+        first.starts_with("HTTP/1.1 200 OK") && first.ends_with("hello"),
+        "Got: {}",
+        first
+    );
+    assert!(
+        second.starts_with("HTTP/1.1 200 OK") && second.ends_with("hello"),
+        "Got: {}",
+        second
+    );
+    assert!(
+        second.to_ascii_lowercase().contains("\r\nage: "),
+        "Expected an Age header on the cached response, got: {}",
+        second
+    );
+    assert_eq!(
+        requests.load(std::sync::atomic::Ordering::SeqCst),
+        1,
+        "Expected the second GET to be served from cache"
     );
+}
 
-    // Phase 3: Send data through the tunnel
-    stream.write_all(b"hello tunnel").await.unwrap();
-    stream.shutdown().await.unwrap();
+#[tokio::test]
+async fn test_no_store_response_not_cached() {
+    setup();
 
-    // Phase 4: Read echo response through the tunnel
-    let mut tunnel_response = Vec::new();
-    tokio::time::timeout(
-        Duration::from_secs(5),
-        stream.read_to_end(&mut tunnel_response),
+    let (upstream, requests) = start_counting_upstream(
+        b"HTTP/1.1 200 OK\r\nCache-Control: no-store\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+        b"",
     )
-    .await
-    .expect("Timed out reading tunnel data")
-    .expect("Failed to read tunnel data");
+    .await;
+    let proxy = start_caching_proxy().await;
 
-    let tunnel_str = String::from_utf8_lossy(&tunnel_response);
+    let [first, second] = get_twice(proxy, upstream).await;
+    assert!(first.ends_with("hello") && second.ends_with("hello"));
     assert!(
-        tunnel_str.contains("echo:hello tunnel"),
-        "Expected echo response through tunnel, got: {}",
-        tunnel_str
+        !second.to_ascii_lowercase().contains("\r\nage: "),
+        "Got: {}",
+        second
+    );
+    assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_stale_entry_revalidated_with_etag() {
+    setup();
+
+    let (upstream, requests) = start_counting_upstream(
+        b"HTTP/1.1 200 OK\r\nCache-Control: no-cache\r\nETag: \"v1\"\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+        b"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    let proxy = start_caching_proxy().await;
+
+    let [_, second] = get_twice(proxy, upstream).await;
+    assert!(
+        second.starts_with("HTTP/1.1 200 OK") && second.ends_with("hello"),
+        "Expected the cached body after a 304, got: {}",
+        second
+    );
+    assert_eq!(
+        requests.load(std::sync::atomic::Ordering::SeqCst),
+        2,
+        "Expected the stale entry to be revalidated upstream"
     );
 }
 
 // ---------------------------------------------------------------------------
-// Multiple requests through same proxy
+// Response size limit
 // ---------------------------------------------------------------------------
 
+/// A 200 with a 1000-byte body, framed by Content-Length or by closing.
+fn large_response(with_length: bool) -> &'static [u8] {
+    let head = if with_length {
+        "HTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\n"
+    } else {
+        "HTTP/1.1 200 OK\r\n\r\n"
+    };
+    let mut response = head.as_bytes().to_vec();
+    response.extend(std::iter::repeat_n(b'x', 1000));
+    response.leak()
+}
+
 #[tokio::test]
-async fn test_proxy_handles_multiple_sequential_requests() {
+async fn test_oversized_declared_response_returns_502() {
     setup();
 
-    let proxy = common::start_proxy().await;
+    let upstream = common::start_upstream(large_response(true)).await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        max_response_size: Some(100),
+        ..Default::default()
+    })
+    .await;
 
-    for i in 0..3 {
-        let upstream =
-            common::start_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK").await;
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
 
-        let request = format!(
-            "GET http://{}/path/{} HTTP/1.1\r\nHost: {}\r\n\r\n",
-            upstream, i, upstream
-        );
-        let response = common::send_raw(proxy, request.as_bytes()).await;
-        assert!(
-            response.contains("200 OK"),
-            "Request {} failed, got: {}",
-            i,
-            response
-        );
-    }
+    assert!(
+        response.starts_with("HTTP/1.1 502 Bad Gateway"),
+        "Expected 502 for a body over the limit, got: {}",
+        response
+    );
 }
 
-// ---------------------------------------------------------------------------
-// Concurrent requests
-// ---------------------------------------------------------------------------
+#[tokio::test]
+async fn test_oversized_streamed_response_is_truncated() {
+    setup();
+
+    let upstream = common::start_upstream(large_response(false)).await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        max_response_size: Some(100),
+        ..Default::default()
+    })
+    .await;
+
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK"),
+        "Expected the response to start normally, got: {}",
+        response
+    );
+    let body = response.split_once("\r\n\r\n").unwrap().1;
+    assert_eq!(body, "x".repeat(100), "Body should stop at the limit");
+}
 
 #[tokio::test]
-async fn test_proxy_handles_concurrent_requests() {
+async fn test_response_within_limit_is_untouched() {
     setup();
 
-    let upstream =
-        common::start_looping_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK").await;
-    let proxy = common::start_proxy().await;
+    let upstream = common::start_upstream(large_response(true)).await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        max_response_size: Some(1000),
+        ..Default::default()
+    })
+    .await;
 
-    let mut handles = Vec::new();
-    for i in 0..10 {
-        let request = format!(
-            "GET http://{}/path/{} HTTP/1.1\r\nHost: {}\r\n\r\n",
-            upstream, i, upstream
-        );
-        let request_bytes = request.into_bytes();
-        handles.push(tokio::spawn(async move {
-            common::send_raw(proxy, &request_bytes).await
-        }));
-    }
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
 
-    for (i, handle) in handles.into_iter().enumerate() {
-        let response = handle.await.expect("Task panicked");
-        assert!(
-            response.contains("200 OK"),
-            "Concurrent request {} failed, got: {}",
-            i,
-            response
-        );
-    }
+    let body = response.split_once("\r\n\r\n").unwrap().1;
+    assert_eq!(body.len(), 1000);
 }
 
 // ---------------------------------------------------------------------------
-// Large body forwarding
+// TLS interception
 // ---------------------------------------------------------------------------
 
 #[tokio::test]
-async fn test_http_post_large_body() {
+async fn test_mitm_decrypts_and_forwards_connect_request() {
     setup();
+    rhoxy::test_support::set_accept_invalid_upstream_certs(true);
+
+    let dir = tempfile::tempdir().unwrap();
+    let (cert_path, key_path) = common::write_test_ca(dir.path());
+    let authority = rhoxy::protocol::mitm::MitmAuthority::load(&cert_path, &key_path).unwrap();
+    let (upstream, received) = common::start_tls_upstream(
+        &authority,
+        "127.0.0.1",
+        b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nsecret",
+    )
+    .await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        connect_allowed_ports: any_connect_port(),
+        mitm: Some(std::sync::Arc::new(authority)),
+        ..Default::default()
+    })
+    .await;
 
-    let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-    let upstream_addr = upstream_listener.local_addr().unwrap();
+    // The client trusts only the test CA, so the handshake succeeds only
+    // against a leaf the proxy issued for the tunnel's host.
+    let ca = native_tls::Certificate::from_pem(&std::fs::read(&cert_path).unwrap()).unwrap();
+    let connector = tokio_native_tls::TlsConnector::from(
+        native_tls::TlsConnector::builder()
+            .add_root_certificate(ca)
+            .disable_built_in_roots(true)
+            .build()
+            .unwrap(),
+    );
+    let tunnel = open_tunnel(proxy, upstream).await;
+    let mut tls = connector.connect("127.0.0.1", tunnel).await.unwrap();
+    tls.write_all(b"GET /inspected?q=1 HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(Duration::from_secs(5), tls.read_to_string(&mut response))
+        .await
+        .expect("Timed out reading the intercepted response")
+        .unwrap();
 
-    tokio::spawn(async move {
-        let (stream, _) = upstream_listener.accept().await.unwrap();
-        let (reader, writer) = stream.into_split();
-        let mut reader = BufReader::new(reader);
-        let mut writer = BufWriter::new(writer);
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK") && response.ends_with("secret"),
+        "Expected the upstream's response through the tunnel, got: {}",
+        response
+    );
+    let request = received.await.unwrap();
+    assert!(
+        request.starts_with("GET /inspected?q=1 HTTP/1.1\r\n"),
+        "Expected the decrypted request upstream, got: {}",
+        request
+    );
+}
 
-        let body = common::read_upstream_body(&mut reader).await;
+// ---------------------------------------------------------------------------
+// Forced https upstreams
+// ---------------------------------------------------------------------------
 
-        let resp_body = format!("received {} bytes", body.len());
-        let resp = format!(
-            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
-            resp_body.len(),
-            resp_body
-        );
-        writer.write_all(resp.as_bytes()).await.unwrap();
-        writer.flush().await.unwrap();
-    });
+#[tokio::test]
+async fn test_force_upstream_https_forwards_http_target_over_tls() {
+    setup();
+    rhoxy::test_support::set_accept_invalid_upstream_certs(true);
+
+    let dir = tempfile::tempdir().unwrap();
+    let (cert_path, key_path) = common::write_test_ca(dir.path());
+    let authority = rhoxy::protocol::mitm::MitmAuthority::load(&cert_path, &key_path).unwrap();
+    let (upstream, received) = common::start_tls_upstream(
+        &authority,
+        "127.0.0.1",
+        b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nencrypted",
+    )
+    .await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        force_upstream_https: true,
+        ..Default::default()
+    })
+    .await;
 
-    let proxy = common::start_proxy().await;
-    let body_size = 128 * 1024; // 128 KiB
-    let body = "X".repeat(body_size);
     let request = format!(
-        "POST http://{}/submit HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{}",
-        upstream_addr,
-        upstream_addr,
-        body.len(),
-        body
+        "GET http://{}/upgraded HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
     );
     let response = common::send_raw(proxy, request.as_bytes()).await;
 
     assert!(
-        response.contains("200 OK"),
-        "Expected 200 OK, got: {}",
+        response.starts_with("HTTP/1.1 200 OK") && response.ends_with("encrypted"),
+        "Expected the TLS upstream's response, got: {}",
         response
     );
-    let expected = format!("received {} bytes", body_size);
+    let request = received.await.unwrap();
     assert!(
-        response.contains(&expected),
-        "Expected upstream to receive full body, got: {}",
+        request.starts_with("GET /upgraded HTTP/1.1\r\n"),
+        "Expected the request to arrive over TLS, got: {}",
+        request
+    );
+}
+
+#[tokio::test]
+async fn test_force_https_except_keeps_host_on_http() {
+    setup();
+
+    let upstream =
+        common::start_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nplain").await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        force_upstream_https: true,
+        force_https_except: vec!["127.0.0.1".to_string()],
+        ..Default::default()
+    })
+    .await;
+
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK") && response.ends_with("plain"),
+        "Expected the exempt host to be reached over plain http, got: {}",
         response
     );
 }
 
 // ---------------------------------------------------------------------------
-// Chunked transfer encoding
+// Allowed hours
 // ---------------------------------------------------------------------------
 
 #[tokio::test]
-async fn test_http_post_chunked_transfer_encoding() {
+async fn test_allowed_hours_gate_requests_by_clock() {
     setup();
 
-    let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-    let upstream_addr = upstream_listener.local_addr().unwrap();
+    let noon = std::time::UNIX_EPOCH + Duration::from_secs(19_000 * 86_400 + 12 * 3600);
+    let evening = noon + Duration::from_secs(8 * 3600);
 
-    tokio::spawn(async move {
-        let (stream, _) = upstream_listener.accept().await.unwrap();
-        let (reader, writer) = stream.into_split();
-        let mut reader = BufReader::new(reader);
-        let mut writer = BufWriter::new(writer);
+    // The clock is process-wide, so both cases run in this one test.
+    rhoxy::test_support::pin_clock(Some(noon));
+    let upstream =
+        common::start_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nopen").await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        allowed_hours: Some("09:00-17:00".parse().unwrap()),
+        ..Default::default()
+    })
+    .await;
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let inside = common::send_raw(proxy, request.as_bytes()).await;
 
-        let body = common::read_upstream_body(&mut reader).await;
+    rhoxy::test_support::pin_clock(Some(evening));
+    let outside = common::send_raw(proxy, request.as_bytes()).await;
+    rhoxy::test_support::pin_clock(None);
 
-        let body_str = String::from_utf8_lossy(&body);
-        let resp_body = format!("body={}", body_str);
-        let resp = format!(
-            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
-            resp_body.len(),
-            resp_body
-        );
-        writer.write_all(resp.as_bytes()).await.unwrap();
-        writer.flush().await.unwrap();
-    });
+    assert!(
+        inside.starts_with("HTTP/1.1 200 OK") && inside.ends_with("open"),
+        "Expected a request at noon to be forwarded, got: {}",
+        inside
+    );
+    assert!(
+        outside.starts_with("HTTP/1.1 403 Forbidden")
+            && outside.contains("only served 09:00-17:00 UTC"),
+        "Expected a request at 20:00 to be refused, got: {}",
+        outside
+    );
+}
 
-    let proxy = common::start_proxy().await;
-    // Chunked: "Hello" (5 bytes) + " World!" (7 bytes) = "Hello World!" (12 bytes)
+// ---------------------------------------------------------------------------
+// Resolver overrides
+// ---------------------------------------------------------------------------
+
+fn resolver_with_override(host: &str, ip: &str) -> std::sync::Arc<rhoxy::dns::Resolver> {
+    let pin = format!("{}={}", host, ip).parse().unwrap();
+    std::sync::Arc::new(rhoxy::dns::Resolver::new(vec![pin], None))
+}
+
+#[tokio::test]
+async fn test_hosts_override_routes_name_to_pinned_address() {
+    setup();
+
+    let upstream =
+        common::start_looping_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nfound").await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        resolver: resolver_with_override("pinned.invalid", "127.0.0.1"),
+        connect_allowed_ports: any_connect_port(),
+        ..Default::default()
+    })
+    .await;
+
+    // `.invalid` never resolves in DNS, so only the override can answer.
+    let target = format!("pinned.invalid:{}", upstream.port());
     let request = format!(
-        "POST http://{}/submit HTTP/1.1\r\nHost: {}\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nHello\r\n7\r\n World!\r\n0\r\n\r\n",
-        upstream_addr, upstream_addr
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        target, target
     );
     let response = common::send_raw(proxy, request.as_bytes()).await;
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK") && response.ends_with("found"),
+        "Expected the pinned upstream's response, got: {}",
+        response
+    );
 
+    // The upstream answers once it has read something through the tunnel.
+    let request = format!(
+        "CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\nping",
+        target, target
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
     assert!(
-        response.contains("200 OK"),
-        "Expected 200 OK, got: {}",
+        response.starts_with("HTTP/1.1 200 Connection Established"),
+        "Expected the tunnel to reach the pinned upstream, got: {}",
         response
     );
+}
+
+#[tokio::test]
+async fn test_name_without_override_uses_system_resolver() {
+    setup();
+
+    let upstream =
+        common::start_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").await;
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        resolver: resolver_with_override("pinned.invalid", "192.0.2.1"),
+        ..Default::default()
+    })
+    .await;
+
+    let target = format!("localhost:{}", upstream.port());
+    let request = format!(
+        "GET http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        target, target
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
     assert!(
-        response.contains("body=Hello World!"),
-        "Expected chunked body reassembled as 'Hello World!', got: {}",
+        response.starts_with("HTTP/1.1 200 OK") && response.ends_with("hello"),
+        "Expected localhost to resolve normally, got: {}",
         response
     );
 }
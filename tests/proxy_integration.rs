@@ -23,6 +23,63 @@ async fn test_health_check_returns_200() {
     );
 }
 
+// ---------------------------------------------------------------------------
+// Readiness check
+// ---------------------------------------------------------------------------
+
+async fn start_proxy_with_ready_probe(target: std::net::SocketAddr) -> std::net::SocketAddr {
+    let probe = rhoxy::readiness::ReadinessProbe::new(&target.to_string()).unwrap();
+    common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        ready_probe: Some(std::sync::Arc::new(probe)),
+        ..Default::default()
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_ready_check_returns_200_when_probe_target_reachable() {
+    let target = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy = start_proxy_with_ready_probe(target.local_addr().unwrap()).await;
+
+    let response = common::send_raw(proxy, b"GET /ready HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK"),
+        "Expected 200 with a live probe target, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_ready_check_returns_503_when_probe_target_down() {
+    // Bind then drop to get a port nothing listens on.
+    let target = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dead = target.local_addr().unwrap();
+    drop(target);
+    let proxy = start_proxy_with_ready_probe(dead).await;
+
+    let response = common::send_raw(proxy, b"GET /ready HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 503"),
+        "Expected 503 with a dead probe target, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_ready_check_without_probe_matches_health() {
+    let proxy = common::start_proxy().await;
+
+    let response = common::send_raw(proxy, b"GET /ready HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK"),
+        "Expected 200 with no probe configured, got: {}",
+        response
+    );
+}
+
 // ---------------------------------------------------------------------------
 // SSRF bypass sanity check
 // ---------------------------------------------------------------------------
@@ -94,6 +151,64 @@ async fn test_http_ssrf_block_localhost() {
     );
 }
 
+#[tokio::test]
+async fn test_allow_private_addresses_reaches_loopback_upstream() {
+    let upstream =
+        common::start_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\ninternal").await;
+    let request = format!(
+        "GET http://{}/status HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+
+    let proxy = common::start_proxy().await;
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+    assert!(
+        response.contains("403 Forbidden"),
+        "Expected 403 without --allow-private-addresses, got: {}",
+        response
+    );
+
+    let proxy = common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        allow_private_addresses: true,
+        ..Default::default()
+    })
+    .await;
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK") && response.ends_with("internal"),
+        "Expected the loopback upstream's response, got: {}",
+        response
+    );
+
+    let response = common::send_raw(
+        proxy,
+        b"GET http://169.254.169.254/latest/meta-data/ HTTP/1.1\r\nHost: 169.254.169.254\r\n\r\n",
+    )
+    .await;
+    assert!(
+        response.contains("403 Forbidden"),
+        "Expected metadata endpoints to stay blocked, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_connect_disallowed_port_returns_403() {
+    let proxy = common::start_proxy().await;
+
+    let response = common::send_raw(
+        proxy,
+        b"CONNECT example.com:25 HTTP/1.1\r\nHost: example.com:25\r\n\r\n",
+    )
+    .await;
+
+    assert!(
+        response.contains("403 Forbidden"),
+        "Expected 403 for CONNECT to port 25, got: {}",
+        response
+    );
+}
+
 // ---------------------------------------------------------------------------
 // SSRF protection — handler-level
 // ---------------------------------------------------------------------------
@@ -101,11 +216,16 @@ async fn test_http_ssrf_block_localhost() {
 #[tokio::test]
 async fn test_connect_handler_ssrf_blocks_private() {
     let mut writer = Vec::new();
-    let mut reader = tokio::io::BufReader::new(std::io::Cursor::new("Host: 127.0.0.1:443\r\n\r\n"));
+    let mut reader = tokio::io::BufReader::new(std::io::Cursor::new(Vec::new()));
 
-    let result =
-        rhoxy::protocol::https::handle_request(&mut writer, &mut reader, "127.0.0.1:443".into())
-            .await;
+    let result = rhoxy::protocol::https::handle_request(
+        &mut writer,
+        &mut reader,
+        "127.0.0.1:443".into(),
+        &rhoxy::config::ProxyConfig::default(),
+        None,
+    )
+    .await;
 
     assert!(result.is_ok());
     let response = String::from_utf8_lossy(&writer);
@@ -115,3 +235,664 @@ async fn test_connect_handler_ssrf_blocks_private() {
         response
     );
 }
+
+// ---------------------------------------------------------------------------
+// Proxy authentication
+// ---------------------------------------------------------------------------
+
+async fn start_authenticated_proxy() -> std::net::SocketAddr {
+    let mut auth = rhoxy::auth::ProxyAuth::new();
+    auth.add("bob", "hunter2");
+    common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        proxy_auth: Some(auth),
+        ..Default::default()
+    })
+    .await
+}
+
+fn assert_auth_required(response: &str) {
+    assert!(
+        response.starts_with("HTTP/1.1 407 Proxy Authentication Required\r\n"),
+        "Expected 407, got: {}",
+        response
+    );
+    assert!(
+        response.contains("Proxy-Authenticate: Basic"),
+        "Expected Proxy-Authenticate challenge, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_auth_missing_header_returns_407() {
+    let proxy = start_authenticated_proxy().await;
+
+    let response = common::send_raw(
+        proxy,
+        b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n",
+    )
+    .await;
+
+    assert_auth_required(&response);
+}
+
+#[tokio::test]
+async fn test_auth_wrong_credentials_returns_407() {
+    let proxy = start_authenticated_proxy().await;
+
+    // bob:wrong
+    let response = common::send_raw(
+        proxy,
+        b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\nProxy-Authorization: Basic Ym9iOndyb25n\r\n\r\n",
+    )
+    .await;
+
+    assert_auth_required(&response);
+}
+
+#[tokio::test]
+async fn test_auth_required_for_connect() {
+    let proxy = start_authenticated_proxy().await;
+
+    let response = common::send_raw(
+        proxy,
+        b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n",
+    )
+    .await;
+
+    assert_auth_required(&response);
+}
+
+#[tokio::test]
+async fn test_auth_valid_credentials_pass_through() {
+    let proxy = start_authenticated_proxy().await;
+
+    // bob:hunter2 — authenticated, so the request reaches the SSRF check.
+    let response = common::send_raw(
+        proxy,
+        b"CONNECT 127.0.0.1:443 HTTP/1.1\r\nHost: 127.0.0.1:443\r\nProxy-Authorization: Basic Ym9iOmh1bnRlcjI=\r\n\r\n",
+    )
+    .await;
+
+    assert!(
+        response.contains("403 Forbidden"),
+        "Expected authenticated request to reach SSRF check, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_auth_not_required_for_health_check() {
+    let proxy = start_authenticated_proxy().await;
+
+    let response =
+        common::send_raw(proxy, b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+
+    assert!(
+        response.contains("200 OK"),
+        "Expected health check to bypass auth, got: {}",
+        response
+    );
+}
+
+// ---------------------------------------------------------------------------
+// Request smuggling
+// ---------------------------------------------------------------------------
+
+#[tokio::test]
+async fn test_conflicting_content_length_returns_400() {
+    let proxy = common::start_proxy().await;
+
+    let response = common::send_raw(
+        proxy,
+        b"POST http://example.com/ HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!",
+    )
+    .await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request"),
+        "Expected 400 for conflicting Content-Length, got: {}",
+        response
+    );
+}
+
+// ---------------------------------------------------------------------------
+// Slow headers
+// ---------------------------------------------------------------------------
+
+#[tokio::test]
+async fn test_dribbled_headers_dropped_after_header_read_timeout() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let proxy = common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        header_read_timeout: Some(std::time::Duration::from_millis(300)),
+        ..Default::default()
+    })
+    .await;
+    let mut stream = tokio::net::TcpStream::connect(proxy).await.unwrap();
+    stream
+        .write_all(b"GET http://example.com/ HTTP/1.1\r\n")
+        .await
+        .unwrap();
+
+    // One byte every 50ms never lets the connection go idle, but the
+    // headers never finish either.
+    let started = std::time::Instant::now();
+    let mut response = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        tokio::select! {
+            read = stream.read(&mut buf) => match read.unwrap() {
+                0 => break,
+                n => response.extend_from_slice(&buf[..n]),
+            },
+            _ = tokio::time::sleep(std::time::Duration::from_millis(50)) => {
+                assert!(
+                    started.elapsed() < std::time::Duration::from_secs(5),
+                    "The connection should have been dropped by now"
+                );
+                // The proxy may already have closed its end.
+                let _ = stream.write_all(b"x").await;
+            }
+        }
+    }
+
+    let response = String::from_utf8(response).unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+        "Expected 408 once the header deadline passed, got: {}",
+        response
+    );
+    assert!(started.elapsed() >= std::time::Duration::from_millis(250));
+}
+
+// ---------------------------------------------------------------------------
+// Method allowlist
+// ---------------------------------------------------------------------------
+
+async fn start_get_only_proxy() -> std::net::SocketAddr {
+    common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        allowed_methods: Some(vec![http::Method::GET, http::Method::HEAD]),
+        ..Default::default()
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_disallowed_method_returns_405_with_allow() {
+    let proxy = start_get_only_proxy().await;
+
+    let response = common::send_raw(
+        proxy,
+        b"FOO http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n",
+    )
+    .await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+        "Expected 405, got: {}",
+        response
+    );
+    assert!(
+        response.contains("Allow: GET, HEAD\r\n"),
+        "Expected Allow header, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_allowed_method_passes_allowlist() {
+    let proxy = start_get_only_proxy().await;
+
+    // Past the allowlist, the private target is refused by SSRF protection.
+    let response = common::send_raw(
+        proxy,
+        b"GET http://127.0.0.1/ HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n",
+    )
+    .await;
+
+    assert!(
+        response.contains("403 Forbidden"),
+        "Expected GET to pass the allowlist, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_trace_and_track_blocked_by_default() {
+    let proxy = common::start_proxy().await;
+
+    for method in ["TRACE", "TRACK"] {
+        let request = format!(
+            "{} http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n",
+            method
+        );
+        let response = common::send_raw(proxy, request.as_bytes()).await;
+        assert!(
+            response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+            "Expected {} to be refused by default, got: {}",
+            method,
+            response
+        );
+        // Every other method is still served, so there is no `Allow` list.
+        assert!(
+            !response.contains("Allow:"),
+            "An empty or partial Allow would misstate what is served: {}",
+            response
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_allow_trace_lifts_default_block() {
+    let proxy = common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        allow_trace: true,
+        ..Default::default()
+    })
+    .await;
+
+    // Past the method check, the private target is refused by SSRF protection.
+    let response = common::send_raw(
+        proxy,
+        b"TRACE http://127.0.0.1/ HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n",
+    )
+    .await;
+    assert!(
+        response.contains("403 Forbidden"),
+        "Expected TRACE to pass the method check, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_denied_method_returns_405_while_get_passes() {
+    let proxy = common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        denied_methods: vec![http::Method::from_bytes(b"PURGE").unwrap()],
+        ..Default::default()
+    })
+    .await;
+
+    let response = common::send_raw(
+        proxy,
+        b"PURGE http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n",
+    )
+    .await;
+    assert!(
+        response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+        "Expected 405 for a denied method, got: {}",
+        response
+    );
+    assert!(!response.contains("Allow:"), "Got: {}", response);
+
+    let response = common::send_raw(
+        proxy,
+        b"GET http://127.0.0.1/ HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n",
+    )
+    .await;
+    assert!(
+        response.contains("403 Forbidden"),
+        "Expected GET to pass the method check, got: {}",
+        response
+    );
+}
+
+// ---------------------------------------------------------------------------
+// Tarpit
+// ---------------------------------------------------------------------------
+
+#[tokio::test]
+async fn test_tarpit_delays_blocked_request() {
+    let delay = std::time::Duration::from_millis(300);
+    let proxy = common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        tarpit: Some(delay),
+        ..Default::default()
+    })
+    .await;
+
+    let started = std::time::Instant::now();
+    let response = common::send_raw(
+        proxy,
+        b"GET http://127.0.0.1/secret HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n",
+    )
+    .await;
+
+    assert!(
+        response.contains("403 Forbidden"),
+        "Expected 403 for private address, got: {}",
+        response
+    );
+    assert!(
+        started.elapsed() >= delay,
+        "Expected the 403 to be held for {:?}, arrived after {:?}",
+        delay,
+        started.elapsed()
+    );
+}
+
+#[tokio::test]
+async fn test_tarpit_does_not_delay_health_check() {
+    let proxy = common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        tarpit: Some(std::time::Duration::from_secs(10)),
+        ..Default::default()
+    })
+    .await;
+
+    let response =
+        common::send_raw(proxy, b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+
+    assert!(
+        response.contains("200 OK"),
+        "Expected an immediate 200, got: {}",
+        response
+    );
+}
+
+// ---------------------------------------------------------------------------
+// Block metrics
+// ---------------------------------------------------------------------------
+
+/// Scrape `/metrics` through the proxy and return one `reason`'s counter.
+async fn blocked_total(proxy: std::net::SocketAddr, reason: &str) -> u64 {
+    let response =
+        common::send_raw(proxy, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK\r\n"),
+        "Expected metrics, got: {}",
+        response
+    );
+    let prefix = format!("rhoxy_blocked_requests_total{{reason=\"{}\"}} ", reason);
+    response
+        .lines()
+        .find_map(|line| line.strip_prefix(prefix.as_str()))
+        .unwrap_or_else(|| panic!("No {} counter in: {}", reason, response))
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_blocks_are_counted_in_metrics() {
+    let proxy = common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        allowed_methods: Some(vec![http::Method::GET, http::Method::HEAD]),
+        metrics: true,
+        ..Default::default()
+    })
+    .await;
+    let ssrf_before = blocked_total(proxy, "ssrf_private").await;
+    let method_before = blocked_total(proxy, "method_not_allowed").await;
+
+    common::send_raw(
+        proxy,
+        b"GET http://127.0.0.1/ HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n",
+    )
+    .await;
+    common::send_raw(
+        proxy,
+        b"DELETE http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n",
+    )
+    .await;
+
+    // Counters are process-wide, so concurrent tests may add more.
+    assert!(blocked_total(proxy, "ssrf_private").await > ssrf_before);
+    assert!(blocked_total(proxy, "method_not_allowed").await > method_before);
+}
+
+#[tokio::test]
+async fn test_metrics_not_served_without_flag() {
+    let proxy = start_authenticated_proxy().await;
+    let response =
+        common::send_raw(proxy, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 407 "),
+        "Expected /metrics to go through the proxy path, got: {}",
+        response
+    );
+    assert!(!response.contains("rhoxy_"), "Got: {}", response);
+}
+
+// ---------------------------------------------------------------------------
+// Admin stats
+// ---------------------------------------------------------------------------
+
+#[tokio::test]
+async fn test_admin_stats_reports_connections() {
+    let proxy = common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        admin: true,
+        ..Default::default()
+    })
+    .await;
+
+    for _ in 0..2 {
+        common::send_raw(proxy, b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    }
+    let response = common::send_raw(proxy, b"GET /stats HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK\r\n")
+            && response.contains("Content-Type: application/json\r\n"),
+        "Expected JSON stats, got: {}",
+        response
+    );
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    let stats: serde_json::Value = serde_json::from_str(body).unwrap();
+
+    // Counters are process-wide, so concurrent tests may add more.
+    assert!(stats["total_connections"].as_u64().unwrap() >= 3);
+    assert!(
+        stats["active_connections"].as_u64().unwrap() >= 1,
+        "The stats request itself is active: {}",
+        body
+    );
+    assert!(stats["uptime_seconds"].is_u64());
+    assert!(stats["bytes_proxied"].is_u64());
+}
+
+#[tokio::test]
+async fn test_stats_not_served_without_admin() {
+    let proxy = common::start_proxy().await;
+    let response = common::send_raw(proxy, b"GET /stats HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+
+    assert!(
+        !response.starts_with("HTTP/1.1 200 OK\r\n"),
+        "Expected /stats to be off by default, got: {}",
+        response
+    );
+}
+
+// ---------------------------------------------------------------------------
+// Tenant labels
+// ---------------------------------------------------------------------------
+
+#[tokio::test]
+async fn test_tenant_header_labels_metrics() {
+    let proxy = common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        tenant_header: Some("x-tenant".to_string()),
+        metrics: true,
+        ..Default::default()
+    })
+    .await;
+
+    for _ in 0..2 {
+        common::send_raw(
+            proxy,
+            b"GET /health HTTP/1.1\r\nHost: localhost\r\nX-Tenant: labeled-acme\r\n\r\n",
+        )
+        .await;
+    }
+    let response =
+        common::send_raw(proxy, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+
+    assert!(
+        response.contains("rhoxy_tenant_requests_total{tenant=\"labeled-acme\"} 2\n"),
+        "Expected a tenant-labeled request series, got: {}",
+        response
+    );
+    assert!(
+        response.contains(
+            "rhoxy_tenant_client_bytes_total{tenant=\"labeled-acme\",direction=\"read\"} "
+        ),
+        "Expected tenant-labeled byte series, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_tenant_header_ignored_from_untrusted_peer() {
+    let proxy = common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        tenant_header: Some("x-tenant".to_string()),
+        tenant_trusted_peers: vec!["192.0.2.1".parse().unwrap()],
+        metrics: true,
+        ..Default::default()
+    })
+    .await;
+
+    common::send_raw(
+        proxy,
+        b"GET /health HTTP/1.1\r\nHost: localhost\r\nX-Tenant: spoofed-acme\r\n\r\n",
+    )
+    .await;
+    let response =
+        common::send_raw(proxy, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+
+    assert!(
+        !response.contains("spoofed-acme"),
+        "An untrusted client's tenant header must be ignored, got: {}",
+        response
+    );
+}
+
+// ---------------------------------------------------------------------------
+// Reverse proxy
+// ---------------------------------------------------------------------------
+
+/// An upstream that answers one request with `200 OK` and sends back its
+/// request line and `Host` header.
+async fn start_reverse_upstream() -> (
+    std::net::SocketAddr,
+    tokio::sync::oneshot::Receiver<(String, String)>,
+) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = tokio::io::BufReader::new(stream);
+        let mut request_line = String::new();
+        stream.read_line(&mut request_line).await.unwrap();
+        let mut host = String::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+            if let Some(value) = line.to_ascii_lowercase().strip_prefix("host:") {
+                host = value.trim().to_string();
+            }
+        }
+        let _ = tx.send((request_line.trim_end().to_string(), host));
+        stream
+            .get_mut()
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK")
+            .await
+            .unwrap();
+    });
+    (addr, rx)
+}
+
+#[tokio::test]
+async fn test_reverse_upstream_forwards_origin_form_to_private_upstream() {
+    let (upstream, rx) = start_reverse_upstream().await;
+    let proxy = common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        reverse_upstream: Some(format!("http://{}/base/", upstream).parse().unwrap()),
+        ..Default::default()
+    })
+    .await;
+
+    let response = common::send_raw(
+        proxy,
+        b"GET /api/x?q=1 HTTP/1.1\r\nHost: proxy.example\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "Got: {}", response);
+
+    let (request_line, host) = rx.await.unwrap();
+    assert_eq!(request_line, "GET /base/api/x?q=1 HTTP/1.1");
+    assert_eq!(host, upstream.to_string());
+
+    // The waiver covers the configured upstream only.
+    let request = format!(
+        "GET http://{}/api/x HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, upstream
+    );
+    let response = common::send_raw(proxy, request.as_bytes()).await;
+    assert!(
+        response.contains("403 Forbidden"),
+        "Expected 403 for an absolute-form private target, got: {}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_reverse_upstream_gets_proxy_endpoint_paths() {
+    let (upstream, rx) = start_reverse_upstream().await;
+    let proxy = common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        reverse_upstream: Some(format!("http://{}", upstream).parse().unwrap()),
+        ..Default::default()
+    })
+    .await;
+
+    let response = common::send_raw(
+        proxy,
+        b"GET /health HTTP/1.1\r\nHost: proxy.example\r\n\r\n",
+    )
+    .await;
+    assert!(response.ends_with("\r\n\r\nOK"), "Got: {}", response);
+
+    let (request_line, _) = rx.await.unwrap();
+    assert_eq!(request_line, "GET /health HTTP/1.1");
+}
+
+#[tokio::test]
+async fn test_reverse_upstream_refuses_dot_segments() {
+    let (upstream, _rx) = start_reverse_upstream().await;
+    let proxy = common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        reverse_upstream: Some(format!("http://{}/app/", upstream).parse().unwrap()),
+        ..Default::default()
+    })
+    .await;
+
+    for target in ["/../secret", "/%2e%2e/secret", "/app/%2E%2E/%2e%2e/secret"] {
+        let request = format!("GET {} HTTP/1.1\r\nHost: proxy.example\r\n\r\n", target);
+        let response = common::send_raw(proxy, request.as_bytes()).await;
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request"),
+            "Expected 400 for {}, got: {}",
+            target,
+            response
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_reverse_upstream_strips_prefix() {
+    let (upstream, rx) = start_reverse_upstream().await;
+    let proxy = common::start_proxy_with_config(rhoxy::config::ProxyConfig {
+        reverse_upstream: Some(format!("http://{}", upstream).parse().unwrap()),
+        strip_prefix: Some("/api".to_string()),
+        ..Default::default()
+    })
+    .await;
+
+    let response = common::send_raw(
+        proxy,
+        b"GET /api/users/7 HTTP/1.1\r\nHost: proxy.example\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "Got: {}", response);
+
+    let (request_line, _) = rx.await.unwrap();
+    assert_eq!(request_line, "GET /users/7 HTTP/1.1");
+}
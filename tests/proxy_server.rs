@@ -1,5 +1,5 @@
-//! Integration tests for production server behaviors: connection timeout
-//! and connection limiting.
+//! Integration tests for production server behaviors: connection timeout,
+//! connection limiting, and the in-process `Server`.
 //!
 //! These tests do NOT require the `_test-support` feature because they do not
 //! forward to localhost upstreams — they test proxy server infrastructure only.
@@ -92,3 +92,30 @@ async fn test_connection_limit_recovers_after_completion() {
         response2
     );
 }
+
+// ---------------------------------------------------------------------------
+// In-process server
+// ---------------------------------------------------------------------------
+
+#[tokio::test]
+async fn test_serve_answers_health_until_shut_down() {
+    let (addr, shutdown) = rhoxy::serve(
+        "127.0.0.1:0".parse().unwrap(),
+        rhoxy::config::ProxyConfig::default(),
+    )
+    .await
+    .unwrap();
+    assert_ne!(addr.port(), 0, "serve should report the port it bound");
+
+    let response = common::send_raw(addr, b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    assert!(response.contains("200 OK"), "Got: {}", response);
+
+    shutdown.shutdown();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while TcpStream::connect(addr).await.is_ok() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("The listener should close after shutdown");
+}
@@ -0,0 +1,109 @@
+//! Integration tests for the SOCKS5 front end.
+//!
+//! These tests require the `_test-support` feature because they tunnel to a
+//! localhost echo upstream.
+//!
+//!     cargo test --features _test-support --test socks5
+#![cfg(feature = "_test-support")]
+
+mod common;
+
+use rhoxy::auth::ProxyAuth;
+use rhoxy::config::ProxyConfig;
+use std::sync::Once;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_socks::tcp::Socks5Stream;
+
+static INIT: Once = Once::new();
+
+fn setup() {
+    INIT.call_once(|| {
+        rhoxy::test_support::set_ssrf_bypass(true);
+    });
+}
+
+/// Echo server for one connection.
+async fn start_echo_upstream() -> std::net::SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (mut reader, mut writer) = stream.split();
+        let _ = tokio::io::copy(&mut reader, &mut writer).await;
+    });
+
+    addr
+}
+
+/// Allow the echo upstream's ephemeral port through the CONNECT allowlist.
+fn config_for(upstream: std::net::SocketAddr) -> ProxyConfig {
+    ProxyConfig {
+        connect_allowed_ports: upstream.port().to_string().parse().unwrap(),
+        ..Default::default()
+    }
+}
+
+async fn assert_echoes(stream: &mut Socks5Stream<tokio::net::TcpStream>) {
+    stream.write_all(b"ping").await.unwrap();
+    let mut echoed = [0u8; 4];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut echoed))
+        .await
+        .expect("Timed out waiting for echo")
+        .unwrap();
+    assert_eq!(&echoed, b"ping");
+}
+
+#[tokio::test]
+async fn test_socks5_connect_without_auth() {
+    setup();
+
+    let upstream = start_echo_upstream().await;
+    let proxy = common::start_socks_proxy_with_config(config_for(upstream)).await;
+
+    let mut stream = Socks5Stream::connect(proxy, upstream).await.unwrap();
+    assert_echoes(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_socks5_connect_with_password() {
+    setup();
+
+    let upstream = start_echo_upstream().await;
+    let mut auth = ProxyAuth::new();
+    auth.add("bob", "hunter2");
+    let proxy = common::start_socks_proxy_with_config(ProxyConfig {
+        proxy_auth: Some(auth),
+        ..config_for(upstream)
+    })
+    .await;
+
+    let rejected = Socks5Stream::connect_with_password(proxy, upstream, "bob", "wrong").await;
+    assert!(rejected.is_err(), "Wrong password should be refused");
+
+    let mut stream = Socks5Stream::connect_with_password(proxy, upstream, "bob", "hunter2")
+        .await
+        .unwrap();
+    assert_echoes(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_socks5_disallowed_port_refused() {
+    setup();
+
+    let upstream = start_echo_upstream().await;
+    // Default allowlist: 443 only.
+    let proxy = common::start_socks_proxy_with_config(ProxyConfig::default()).await;
+
+    let result = Socks5Stream::connect(proxy, upstream).await;
+    assert!(
+        matches!(
+            result,
+            Err(tokio_socks::Error::ConnectionNotAllowedByRuleset)
+        ),
+        "Expected a not-allowed reply, got: {:?}",
+        result.map(|_| ())
+    );
+}
@@ -0,0 +1,79 @@
+//! Integration tests for `--tls-cert`/`--tls-key`. These run the real binary
+//! because the listener setup lives in `main.rs`.
+//!
+//!     cargo test --test tls_listener
+
+mod common;
+
+use rhoxy::protocol::mitm::MitmAuthority;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn test_tls_listener_serves_health() {
+    let dir = tempfile::tempdir().unwrap();
+    let (ca_cert_path, ca_key_path) = common::write_test_ca(dir.path());
+    let authority = MitmAuthority::load(&ca_cert_path, &ca_key_path).unwrap();
+    let (cert, key) = authority.issue_leaf("localhost").unwrap();
+    let cert_path = dir.path().join("proxy.pem");
+    let key_path = dir.path().join("proxy.key");
+    std::fs::write(&cert_path, cert.to_pem().unwrap()).unwrap();
+    std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+    let addr: SocketAddr = format!("127.0.0.1:{}", common::free_port())
+        .parse()
+        .unwrap();
+    let _child = common::spawn_rhoxy(&[
+        "--port",
+        &addr.port().to_string(),
+        "--tls-cert",
+        cert_path.to_str().unwrap(),
+        "--tls-key",
+        key_path.to_str().unwrap(),
+    ]);
+    common::wait_for_listener(addr).await;
+
+    let ca = native_tls::Certificate::from_pem(&std::fs::read(&ca_cert_path).unwrap()).unwrap();
+    let connector = tokio_native_tls::TlsConnector::from(
+        native_tls::TlsConnector::builder()
+            .add_root_certificate(ca)
+            .build()
+            .unwrap(),
+    );
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut stream = connector.connect("localhost", stream).await.unwrap();
+    stream
+        .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+
+    let mut response = Vec::new();
+    // The proxy closes the connection after one request; some TLS stacks
+    // report that without a close_notify, so keep what arrived.
+    let _ = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("Timed out reading response");
+    let response = String::from_utf8_lossy(&response);
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK") && response.ends_with("OK"),
+        "Expected a health check over TLS, got: {}",
+        response
+    );
+
+    // A plaintext client fails the handshake and gets no HTTP response.
+    let mut plain = TcpStream::connect(addr).await.unwrap();
+    plain
+        .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    let _ = tokio::time::timeout(Duration::from_secs(5), plain.read_to_end(&mut response))
+        .await
+        .expect("Timed out reading response");
+    assert!(
+        !String::from_utf8_lossy(&response).contains("200 OK"),
+        "Plaintext must not be served on a TLS listener"
+    );
+}
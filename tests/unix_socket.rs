@@ -0,0 +1,101 @@
+//! Integration tests for `--unix-socket`. These run the real binary because
+//! the listener setup lives in `main.rs`.
+//!
+//!     cargo test --test unix_socket
+#![cfg(unix)]
+
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rhoxy-{}-{}.sock", name, std::process::id()))
+}
+
+async fn wait_for_socket(path: &Path) -> UnixStream {
+    for _ in 0..100 {
+        if let Ok(stream) = UnixStream::connect(path).await {
+            return stream;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("rhoxy never started listening on {}", path.display());
+}
+
+async fn send_unix(mut stream: UnixStream, request: &[u8]) -> String {
+    stream.write_all(request).await.unwrap();
+    stream.shutdown().await.unwrap();
+
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("Timed out reading response")
+        .expect("Failed to read response");
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+#[tokio::test]
+async fn test_unix_socket_health_check() {
+    let path = socket_path("health");
+    let _child = common::spawn_rhoxy(&["--unix-socket", path.to_str().unwrap()]);
+
+    let stream = wait_for_socket(&path).await;
+    let response = send_unix(stream, b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+
+    assert!(
+        response.contains("200 OK"),
+        "Expected 200 OK over Unix socket, got: {}",
+        response
+    );
+}
+
+#[test]
+fn test_unix_socket_in_use_is_not_taken_over() {
+    let path = socket_path("in-use");
+    let _ = std::fs::remove_file(&path);
+    let _live = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_rhoxy"))
+        .args(["--unix-socket", path.to_str().unwrap()])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("address in use"), "Got: {}", stderr);
+    assert!(
+        std::os::unix::net::UnixStream::connect(&path).is_ok(),
+        "The running server's socket should be left alone"
+    );
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn test_unix_socket_replaces_stale_file_and_cleans_up() {
+    let path = socket_path("stale");
+
+    // Binding and dropping a std listener leaves the socket file behind,
+    // exactly like a crashed previous run.
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    let mut child = common::spawn_rhoxy(&["--unix-socket", path.to_str().unwrap()]);
+    let stream = wait_for_socket(&path).await;
+    let response = send_unix(stream, b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+    assert!(response.contains("200 OK"), "got: {}", response);
+
+    common::signal(&child, "INT");
+    let status = tokio::time::timeout(Duration::from_secs(5), child.wait())
+        .await
+        .expect("rhoxy did not exit after SIGINT")
+        .unwrap();
+
+    assert!(status.success(), "rhoxy exited with {}", status);
+    assert!(
+        !path.exists(),
+        "Socket file should be removed on graceful shutdown"
+    );
+}
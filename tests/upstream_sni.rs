@@ -0,0 +1,79 @@
+//! Integration tests for `--upstream-sni`. Upstream certificates are
+//! verified here, so these run in their own binary where no test turns
+//! verification off.
+//!
+//!     cargo test --features _test-support --test upstream_sni
+#![cfg(feature = "_test-support")]
+
+mod common;
+
+use rhoxy::config::ProxyConfig;
+use rhoxy::protocol::mitm::MitmAuthority;
+
+const UPSTREAM_NAME: &str = "upstream.test";
+
+/// Trust a fresh CA for upstream TLS, returning it for issuing upstream
+/// certificates. The directory holding it must outlive the test.
+fn trust_test_ca() -> (MitmAuthority, tempfile::TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let (cert_path, key_path) = common::write_test_ca(dir.path());
+    rhoxy::test_support::set_upstream_root_certificate(Some(std::fs::read(&cert_path).unwrap()));
+    (MitmAuthority::load(&cert_path, &key_path).unwrap(), dir)
+}
+
+/// An https upstream on loopback whose certificate is for `UPSTREAM_NAME`
+/// only.
+async fn start_named_upstream(authority: &MitmAuthority) -> std::net::SocketAddr {
+    let (upstream, _) = common::start_tls_upstream(
+        authority,
+        UPSTREAM_NAME,
+        b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\nverified",
+    )
+    .await;
+    upstream
+}
+
+async fn fetch_through(upstream: std::net::SocketAddr, sni: &[&str]) -> String {
+    let proxy = common::start_proxy_with_config(ProxyConfig {
+        allow_private_addresses: true,
+        upstream_sni: sni.iter().map(|s| s.parse().unwrap()).collect(),
+        ..Default::default()
+    })
+    .await;
+    let request = format!(
+        "GET https://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n",
+        upstream, UPSTREAM_NAME
+    );
+    common::send_raw(proxy, request.as_bytes()).await
+}
+
+#[tokio::test]
+async fn test_upstream_sni_verifies_named_certificate() {
+    let (authority, _ca) = trust_test_ca();
+
+    let upstream = start_named_upstream(&authority).await;
+    let response = fetch_through(upstream, &[]).await;
+    assert!(
+        response.starts_with("HTTP/1.1 502"),
+        "A certificate for {} must not verify for 127.0.0.1, got: {}",
+        UPSTREAM_NAME,
+        response
+    );
+
+    let upstream = start_named_upstream(&authority).await;
+    let response = fetch_through(upstream, &["127.0.0.1=other.test"]).await;
+    assert!(
+        response.starts_with("HTTP/1.1 502"),
+        "The wrong SNI override must not verify, got: {}",
+        response
+    );
+
+    let upstream = start_named_upstream(&authority).await;
+    let sni = format!("127.0.0.1={}", UPSTREAM_NAME);
+    let response = fetch_through(upstream, &[&sni]).await;
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK") && response.ends_with("verified"),
+        "Expected the upstream's response with the SNI override, got: {}",
+        response
+    );
+}